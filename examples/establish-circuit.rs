@@ -8,7 +8,8 @@ extern crate toml;
 use opensim_networking::circuit::message_handlers;
 use opensim_networking::logging::{Log, LogLevel};
 use opensim_networking::login::{hash_password, LoginRequest};
-use opensim_networking::simulator::{ConnectInfo, Simulator};
+use opensim_networking::services::ServicesBuilder;
+use opensim_networking::simulator::{ConnectInfo, ConnectOptions, Simulator};
 use opensim_networking::systems::agent_update::{AgentState, Modality, MoveDirection};
 use opensim_networking::types::{Duration, UnitQuaternion, Vector3};
 
@@ -64,9 +65,17 @@ fn main() {
 
     let message_handlers = message_handlers::Handlers::default();
     let sim_connect_info = ConnectInfo::from(resp);
-    let sim = Simulator::connect(sim_connect_info, message_handlers, core.handle(), log)
-        .wait()
-        .unwrap();
+    let services = ServicesBuilder::new();
+    let sim = Simulator::connect(
+        sim_connect_info,
+        ConnectOptions::default(),
+        message_handlers,
+        services,
+        core.handle(),
+        log,
+    )
+    .wait()
+    .unwrap();
 
     // Exemplary texture request.
     let texture_id = sim.region_info().terrain_detail[0].clone();
@@ -84,6 +93,7 @@ fn main() {
         // TODO: This initialization is redundant as it was already done in simulator.rs
         body_rotation: UnitQuaternion::from_axis_angle(&z_axis, 0.),
         head_rotation: UnitQuaternion::from_axis_angle(&z_axis, 0.),
+        draw_distance: 0.,
     };
 
     loop {
@@ -0,0 +1,235 @@
+//! Benchmark of the threads and CPU time used by many circuits, comparing
+//! circuits driven by threads of their own to circuits sharing a
+//! `CircuitRuntime`.
+//!
+//! A fake sim on localhost pings every circuit once per second, which the
+//! circuits answer, and sends them chat messages to decode twenty times per
+//! second. This only works on Linux, where the numbers are read from
+//! `/proc/self`. The CPU time includes the fake sim.
+
+extern crate opensim_networking;
+extern crate tokio_core;
+extern crate url;
+
+use opensim_networking::circuit::message_handlers::{self, HandlerContext};
+use opensim_networking::circuit::{Circuit, CircuitConfig, CircuitRuntime};
+use opensim_networking::logging::{Log, LogLevel};
+use opensim_networking::messages::{
+    ChatFromSimulator, ChatFromSimulator_ChatData, StartPingCheck, StartPingCheck_PingID,
+};
+use opensim_networking::packet::Packet;
+use opensim_networking::simulator::ConnectInfo;
+use opensim_networking::types::{Duration, Ip4Addr, Uuid, Vector3};
+
+use std::collections::HashSet;
+use std::env;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::Error as IoError;
+use std::net::{SocketAddr, UdpSocket};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+use tokio_core::reactor::Core;
+use url::Url;
+
+const CIRCUIT_COUNTS: [usize; 4] = [10, 50, 100, 200];
+
+/// Time for the circuits to reach the sim before measuring.
+const WARM_UP: Duration = Duration::from_secs(3);
+const MEASURE: Duration = Duration::from_secs(5);
+
+/// The fake sim sends chat messages to every circuit once per round, and
+/// pings them every `ROUNDS_PER_PING` rounds.
+const ROUND: Duration = Duration::from_millis(50);
+const ROUNDS_PER_PING: u32 = 20;
+/// The length of the chat messages.
+const CHAT_LEN: usize = 1000;
+
+/// Clock ticks per second of the CPU times in `/proc`, which is 100 on
+/// practically all Linux systems.
+const CLOCK_TICKS: u64 = 100;
+
+fn main() {
+    let sim = FakeSim::start();
+    let connect_info = ConnectInfo {
+        capabilities_seed: Url::parse("http://127.0.0.1/").unwrap(),
+        agent_id: Uuid::nil(),
+        session_id: Uuid::nil(),
+        circuit_code: 0,
+        sim_ip: Ip4Addr::new(127, 0, 0, 1),
+        sim_port: sim.port,
+    };
+    let log_dir = env::temp_dir().join(format!("many-circuits-{}", process::id()));
+    let log = Log::new_dir(log_dir, LogLevel::Events).expect("Setting up log failed.");
+
+    // Only used by message handlers of circuits with threads of their own,
+    // the default handlers don't need it.
+    let core = Core::new().unwrap();
+    let runtime = CircuitRuntime::new(2).unwrap();
+
+    println!("{:>8} {:>9} {:>8} {:>12}", "mode", "circuits", "threads", "cpu ms/s");
+    for &count in CIRCUIT_COUNTS.iter() {
+        measure("threads", count, &sim, || {
+            Circuit::initiate(&connect_info, config(), handlers(), core.remote(), log.clone())
+        });
+        measure("runtime", count, &sim, || {
+            Circuit::initiate_on(&runtime, &connect_info, config(), handlers(), log.clone())
+        });
+    }
+}
+
+/// The default handlers, and one dropping the chat messages once they were
+/// decoded.
+fn handlers() -> message_handlers::Handlers {
+    let mut handlers = message_handlers::Handlers::default();
+    handlers.register(|_: ChatFromSimulator, _: &HandlerContext| Ok(()));
+    handlers
+}
+
+fn config() -> CircuitConfig {
+    CircuitConfig {
+        send_timeout: Duration::from_millis(5000),
+        send_attempts: 5,
+        // Until the sim pings them, the circuits ping the sim.
+        ping_interval: Duration::from_secs(1),
+        throttles: None,
+        // Chat from the sim is only accepted on trusted circuits.
+        trusted: true,
+        silence_timeout: Duration::from_secs(10),
+        dead_timeout: None,
+        stats_interval: None,
+    }
+}
+
+fn measure<F>(mode: &str, count: usize, sim: &FakeSim, initiate: F)
+where
+    F: Fn() -> Result<Circuit, IoError>,
+{
+    sim.forget_clients();
+    let circuits: Vec<_> = (0..count).map(|_| initiate().unwrap()).collect();
+    thread::sleep(WARM_UP);
+
+    let threads = thread_count();
+    let start = cpu_ticks();
+    thread::sleep(MEASURE);
+    let ticks = cpu_ticks() - start;
+    let cpu_ms = ticks * 1000 / CLOCK_TICKS / MEASURE.as_secs();
+    println!("{:>8} {:>9} {:>8} {:>12}", mode, count, threads, cpu_ms);
+
+    for circuit in circuits {
+        circuit.close().unwrap();
+    }
+}
+
+/// The number of threads of this process.
+fn thread_count() -> usize {
+    let status = read_proc("/proc/self/status");
+    status
+        .lines()
+        .find(|line| line.starts_with("Threads:"))
+        .and_then(|line| line["Threads:".len()..].trim().parse().ok())
+        .expect("Threads missing in /proc/self/status.")
+}
+
+/// The user and system CPU time of this process in clock ticks.
+fn cpu_ticks() -> u64 {
+    let stat = read_proc("/proc/self/stat");
+    // The process name might contain spaces, the fields after it don't.
+    let fields: Vec<u64> = stat[stat.rfind(')').unwrap() + 1..]
+        .split_whitespace()
+        .skip(11)
+        .take(2)
+        .map(|field| field.parse().unwrap())
+        .collect();
+    fields[0] + fields[1]
+}
+
+fn read_proc(path: &str) -> String {
+    let mut content = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut content))
+        .expect("Reading /proc failed, this only works on Linux.");
+    content
+}
+
+/// Pings every address it received a packet from once per second, and sends
+/// chat messages to them.
+struct FakeSim {
+    port: u16,
+    clients: Arc<Mutex<HashSet<SocketAddr>>>,
+}
+
+impl FakeSim {
+    fn start() -> FakeSim {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let clients = Arc::new(Mutex::new(HashSet::new()));
+        let clients_1 = Arc::clone(&clients);
+
+        thread::spawn(move || {
+            let mut buf = [0u8; 4096];
+            let mut next_round = Instant::now();
+            let mut round = 0u32;
+            let mut ping_id = 0u8;
+            let mut sequence_number = 0;
+            loop {
+                if let Ok((_, address)) = socket.recv_from(&mut buf) {
+                    clients_1.lock().unwrap().insert(address);
+                }
+                if Instant::now() < next_round {
+                    continue;
+                }
+                next_round += ROUND;
+                round += 1;
+                let ping = round % ROUNDS_PER_PING == 0;
+                if ping {
+                    ping_id = ping_id.wrapping_add(1);
+                }
+                for address in clients_1.lock().unwrap().iter() {
+                    if ping {
+                        sequence_number += 1;
+                        let ping = StartPingCheck {
+                            ping_id: StartPingCheck_PingID {
+                                ping_id: ping_id,
+                                oldest_unacked: 0,
+                            },
+                        };
+                        let mut packet = Vec::new();
+                        Packet::new(ping, sequence_number).write_to_vec(&mut packet).unwrap();
+                        let _ = socket.send_to(&packet, address);
+                    }
+
+                    sequence_number += 1;
+                    let chat = ChatFromSimulator {
+                        chat_data: ChatFromSimulator_ChatData {
+                            from_name: b"Fake Sim".to_vec(),
+                            source_id: Uuid::nil(),
+                            owner_id: Uuid::nil(),
+                            source_type: 2,
+                            chat_type: 1,
+                            audible: 1,
+                            position: Vector3::new(128., 128., 20.),
+                            message: vec![b'x'; CHAT_LEN],
+                        },
+                    };
+                    let mut packet = Vec::new();
+                    Packet::new(chat, sequence_number).write_to_vec(&mut packet).unwrap();
+                    let _ = socket.send_to(&packet, address);
+                }
+            }
+        });
+
+        FakeSim {
+            port: port,
+            clients: clients,
+        }
+    }
+
+    /// Stop pinging the circuits of the previous measurement.
+    fn forget_clients(&self) {
+        self.clients.lock().unwrap().clear();
+    }
+}
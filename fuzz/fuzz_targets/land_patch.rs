@@ -0,0 +1,17 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate opensim_networking;
+
+use opensim_networking::layer_data;
+use opensim_networking::messages::MessageInstance;
+use opensim_networking::packet::Packet;
+
+// The corpus consists of whole LayerData packets as received from a sim.
+fuzz_target!(|data: &[u8]| {
+    if let Ok(packet) = Packet::read(data) {
+        if let MessageInstance::LayerData(msg) = packet.message {
+            let _ = layer_data::extract_land_patch(&msg);
+        }
+    }
+});
@@ -0,0 +1,12 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate opensim_networking;
+
+use opensim_networking::object_update;
+use std::io::BufReader;
+
+fuzz_target!(|data: &[u8]| {
+    let mut reader = BufReader::new(data);
+    let _ = object_update::read_object_data(&mut reader);
+});
@@ -0,0 +1,10 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate opensim_networking;
+
+use opensim_networking::packet::Packet;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Packet::read(data);
+});
@@ -1,17 +1,33 @@
+SERDE_DERIVE = "#[cfg_attr(feature = \"serde-serialize\", derive(Serialize, Deserialize))]\n"
+
+# Serde representations of the field types which need a stable one, see
+# serde_fields.rs.
+SERDE_WITH = {
+    "LLUUID": "uuid",
+    "LLVector3": "vector3", "LLVector3d": "vector3d", "LLVector4": "vector4",
+    "LLQuaternion": "quaternion",
+    "Variable": "bytes",
+    "Fixed": "fixed_bytes",
+}
+
 def generate_struct(message):
     # Generate block definitions.
     code = ""
     for block in message.blocks:
-        code += "#[derive(Clone, Debug)]\n"
+        code += "#[derive(Clone, Debug, PartialEq)]\n"
+        code += SERDE_DERIVE
         code += "pub struct %s {\n" % block.r_name
         for field in block.fields:
             code += "\t%s\n" % field.doc
+            if field.ll_type in SERDE_WITH:
+                code += "\t#[cfg_attr(feature = \"serde-serialize\", serde(with = \"::serde_fields::%s\"))]\n" % SERDE_WITH[field.ll_type]
             code += "\tpub %s: %s,\n" % (field.r_name, field.r_type)
         code += "}\n\n"
 
     # Generate message definition.
     code += "%s\n" % message.doc
-    code += "#[derive(Clone, Debug)]\n"
+    code += "#[derive(Clone, Debug, PartialEq)]\n"
+    code += SERDE_DERIVE
     code += "pub struct %s {\n" % message.name
 
     for block in message.blocks:
@@ -88,6 +104,35 @@ def generate_field_writer(field, source):
     else:
         raise RuntimeError("No rule for field writer generation of r_type: %s" % field.r_type)
 
+# Encoded sizes of the fixed size field types.
+FIELD_SIZES = {
+    "u8": 1, "i8": 1, "bool": 1,
+    "u16": 2, "i16": 2, "IpPort": 2,
+    "u32": 4, "i32": 4, "f32": 4, "Ip4Addr": 4,
+    "u64": 8, "i64": 8, "f64": 8,
+    "Uuid": 16,
+    "Vector3<f32>": 12, "Vector3<f64>": 24, "Vector4<f32>": 16,
+    # Only the vector part is transmitted.
+    "Quaternion<f32>": 12,
+}
+
+def generate_block_encoded_len(block):
+    """ Generate an expression for the number of bytes the block (bound to self) is encoded to. """
+    fixed = 0
+    variable = []
+    for field in block.fields:
+        r_type = field.r_type
+        if r_type in FIELD_SIZES:
+            fixed += FIELD_SIZES[r_type]
+        elif r_type == "Vec<u8>":
+            fixed += int(field.count)
+            variable.append("self.%s.len()" % field.r_name)
+        elif r_type[0:4] == "[u8;":
+            fixed += int(field.count)
+        else:
+            raise RuntimeError("No rule for the size of r_type: %s" % r_type)
+    return " + ".join([str(fixed)] + variable)
+
 def generate_field_reader(field):
     r_type = field.r_type
     if r_type in "u16 u32 u64 i16 i32 i64 f32 f64":
@@ -111,10 +156,13 @@ def generate_field_reader(field):
                                buffer.read_f32::<LittleEndian>()?,
                                buffer.read_f32::<LittleEndian>()?)"""
     elif r_type == "Quaternion<f32>":
-        return """Quaternion::from_parts(1., Vector3::new(
-                      buffer.read_f32::<LittleEndian>()?,
-                      buffer.read_f32::<LittleEndian>()?,
-                      buffer.read_f32::<LittleEndian>()?))"""
+        # Only the vector part of the normalized quaternion is transmitted,
+        # the writer ensures that the scalar part is not negative.
+        return """{ let x = buffer.read_f32::<LittleEndian>()?;
+                    let y = buffer.read_f32::<LittleEndian>()?;
+                    let z = buffer.read_f32::<LittleEndian>()?;
+                    let w = (1. - (x * x + y * y + z * z)).max(0.).sqrt();
+                    Quaternion::new(w, x, y, z) }"""
     elif r_type == "bool":
         return "buffer.read_u8()? == 1"
     elif r_type == "Vec<u8>":
@@ -131,9 +179,88 @@ def generate_field_reader(field):
     else:
         raise RuntimeError("No rule for field reader generation of r_type: %s" % field)
 
+def generate_field_arbitrary(field):
+    """ Generate an expression creating an arbitrary value for the field, used by the tests. """
+    r_type = field.r_type
+    if r_type == "Vec<u8>":
+        if field.count == "1":
+            return "g.gen_bytes(255)"
+        elif field.count == "2":
+            return "g.gen_bytes(65535)"
+        else:
+            raise RuntimeError("invalid quantity for field: %s" % field.__dict__)
+    elif r_type[0:4] == "[u8;":
+        return "{ let mut raw = [0; %s]; g.fill_bytes(&mut raw); raw }" % field.count
+    else:
+        return "Arbitrary::arbitrary(g)"
+
+def generate_block_arbitrary_impl(block):
+    out = ""
+    out += "#[cfg(test)]\n"
+    out += "impl Arbitrary for %s {\n" % block.r_name
+    out += "\tfn arbitrary(g: &mut Gen) -> Self {\n"
+    out += "\t\t%s {\n" % block.r_name
+    for field in block.fields:
+        out += "\t\t\t%s: %s,\n" % (field.r_name, generate_field_arbitrary(field))
+    out += "\t\t}\n"
+    out += "\t}\n"
+    out += "}\n\n"
+    return out
+
+def generate_message_arbitrary_impl(message):
+    g_var = "g" if len(message.blocks) > 0 else "_"
+    out = ""
+    out += "#[cfg(test)]\n"
+    out += "impl Arbitrary for %s {\n" % message.name
+    out += "\tfn arbitrary(%s: &mut Gen) -> Self {\n" % g_var
+    out += "\t\t%s {\n" % message.name
+    for block in message.blocks:
+        if block.quantity == "single":
+            value = "Arbitrary::arbitrary(g)"
+        elif block.quantity == "multiple":
+            value = "ArrayVec::from([%s])" % ", ".join(["Arbitrary::arbitrary(g)"] * block.quantity_count)
+        elif block.quantity == "variable":
+            value = "(0..g.gen_len(255)).map(|_| Arbitrary::arbitrary(g)).collect()"
+        else:
+            raise RuntimeError("Invalid block quantity: %s" % block.quantity)
+        out += "\t\t\t%s: %s,\n" % (block.f_name, value)
+    out += "\t\t}\n"
+    out += "\t}\n"
+    out += "}\n\n"
+    return out
+
+def generate_block_validate(block):
+    """ Generate the method checking the lengths of the Variable fields of the block. """
+    checks = ""
+    for field in block.fields:
+        if field.r_type == "Vec<u8>":
+            max_len = {"1": 255, "2": 65535}[field.count]
+            checks += "\t\tif self.%s.len() > %s {\n" % (field.r_name, max_len)
+            checks += "\t\t\treturn Err(ValidationError::FieldTooLong {\n"
+            checks += "\t\t\t\tmessage: message,\n"
+            checks += "\t\t\t\tblock: \"%s\",\n" % block.ll_name
+            checks += "\t\t\t\tfield: \"%s\",\n" % field.r_name
+            checks += "\t\t\t\tlen: self.%s.len(),\n" % field.r_name
+            checks += "\t\t\t\tmax: %s,\n" % max_len
+            checks += "\t\t\t});\n"
+            checks += "\t\t}\n"
+
+    message_var = "message" if checks else "_message"
+    out = ""
+    out += "\tfn validate(&self, %s: &'static str) -> Result<(), ValidationError> {\n" % message_var
+    out += checks
+    out += "\t\tOk(())\n"
+    out += "\t}\n\n"
+    return out
+
 def generate_block_reader_impl(block):
     out = ""
     out += "impl %s {\n" % block.r_name
+    out += generate_block_validate(block)
+    out += "\t/// Returns the number of bytes the block is encoded to.\n"
+    out += "\tpub fn encoded_len(&self) -> usize {\n"
+    out += "\t\t%s\n" % generate_block_encoded_len(block)
+    out += "\t}\n\n"
     out += "\tfn read_from<R: ?Sized>(buffer: &mut R) -> Result<Self, ReadError> where R: Read {\n"
     out += "\t\tOk(%s {\n" % block.r_name
     for field in block.fields:
@@ -151,7 +278,7 @@ def generate_message_impl(message):
     #   Writer   #
     ##############
 
-    out += "\tfn write_to<W: ?Sized>(&self, buffer: &mut W) -> WriteMessageResult where W: Write {\n"
+    out += "\tfn write_unvalidated<W: ?Sized>(&self, buffer: &mut W) -> WriteMessageResult where W: Write {\n"
     out += "\t\t// Write the message number.\n"
     out += "\t\tbuffer.write(&%s)?;\n" % generate_message_id_bytes(message)
     for block in message.blocks:
@@ -175,6 +302,60 @@ def generate_message_impl(message):
     out += "\t\tOk(())\n"
     out += "\t}\n\n"
 
+    ##############
+    # Validation #
+    ##############
+
+    out += "\tfn validate(&self) -> Result<(), ValidationError> {\n"
+    for block in message.blocks:
+        if block.quantity == "single":
+            out += "\t\tself.%s.validate(\"%s\")?;\n" % (block.f_name, message.name)
+            continue
+
+        if block.quantity == "multiple":
+            out += "\t\tif self.%s.len() != %s {\n" % (block.f_name, block.quantity_count)
+            out += "\t\t\treturn Err(ValidationError::WrongBlockCount {\n"
+            out += "\t\t\t\tmessage: \"%s\",\n" % message.name
+            out += "\t\t\t\tblock: \"%s\",\n" % block.ll_name
+            out += "\t\t\t\tcount: self.%s.len(),\n" % block.f_name
+            out += "\t\t\t\texpected: %s,\n" % block.quantity_count
+            out += "\t\t\t});\n"
+            out += "\t\t}\n"
+        elif block.quantity == "variable":
+            out += "\t\tif self.%s.len() > 255 {\n" % block.f_name
+            out += "\t\t\treturn Err(ValidationError::TooManyBlocks {\n"
+            out += "\t\t\t\tmessage: \"%s\",\n" % message.name
+            out += "\t\t\t\tblock: \"%s\",\n" % block.ll_name
+            out += "\t\t\t\tcount: self.%s.len(),\n" % block.f_name
+            out += "\t\t\t});\n"
+            out += "\t\t}\n"
+        else:
+            raise RuntimeError("Invalid block quantity: %s" % block.quantity)
+        out += "\t\tfor block in &self.%s {\n" % block.f_name
+        out += "\t\t\tblock.validate(\"%s\")?;\n" % message.name
+        out += "\t\t}\n"
+    out += "\t\tOk(())\n"
+    out += "\t}\n\n"
+
+    ##############
+    #   Length   #
+    ##############
+
+    number_len = len(generate_message_id_bytes(message).split(","))
+    out += "\tfn encoded_len(&self) -> usize {\n"
+    out += "\t\t%s" % number_len
+    for block in message.blocks:
+        if block.quantity == "single":
+            out += "\n\t\t+ self.%s.encoded_len()" % block.f_name
+        elif block.quantity == "multiple" or block.quantity == "variable":
+            # Variable blocks are preceded by their count.
+            if block.quantity == "variable":
+                out += "\n\t\t+ 1"
+            out += "\n\t\t+ self.%s.iter().map(|b| b.encoded_len()).sum::<usize>()" % block.f_name
+        else:
+            raise RuntimeError("Invalid block quantity: %s" % block.quantity)
+    out += "\n\t}\n\n"
+
     ##############
     #   Reader   #
     ##############
@@ -213,6 +394,90 @@ def generate_message_impl(message):
 
     return out
 
+# Messages which may be split into multiple packets. Their variable block describes
+# independent items, e.g. objects, while the other blocks only identify the agent.
+# Other messages describe a single operation, like linking objects, which must not be
+# applied in pieces.
+SPLITTABLE_MESSAGES = {
+    "FetchInventory",
+    "ImprovedTerseObjectUpdate",
+    "KillObject",
+    "ObjectCategory",
+    "ObjectClickAction",
+    "ObjectDescription",
+    "ObjectDeselect",
+    "ObjectExtraParams",
+    "ObjectImage",
+    "ObjectIncludeInSearch",
+    "ObjectMaterial",
+    "ObjectName",
+    "ObjectPermissions",
+    "ObjectProperties",
+    "ObjectSaleInfo",
+    "ObjectSelect",
+    "ObjectShape",
+    "ObjectUpdate",
+    "ObjectUpdateCached",
+    "ObjectUpdateCompressed",
+    "PacketAck",
+    "RequestImage",
+    "RequestMultipleObjects",
+    "UUIDGroupNameReply",
+    "UUIDGroupNameRequest",
+    "UUIDNameReply",
+    "UUIDNameRequest",
+    "ViewerEffect",
+}
+
+def splittable_block(message):
+    """
+    Returns the variable block along which the message can be split, if it is one of
+    SPLITTABLE_MESSAGES and there is exactly one.
+    """
+    if message.name not in SPLITTABLE_MESSAGES:
+        return None
+    variable = [b for b in message.blocks if b.quantity == "variable"]
+    if len(variable) == 1:
+        return variable[0]
+    return None
+
+def generate_message_split_impl(message):
+    """ Generate the split method for the messages which can be split. """
+    split_block = splittable_block(message)
+    if split_block is None:
+        return ""
+
+    blocks = "self.%s" % split_block.f_name
+    out = ""
+    out += "impl %s {\n" % message.name
+    out += "\t/// Split the message into messages encoded to at most `max_len` bytes each,\n"
+    out += "\t/// distributing the `%s` blocks among them.\n" % split_block.ll_name
+    out += "\t///\n"
+    out += "\t/// Returns the message unchanged if it can't be split, i.e. if the other blocks\n"
+    out += "\t/// and a single `%s` block don't fit together.\n" % split_block.ll_name
+    out += "\tpub fn split(self, max_len: usize) -> Result<Vec<%s>, %s> {\n" % (message.name, message.name)
+    out += "\t\tlet blocks_len = %s.iter().map(|b| b.encoded_len()).sum::<usize>();\n" % blocks
+    out += "\t\tlet base_len = self.encoded_len() - blocks_len;\n"
+    out += "\t\tif base_len + blocks_len <= max_len && %s.len() <= MAX_VARIABLE_BLOCKS {\n" % blocks
+    out += "\t\t\treturn Ok(vec![self]);\n"
+    out += "\t\t}\n"
+    out += "\t\tlet ranges = match max_len\n"
+    out += "\t\t\t.checked_sub(base_len)\n"
+    out += "\t\t\t.and_then(|room| split_blocks(&%s, |b| b.encoded_len(), room)) {\n" % blocks
+    out += "\t\t\tSome(ranges) => ranges,\n"
+    out += "\t\t\tNone => return Err(self),\n"
+    out += "\t\t};\n"
+    out += "\t\tOk(ranges.into_iter().map(|range| %s {\n" % message.name
+    for block in message.blocks:
+        if block is split_block:
+            out += "\t\t\t%s: %s[range].to_vec(),\n" % (block.f_name, blocks)
+        else:
+            out += "\t\t\t%s: self.%s.clone(),\n" % (block.f_name, block.f_name)
+    out += "\t\t}).collect())\n"
+    out += "\t}\n"
+    out += "}\n\n"
+    return out
+
 def generate_message_type_enum(all_msgnames):
     code = ""
     code += "#[derive(Clone, Debug, Eq, Hash, PartialEq)]\n"
@@ -222,9 +487,37 @@ def generate_message_type_enum(all_msgnames):
     code += "}\n\n"
     return code
 
+# Conversion from the deprecation markers of the message template to the Rust enum variants.
+DEPRECATIONS = {
+    None: "NotDeprecated",
+    "deprecated": "Deprecated",
+    "udpdeprecated": "UdpDeprecated",
+    "udpblacklisted": "UdpBlackListed",
+}
+
+def generate_message_type_impl(messages):
+    code = ""
+    code += "impl MessageType {\n"
+    code += "\t/// Returns the metadata of the message as specified by the message template.\n"
+    code += "\tpub fn meta(&self) -> MessageMeta {\n"
+    code += "\t\tmatch *self {\n"
+    for message in messages:
+        code += "\t\t\tMessageType::%s => MessageMeta {\n" % message.name
+        code += "\t\t\t\tfrequency: MessageFrequency::%s,\n" % message.frequency_class.capitalize()
+        code += "\t\t\t\tnumber: %s,\n" % message.message_num
+        code += "\t\t\t\ttrusted: %s,\n" % str(message.is_trusted).lower()
+        code += "\t\t\t\tzerocoded: %s,\n" % str(message.is_zerocoded).lower()
+        code += "\t\t\t\tdeprecation: Deprecation::%s,\n" % DEPRECATIONS[message.deprecation]
+        code += "\t\t\t},\n"
+    code += "\t\t}\n"
+    code += "\t}\n"
+    code += "}\n\n"
+    return code
+
 def generate_message_instance_enum(all_msgnames, messages):
     code = ""
-    code += "#[derive(Clone, Debug)]\n"
+    code += "#[derive(Clone, Debug, PartialEq)]\n"
+    code += SERDE_DERIVE
     code += "pub enum MessageInstance {\n"
     for name in all_msgnames:
         code += "\t%s(%s),\n" % (name, name)
@@ -246,8 +539,56 @@ def generate_message_instance_enum(all_msgnames, messages):
     for name in all_msgnames:
         code += "\t\t\tMessageInstance::%s(ref msg) => msg.write_to(buffer),\n" % name
     code += "\t\t}\n"
+    code += "\t}\n\n"
+
+    # MessageInstance::write_unvalidated
+    code += "\t/// Write the message like `write_to`, but without validating it first.\n"
+    code += "\t///\n"
+    code += "\t/// Only use this for messages which were validated before, an invalid\n"
+    code += "\t/// message is written incorrectly.\n"
+    code += "\tpub fn write_unvalidated<W: Write>(&self, buffer: &mut W) -> WriteMessageResult {\n"
+    code += "\t\tmatch *self {\n"
+    for name in all_msgnames:
+        code += "\t\t\tMessageInstance::%s(ref msg) => msg.write_unvalidated(buffer),\n" % name
+    code += "\t\t}\n"
     code += "\t}\n"
 
+    # MessageInstance::validate
+    code += "\t/// Check that the message respects the limits of the message template, i.e. it can be written.\n"
+    code += "\tpub fn validate(&self) -> Result<(), ValidationError> {\n"
+    code += "\t\tmatch *self {\n"
+    for name in all_msgnames:
+        code += "\t\t\tMessageInstance::%s(ref msg) => msg.validate(),\n" % name
+    code += "\t\t}\n"
+    code += "\t}\n\n"
+
+    # MessageInstance::encoded_len
+    code += "\t/// Returns the number of bytes the message is encoded to, including the message number.\n"
+    code += "\tpub fn encoded_len(&self) -> usize {\n"
+    code += "\t\tmatch *self {\n"
+    for name in all_msgnames:
+        code += "\t\t\tMessageInstance::%s(ref msg) => msg.encoded_len(),\n" % name
+    code += "\t\t}\n"
+    code += "\t}\n\n"
+
+    # MessageInstance::split
+    code += "\t/// Split the message into messages encoded to at most `max_len` bytes each.\n"
+    code += "\t///\n"
+    code += "\t/// Only messages whose variable blocks describe independent items, like the\n"
+    code += "\t/// objects of an `ObjectUpdate`, can be split. Their blocks are distributed among\n"
+    code += "\t/// the parts while all other blocks are copied. Returns the message unchanged if\n"
+    code += "\t/// it is too large but can't be split.\n"
+    code += "\tpub fn split(self, max_len: usize) -> Result<Vec<MessageInstance>, MessageInstance> {\n"
+    code += "\t\tmatch self {\n"
+    for message in messages:
+        if splittable_block(message) is not None:
+            code += "\t\t\tMessageInstance::%s(msg) => msg.split(max_len)\n" % message.name
+            code += "\t\t\t\t.map(|parts| parts.into_iter().map(MessageInstance::from).collect())\n"
+            code += "\t\t\t\t.map_err(MessageInstance::from),\n"
+    code += "\t\t\tmsg => if msg.encoded_len() <= max_len { Ok(vec![msg]) } else { Err(msg) },\n"
+    code += "\t\t}\n"
+    code += "\t}\n\n"
+
     # MessageInstance::read_message
     code += "\tpub fn read_message<R: ?Sized>(buffer: &mut R, message_num: u32) -> Result<MessageInstance, ReadError> where R: Read {\n"
     code += "\t\tmatch message_num {\n"
@@ -259,6 +600,18 @@ def generate_message_instance_enum(all_msgnames, messages):
 
     code += "}\n\n"
 
+    # MessageInstance::arbitrary_all
+    code += "#[cfg(test)]\n"
+    code += "impl MessageInstance {\n"
+    code += "\t/// Returns an arbitrary instance of each message type.\n"
+    code += "\tpub(crate) fn arbitrary_all(g: &mut Gen) -> Vec<MessageInstance> {\n"
+    code += "\t\tvec![\n"
+    for name in all_msgnames:
+        code += "\t\t\tMessageInstance::%s(Arbitrary::arbitrary(g)),\n" % name
+    code += "\t\t]\n"
+    code += "\t}\n"
+    code += "}\n\n"
+
     for name in all_msgnames:
         code += "impl From<%s> for MessageInstance {\n" % name
         code += "\tfn from(msg: %s) -> Self {\n" % name
@@ -266,4 +619,14 @@ def generate_message_instance_enum(all_msgnames, messages):
         code += "\t}\n"
         code += "}\n\n"
 
+        code += "impl TypedMessage for %s {\n" % name
+        code += "\tconst TYPE: MessageType = MessageType::%s;\n\n" % name
+        code += "\tfn from_instance(msg: MessageInstance) -> Result<Self, MessageInstance> {\n"
+        code += "\t\tmatch msg {\n"
+        code += "\t\t\tMessageInstance::%s(msg) => Ok(msg),\n" % name
+        code += "\t\t\tmsg => Err(msg),\n"
+        code += "\t\t}\n"
+        code += "\t}\n"
+        code += "}\n\n"
+
     return code
@@ -136,9 +136,19 @@ class Message:
         # TODO ? self.id = 
         self.trusted = xml_obj.trusted
         self.compression = xml_obj.compression
+        # Only specified for deprecated messages.
+        self.deprecation = getattr(xml_obj, "deprecation", None)
         self.blocks = [Block(block, self) for block in xml_obj.block]
         self.doc = to_rust_doc(xml_obj.doc)
 
+    @property
+    def is_trusted(self):
+        return self.trusted == "trusted"
+
+    @property
+    def is_zerocoded(self):
+        return self.compression == "zerocoded"
+
     @property
     def message_num(self):
         bs = [self.id_byte(n)[2:4] for n in reversed(range(4))]
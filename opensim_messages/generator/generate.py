@@ -24,10 +24,15 @@ def list_all_messages():
 
     msgs_1 = map(file_to_msgname, glob("protocol/messages/*.xml"))
     msgs_2 = map(file_to_msgname, glob("protocol/messages-original/*.xml"))
-    return list(sorted(set(itertools.chain(msgs_1, msgs_2))))
+    msgs_3 = map(file_to_msgname, glob("messages-local/*.xml"))
+    return list(sorted(set(itertools.chain(msgs_1, msgs_2, msgs_3))))
 
 def open_message_xml(msgname):
     """ Returns a file handle to the relevant file for the message with the specified name. """
+    # Local changes which were not upstreamed to the protocol repository yet.
+    path_0 = os.path.join("messages-local", "{}.xml".format(msgname))
+    if os.path.exists(path_0):
+        return open(path_0, "r")
     path_1 = os.path.join("protocol", "messages", "{}.xml".format(msgname))
     path_2 = os.path.join("protocol", "messages-original", "{}.xml".format(msgname))
     if os.path.exists(path_1):
@@ -61,6 +66,7 @@ if __name__ == "__main__":
             f.write(gen.code.generate_struct(message))
 
         f.write(gen.code.generate_message_type_enum(all_msgnames))
+        f.write(gen.code.generate_message_type_impl(messages))
         f.write(gen.code.generate_message_instance_enum(all_msgnames, messages))
 
         f.write("\n\n\n\n// BLOCK IMPLEMENTATIONS\n\n")
@@ -72,6 +78,13 @@ if __name__ == "__main__":
         for message in messages:
             code = gen.code.generate_message_impl(message)
             f.write(code)
+            f.write(gen.code.generate_message_split_impl(message))
+
+        f.write("\n\n\n\n// ARBITRARY INSTANCES (TESTS)\n\n")
+        for message in messages:
+            for block in message.blocks:
+                f.write(gen.code.generate_block_arbitrary_impl(block))
+            f.write(gen.code.generate_message_arbitrary_impl(message))
 
     # Format the file.
     subprocess.run(["cargo", "fmt"], cwd=TARGET_DIR)
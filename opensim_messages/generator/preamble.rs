@@ -1,6 +1,10 @@
 use {Vector3, Vector4, Quaternion, Ip4Addr, IpPort, Uuid, WriteMessageResult, Message,
-     ReadError};
+     ReadError, ValidationError, Deprecation, MessageFrequency, MessageMeta, TypedMessage};
 
 use arrayvec::ArrayVec;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use split::{split_blocks, MAX_VARIABLE_BLOCKS};
 use std::io::{Read, Write};
+
+#[cfg(test)]
+use arbitrary::{Arbitrary, Gen};
@@ -0,0 +1,176 @@
+//! Generation of arbitrary message instances, used by the tests.
+//!
+//! The implementations for the message and block structs are generated, see
+//! `MessageInstance::arbitrary_all`.
+
+use {Ip4Addr, Quaternion, Uuid, Vector3, Vector4};
+
+/// Upper bound for the length of variable sized data in `Gen::new` mode,
+/// so the tests don't spend all their time copying bytes around.
+const RANDOM_MAX_LEN: usize = 64;
+
+enum Mode {
+    Random,
+    Empty,
+    Full,
+}
+
+/// Source of the arbitrary values, a deterministic pseudo random generator
+/// (xorshift64*) so failures are reproducible.
+pub struct Gen {
+    state: u64,
+    mode: Mode,
+}
+
+impl Gen {
+    /// Random values, variable sized data has a random length.
+    pub fn new(seed: u64) -> Self {
+        Gen {
+            // The state must never be zero.
+            state: seed ^ 0x9e37_79b9_7f4a_7c15,
+            mode: Mode::Random,
+        }
+    }
+
+    /// Random values, but all variable sized data is empty.
+    pub fn empty() -> Self {
+        Gen {
+            mode: Mode::Empty,
+            ..Gen::new(0)
+        }
+    }
+
+    /// Random values, but all variable sized data has its maximum length.
+    pub fn full() -> Self {
+        Gen {
+            mode: Mode::Full,
+            ..Gen::new(0)
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Length of variable sized data (block counts or byte fields) with the
+    /// provided maximum.
+    pub fn gen_len(&mut self, max: usize) -> usize {
+        match self.mode {
+            Mode::Random => {
+                let max = if max < RANDOM_MAX_LEN {
+                    max
+                } else {
+                    RANDOM_MAX_LEN
+                };
+                (self.next_u64() % (max as u64 + 1)) as usize
+            }
+            Mode::Empty => 0,
+            Mode::Full => max,
+        }
+    }
+
+    pub fn gen_bytes(&mut self, max: usize) -> Vec<u8> {
+        let mut bytes = vec![0; self.gen_len(max)];
+        self.fill_bytes(&mut bytes);
+        bytes
+    }
+
+    pub fn fill_bytes(&mut self, buf: &mut [u8]) {
+        for byte in buf.iter_mut() {
+            *byte = self.next_u64() as u8;
+        }
+    }
+}
+
+pub trait Arbitrary {
+    fn arbitrary(g: &mut Gen) -> Self;
+}
+
+macro_rules! impl_arbitrary_int {
+    ($($t:ty),*) => {
+        $(
+            impl Arbitrary for $t {
+                fn arbitrary(g: &mut Gen) -> Self {
+                    g.next_u64() as $t
+                }
+            }
+        )*
+    };
+}
+
+impl_arbitrary_int!(u8, u16, u32, u64, i8, i16, i32, i64);
+
+// Floats are created from integers, this way they are never NaN and equality
+// of the round trip holds.
+impl Arbitrary for f32 {
+    fn arbitrary(g: &mut Gen) -> Self {
+        (g.next_u64() as i32) as f32 / 1024.
+    }
+}
+
+impl Arbitrary for f64 {
+    fn arbitrary(g: &mut Gen) -> Self {
+        (g.next_u64() as i64) as f64 / 1024.
+    }
+}
+
+impl Arbitrary for bool {
+    fn arbitrary(g: &mut Gen) -> Self {
+        g.next_u64() % 2 == 0
+    }
+}
+
+impl Arbitrary for Uuid {
+    fn arbitrary(g: &mut Gen) -> Self {
+        let mut raw = [0u8; 16];
+        g.fill_bytes(&mut raw);
+        Uuid::from_bytes(raw)
+    }
+}
+
+impl Arbitrary for Ip4Addr {
+    fn arbitrary(g: &mut Gen) -> Self {
+        Ip4Addr::from(g.next_u64() as u32)
+    }
+}
+
+impl Arbitrary for Vector3<f32> {
+    fn arbitrary(g: &mut Gen) -> Self {
+        Vector3::new(f32::arbitrary(g), f32::arbitrary(g), f32::arbitrary(g))
+    }
+}
+
+impl Arbitrary for Vector3<f64> {
+    fn arbitrary(g: &mut Gen) -> Self {
+        Vector3::new(f64::arbitrary(g), f64::arbitrary(g), f64::arbitrary(g))
+    }
+}
+
+impl Arbitrary for Vector4<f32> {
+    fn arbitrary(g: &mut Gen) -> Self {
+        Vector4::new(
+            f32::arbitrary(g),
+            f32::arbitrary(g),
+            f32::arbitrary(g),
+            f32::arbitrary(g),
+        )
+    }
+}
+
+impl Arbitrary for Quaternion<f32> {
+    /// Only normalized quaternions with a non-negative scalar part survive the
+    /// round trip, these are picked so no rounding takes place.
+    fn arbitrary(g: &mut Gen) -> Self {
+        let (w, i, j, k) = match g.next_u64() % 5 {
+            0 => (1., 0., 0., 0.),
+            1 => (0., 1., 0., 0.),
+            2 => (0., 0., -1., 0.),
+            3 => (0., 0., 0., 1.),
+            _ => (0.5, -0.5, 0.5, -0.5),
+        };
+        Quaternion::new(w, i, j, k)
+    }
+}
@@ -35,3 +35,64 @@ impl From<IoError> for ReadError {
         ReadError::IoError(e)
     }
 }
+
+#[derive(Debug, Fail)]
+pub enum WriteError {
+    #[fail(display = "IO error: {}", _0)]
+    IoError(#[cause] ::std::io::Error),
+
+    #[fail(display = "Invalid message: {}", _0)]
+    Invalid(#[cause] ValidationError),
+}
+
+impl From<IoError> for WriteError {
+    fn from(e: IoError) -> Self {
+        WriteError::IoError(e)
+    }
+}
+
+impl From<ValidationError> for WriteError {
+    fn from(e: ValidationError) -> Self {
+        WriteError::Invalid(e)
+    }
+}
+
+/// A message violates a limit of the message template and can't be written.
+///
+/// Messages and blocks are named like in the message template, fields by
+/// their name in the generated struct.
+#[derive(Clone, Copy, Debug, Fail, PartialEq, Eq)]
+pub enum ValidationError {
+    #[fail(
+        display = "{}.{}.{} has {} bytes, at most {} are allowed.",
+        message, block, field, len, max
+    )]
+    FieldTooLong {
+        message: &'static str,
+        block: &'static str,
+        field: &'static str,
+        len: usize,
+        max: usize,
+    },
+
+    #[fail(
+        display = "{}.{} has {} blocks, at most 255 are allowed.",
+        message, block, count
+    )]
+    TooManyBlocks {
+        message: &'static str,
+        block: &'static str,
+        count: usize,
+    },
+
+    #[fail(
+        display = "{}.{} has {} blocks, exactly {} are required.",
+        message, block, count, expected
+    )]
+    WrongBlockCount {
+        message: &'static str,
+        block: &'static str,
+        count: usize,
+        expected: usize,
+    },
+}
@@ -4,21 +4,68 @@ extern crate arrayvec;
 extern crate byteorder;
 #[macro_use]
 extern crate failure;
+#[cfg(feature = "serde-serialize")]
+extern crate serde;
+#[cfg(feature = "serde-serialize")]
+#[macro_use]
+extern crate serde_derive;
+#[cfg(all(test, feature = "serde-serialize"))]
+#[macro_use]
+extern crate serde_json;
 
 use opensim_types::*;
 use std::io::{Read, Write};
 
 mod errors;
-pub use self::errors::ReadError;
+pub use self::errors::{ReadError, ValidationError, WriteError};
+
+mod meta;
+pub use self::meta::{Deprecation, MessageFrequency, MessageMeta};
+
+/// Messages defined by a message template loaded at runtime.
+pub mod template;
+
+#[cfg(feature = "serde-serialize")]
+mod serde_fields;
+
+mod split;
+pub use self::split::MAX_VARIABLE_BLOCKS;
+
+#[cfg(test)]
+mod arbitrary;
+#[cfg(test)]
+mod tests;
 
-pub type WriteMessageResult = ::std::io::Result<()>;
+pub type WriteMessageResult = Result<(), WriteError>;
 
 pub trait Message {
     /// Write the message to a buffer for network transmission.
+    ///
+    /// The message is validated first, nothing is written if this fails.
     fn write_to<W: ?Sized>(&self, buffer: &mut W) -> WriteMessageResult
+    where
+        W: Write,
+    {
+        self.validate()?;
+        self.write_unvalidated(buffer)
+    }
+
+    /// Write the message like `write_to`, but without validating it first.
+    ///
+    /// Only use this for messages which were validated before, an invalid
+    /// message is written incorrectly.
+    fn write_unvalidated<W: ?Sized>(&self, buffer: &mut W) -> WriteMessageResult
     where
         W: Write;
 
+    /// Check that the message respects the limits of the message template,
+    /// i.e. it can be written.
+    fn validate(&self) -> Result<(), ValidationError>;
+
+    /// Returns the number of bytes `write_to` writes, without encoding the
+    /// message.
+    fn encoded_len(&self) -> usize;
+
     /// Read the message from a buffer obtained from the network.
     /// When this function is invoked it is assumed that the message number has
     /// already been read from the buffer object and the body of the message
@@ -28,6 +75,16 @@ pub trait Message {
         R: Read;
 }
 
+/// A message struct of a known `MessageType`, implemented by all messages.
+pub trait TypedMessage: Into<MessageInstance> + Sized {
+    /// The type of the message.
+    const TYPE: MessageType;
+
+    /// Extract the message from an instance, which is returned unchanged if
+    /// it is of another type.
+    fn from_instance(msg: MessageInstance) -> Result<Self, MessageInstance>;
+}
+
 /// Contains all available messages.
 pub mod all;
 
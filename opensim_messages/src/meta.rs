@@ -0,0 +1,83 @@
+//! Metadata of the messages as specified by the message template.
+//!
+//! The actual values are generated for each message, see `MessageType::meta`.
+
+/// The frequency class of a message, it determines how many bytes are used
+/// to encode the message number.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum MessageFrequency {
+    /// One byte message number, used for messages which are sent very often.
+    High,
+    /// Two byte message number.
+    Medium,
+    /// Four byte message number.
+    Low,
+    /// Four byte message number, reserved for a few special messages like
+    /// `PacketAck`.
+    Fixed,
+}
+
+impl MessageFrequency {
+    /// Returns the number of bytes the message number takes up on the wire.
+    pub fn number_len(&self) -> usize {
+        match *self {
+            MessageFrequency::High => 1,
+            MessageFrequency::Medium => 2,
+            MessageFrequency::Low | MessageFrequency::Fixed => 4,
+        }
+    }
+}
+
+/// Whether a message should still be used.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Deprecation {
+    /// The message can be used.
+    NotDeprecated,
+    /// The message is not in use anymore.
+    Deprecated,
+    /// The message should not be sent over UDP anymore, there is a capability
+    /// replacing it.
+    UdpDeprecated,
+    /// The message must not be sent over UDP, it will be dropped by the
+    /// receiving end.
+    UdpBlackListed,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct MessageMeta {
+    pub frequency: MessageFrequency,
+    /// The message number as it is passed to `MessageInstance::read_message`.
+    pub number: u32,
+    /// Trusted messages must only be accepted through trusted circuits,
+    /// i.e. between simulators but never on a viewer's circuit.
+    pub trusted: bool,
+    /// Whether the message is supposed to be sent zerocoded.
+    pub zerocoded: bool,
+    pub deprecation: Deprecation,
+}
+
+impl MessageMeta {
+    /// Returns false if the message must not be sent through a circuit.
+    pub fn is_sendable(&self) -> bool {
+        match self.deprecation {
+            Deprecation::NotDeprecated | Deprecation::UdpDeprecated => true,
+            Deprecation::Deprecated | Deprecation::UdpBlackListed => false,
+        }
+    }
+}
+
+/// Convert the message id as it is specified in the message template to the
+/// message number as it is used by `MessageMeta` and
+/// `MessageInstance::read_message`.
+///
+/// The message number consists of the bytes written to the wire in little
+/// endian order. Returns `None` if the id is out of range for the frequency.
+pub(crate) fn message_number(frequency: MessageFrequency, id: u32) -> Option<u32> {
+    match frequency {
+        MessageFrequency::High if id < 0xff => Some(id),
+        MessageFrequency::Medium if id < 0xff => Some(0xff | id << 8),
+        MessageFrequency::Low if id <= 0xffff => Some(0xffff | (id >> 8) << 16 | (id & 0xff) << 24),
+        MessageFrequency::Fixed if id >> 8 == 0xff_ffff => Some(0xff_ffff | (id & 0xff) << 24),
+        _ => None,
+    }
+}
@@ -0,0 +1,188 @@
+//! Serde representations of the field types which don't have one of their own,
+//! or whose representation depends on the version of the providing crate.
+//!
+//! These are used by the generated code with `#[serde(with = "...")]`, so
+//! serialized messages stay readable by later versions of this crate:
+//!
+//! - `Uuid`: hyphenated lowercase string.
+//! - `Vector3`, `Vector4`: array of the components `[x, y, z(, w)]`.
+//! - `Quaternion`: array of the components `[x, y, z, w]`.
+//! - Byte fields (`Vec<u8>` and `[u8; N]`): lowercase hex string.
+//!
+//! `Ip4Addr` uses the representation of the standard library, i.e. a string
+//! like `"127.0.0.1"` for human readable formats.
+
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use {Quaternion, Uuid, Vector3, Vector4};
+
+pub mod uuid {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(value: &Uuid, serializer: S) -> Result<S::Ok, S::Error> {
+        value.to_hyphenated().to_string().serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Uuid, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Uuid::parse_str(&s).map_err(|e| D::Error::custom(format!("invalid UUID: {}", e)))
+    }
+}
+
+pub mod vector3 {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        value: &Vector3<f32>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        [value.x, value.y, value.z].serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vector3<f32>, D::Error> {
+        let [x, y, z] = <[f32; 3]>::deserialize(deserializer)?;
+        Ok(Vector3::new(x, y, z))
+    }
+}
+
+pub mod vector3d {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        value: &Vector3<f64>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        [value.x, value.y, value.z].serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vector3<f64>, D::Error> {
+        let [x, y, z] = <[f64; 3]>::deserialize(deserializer)?;
+        Ok(Vector3::new(x, y, z))
+    }
+}
+
+pub mod vector4 {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        value: &Vector4<f32>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        [value.x, value.y, value.z, value.w].serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vector4<f32>, D::Error> {
+        let [x, y, z, w] = <[f32; 4]>::deserialize(deserializer)?;
+        Ok(Vector4::new(x, y, z, w))
+    }
+}
+
+pub mod quaternion {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        value: &Quaternion<f32>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        [value.i, value.j, value.k, value.w].serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Quaternion<f32>, D::Error> {
+        let [x, y, z, w] = <[f32; 4]>::deserialize(deserializer)?;
+        Ok(Quaternion::new(w, x, y, z))
+    }
+}
+
+/// Variable sized byte fields.
+pub mod bytes {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(value: &Vec<u8>, serializer: S) -> Result<S::Ok, S::Error> {
+        to_hex(value).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        from_hex(&s).map_err(D::Error::custom)
+    }
+}
+
+/// Fixed sized byte fields.
+pub mod fixed_bytes {
+    use super::*;
+
+    pub fn serialize<T, S>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        T: AsRef<[u8]>,
+        S: Serializer,
+    {
+        to_hex(value.as_ref()).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+    where
+        T: Default + AsMut<[u8]>,
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        let bytes = from_hex(&s).map_err(D::Error::custom)?;
+
+        let mut value = T::default();
+        if bytes.len() != value.as_mut().len() {
+            return Err(D::Error::custom(format!(
+                "expected {} bytes, found {}",
+                value.as_mut().len(),
+                bytes.len()
+            )));
+        }
+        value.as_mut().copy_from_slice(&bytes);
+        Ok(value)
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        s.push_str(&format!("{:02x}", byte));
+    }
+    s
+}
+
+fn from_hex(s: &str) -> Result<Vec<u8>, String> {
+    if s.len() % 2 != 0 {
+        return Err(format!("hex string of odd length: {}", s.len()));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            s.get(i..i + 2)
+                .filter(|digits| digits.bytes().all(|b| b.is_ascii_hexdigit()))
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .ok_or_else(|| format!("invalid hex string: {:?}", s))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_hex, to_hex};
+
+    #[test]
+    fn hex_round_trip() {
+        let bytes = vec![0x00, 0x0f, 0xa0, 0xff];
+        assert_eq!(to_hex(&bytes), "000fa0ff");
+        assert_eq!(from_hex("000fa0ff").unwrap(), bytes);
+        assert_eq!(from_hex("000FA0FF").unwrap(), bytes);
+        assert!(from_hex("abc").is_err());
+        assert!(from_hex("zz").is_err());
+        assert!(from_hex("+1").is_err());
+    }
+}
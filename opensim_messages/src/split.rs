@@ -0,0 +1,67 @@
+//! Splitting of messages which are too large to be sent in one packet.
+//!
+//! The per message `split` methods are generated for the messages listed in
+//! `SPLITTABLE_MESSAGES` of the generator, whose only variable block describes
+//! independent items. The remaining blocks are copied into each part.
+
+use std::ops::Range;
+
+/// Maximum number of instances of a variable block, its count is encoded in
+/// one byte.
+pub const MAX_VARIABLE_BLOCKS: usize = 255;
+
+/// Distributes `blocks` into consecutive ranges, each containing at most
+/// `MAX_VARIABLE_BLOCKS` blocks with a total encoded length of at most `room`.
+///
+/// Returns `None` if a single block is larger than `room`.
+pub(crate) fn split_blocks<B, F>(
+    blocks: &[B],
+    encoded_len: F,
+    room: usize,
+) -> Option<Vec<Range<usize>>>
+where
+    F: Fn(&B) -> usize,
+{
+    let mut ranges = Vec::new();
+    let mut start = 0;
+    let mut len = 0;
+    for (i, block) in blocks.iter().enumerate() {
+        let block_len = encoded_len(block);
+        if block_len > room {
+            return None;
+        }
+        if len + block_len > room || i - start == MAX_VARIABLE_BLOCKS {
+            ranges.push(start..i);
+            start = i;
+            len = 0;
+        }
+        len += block_len;
+    }
+    ranges.push(start..blocks.len());
+    Some(ranges)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_by_length() {
+        let blocks = [3, 3, 3, 5, 1];
+        let ranges = split_blocks(&blocks, |b| *b, 6).unwrap();
+        assert_eq!(ranges, vec![0..2, 2..3, 3..5]);
+    }
+
+    #[test]
+    fn split_by_count() {
+        let blocks = vec![0; 600];
+        let ranges = split_blocks(&blocks, |_| 1, 1000).unwrap();
+        assert_eq!(ranges, vec![0..255, 255..510, 510..600]);
+    }
+
+    #[test]
+    fn split_impossible() {
+        assert_eq!(split_blocks(&[1, 7], |b| *b, 6), None);
+        assert_eq!(split_blocks::<u8, _>(&[], |_| 1, 6), Some(vec![0..0]));
+    }
+}
@@ -0,0 +1,759 @@
+//! Runtime support for messages defined in a `message_template.msg` file.
+//!
+//! The messages in `all` are generated at compile time, so any message not
+//! known when this crate was built (e.g. extension messages of an OpenSim
+//! fork) fails to decode with `ReadError::UnknownMessageNumber`. The
+//! `MessageTemplates` registry loads a template file at runtime instead and
+//! decodes the messages into a generic tree of blocks and fields
+//! (`DynamicMessage`), which can also be encoded again.
+
+use {Ip4Addr, IpPort, MessageMeta, Quaternion, ReadError, Uuid, Vector3, Vector4};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+mod parser;
+
+#[derive(Debug, Fail)]
+pub enum TemplateError {
+    #[fail(display = "IO error: {}", _0)]
+    IoError(#[cause] ::std::io::Error),
+
+    #[fail(display = "Parsing the template failed on line {}: {}", line, msg)]
+    Parse { line: usize, msg: String },
+
+    #[fail(display = "No template for message {} found.", _0)]
+    UnknownMessage(String),
+
+    #[fail(display = "Message {} does not match its template: {}", message, msg)]
+    Mismatch { message: String, msg: String },
+}
+
+impl From<::std::io::Error> for TemplateError {
+    fn from(e: ::std::io::Error) -> Self {
+        TemplateError::IoError(e)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FieldType {
+    U8,
+    U16,
+    U32,
+    U64,
+    S8,
+    S16,
+    S32,
+    S64,
+    F32,
+    F64,
+    Uuid,
+    Bool,
+    IpAddr,
+    IpPort,
+    Vector3,
+    Vector3d,
+    Vector4,
+    Quaternion,
+    /// Fixed number of bytes.
+    Fixed(usize),
+    /// Up to 255 bytes, preceded by a one byte length.
+    Variable1,
+    /// Up to 65535 bytes, preceded by a two byte length.
+    Variable2,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BlockQuantity {
+    /// The block occurs exactly once.
+    Single,
+    /// The block occurs exactly the specified number of times.
+    Multiple(u8),
+    /// The block occurs up to 255 times, preceded by a one byte count.
+    Variable,
+}
+
+#[derive(Clone, Debug)]
+pub struct FieldTemplate {
+    pub name: String,
+    pub field_type: FieldType,
+}
+
+#[derive(Clone, Debug)]
+pub struct BlockTemplate {
+    pub name: String,
+    pub quantity: BlockQuantity,
+    pub fields: Vec<FieldTemplate>,
+}
+
+#[derive(Clone, Debug)]
+pub struct MessageTemplate {
+    pub name: String,
+    pub meta: MessageMeta,
+    pub blocks: Vec<BlockTemplate>,
+}
+
+/// The value of a single field of a `DynamicMessage`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FieldValue {
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    U64(u64),
+    S8(i8),
+    S16(i16),
+    S32(i32),
+    S64(i64),
+    F32(f32),
+    F64(f64),
+    Uuid(Uuid),
+    Bool(bool),
+    IpAddr(Ip4Addr),
+    IpPort(IpPort),
+    Vector3(Vector3<f32>),
+    Vector3d(Vector3<f64>),
+    Vector4(Vector4<f32>),
+    Quaternion(Quaternion<f32>),
+    Fixed(Vec<u8>),
+    Variable(Vec<u8>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DynamicField {
+    pub name: String,
+    pub value: FieldValue,
+}
+
+/// All instances of one block of a message.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DynamicBlock {
+    pub name: String,
+    /// The fields of each instance of the block, in template order.
+    pub instances: Vec<Vec<DynamicField>>,
+}
+
+/// A message decoded with a runtime template.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DynamicMessage {
+    pub name: String,
+    /// The message number as in `MessageMeta::number`.
+    pub number: u32,
+    /// The blocks of the message, in template order.
+    pub blocks: Vec<DynamicBlock>,
+}
+
+impl DynamicMessage {
+    /// Returns the value of a field in the `index`-th instance of a block.
+    pub fn field(&self, block: &str, index: usize, field: &str) -> Option<&FieldValue> {
+        self.blocks
+            .iter()
+            .find(|b| b.name == block)?
+            .instances
+            .get(index)?
+            .iter()
+            .find(|f| f.name == field)
+            .map(|f| &f.value)
+    }
+}
+
+/// A set of message templates, indexed by message number.
+#[derive(Clone, Debug, Default)]
+pub struct MessageTemplates {
+    by_number: HashMap<u32, MessageTemplate>,
+    by_name: HashMap<String, u32>,
+}
+
+impl MessageTemplates {
+    /// Parse the content of a `message_template.msg` file.
+    pub fn parse(input: &str) -> Result<Self, TemplateError> {
+        let mut templates = MessageTemplates::default();
+        for message in parser::parse(input)? {
+            templates.insert(message);
+        }
+        Ok(templates)
+    }
+
+    /// Load a `message_template.msg` file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, TemplateError> {
+        let mut input = String::new();
+        File::open(path)?.read_to_string(&mut input)?;
+        Self::parse(&input)
+    }
+
+    /// Add a template, replacing any existing template with the same number.
+    pub fn insert(&mut self, message: MessageTemplate) {
+        self.by_name
+            .insert(message.name.clone(), message.meta.number);
+        self.by_number.insert(message.meta.number, message);
+    }
+
+    pub fn get(&self, number: u32) -> Option<&MessageTemplate> {
+        self.by_number.get(&number)
+    }
+
+    pub fn get_by_name(&self, name: &str) -> Option<&MessageTemplate> {
+        self.by_name.get(name).and_then(|n| self.get(*n))
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_number.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_number.is_empty()
+    }
+
+    /// Read a message from a buffer, like `MessageInstance::read_message` the
+    /// message number has to be read from the buffer already.
+    pub fn read_message<R: ?Sized>(
+        &self,
+        buffer: &mut R,
+        message_num: u32,
+    ) -> Result<DynamicMessage, ReadError>
+    where
+        R: Read,
+    {
+        let template = self
+            .get(message_num)
+            .ok_or(ReadError::UnknownMessageNumber(message_num))?;
+
+        let mut blocks = Vec::with_capacity(template.blocks.len());
+        for block in &template.blocks {
+            let count = match block.quantity {
+                BlockQuantity::Single => 1,
+                BlockQuantity::Multiple(n) => n,
+                BlockQuantity::Variable => buffer.read_u8()?,
+            };
+            let mut instances = Vec::with_capacity(count as usize);
+            for _ in 0..count {
+                let mut fields = Vec::with_capacity(block.fields.len());
+                for field in &block.fields {
+                    fields.push(DynamicField {
+                        name: field.name.clone(),
+                        value: read_field(buffer, field.field_type)?,
+                    });
+                }
+                instances.push(fields);
+            }
+            blocks.push(DynamicBlock {
+                name: block.name.clone(),
+                instances: instances,
+            });
+        }
+
+        Ok(DynamicMessage {
+            name: template.name.clone(),
+            number: template.meta.number,
+            blocks: blocks,
+        })
+    }
+
+    /// Write a message including its message number to a buffer.
+    ///
+    /// The message is checked against its template, so that no malformed
+    /// data is written.
+    pub fn write_message<W: ?Sized>(
+        &self,
+        msg: &DynamicMessage,
+        buffer: &mut W,
+    ) -> Result<(), TemplateError>
+    where
+        W: Write,
+    {
+        let template = self
+            .get(msg.number)
+            .ok_or_else(|| TemplateError::UnknownMessage(msg.name.clone()))?;
+        let mismatch = |detail: String| TemplateError::Mismatch {
+            message: msg.name.clone(),
+            msg: detail,
+        };
+
+        if msg.blocks.len() != template.blocks.len() {
+            return Err(mismatch(format!(
+                "expected {} blocks, found {}",
+                template.blocks.len(),
+                msg.blocks.len()
+            )));
+        }
+        for (block, block_template) in msg.blocks.iter().zip(&template.blocks) {
+            if block.name != block_template.name {
+                return Err(mismatch(format!(
+                    "expected block {}, found {}",
+                    block_template.name, block.name
+                )));
+            }
+            let count_ok = match block_template.quantity {
+                BlockQuantity::Single => block.instances.len() == 1,
+                BlockQuantity::Multiple(n) => block.instances.len() == n as usize,
+                BlockQuantity::Variable => block.instances.len() <= 255,
+            };
+            if !count_ok {
+                return Err(mismatch(format!(
+                    "block {} has a wrong number of instances: {}",
+                    block.name,
+                    block.instances.len()
+                )));
+            }
+            for fields in &block.instances {
+                if fields.len() != block_template.fields.len() {
+                    return Err(mismatch(format!(
+                        "block {} has a wrong number of fields: {}",
+                        block.name,
+                        fields.len()
+                    )));
+                }
+                for (field, field_template) in fields.iter().zip(&block_template.fields) {
+                    if field.name != field_template.name
+                        || !field.value.matches(field_template.field_type)
+                    {
+                        return Err(mismatch(format!(
+                            "field {}.{} does not match the type {:?}",
+                            block.name, field.name, field_template.field_type
+                        )));
+                    }
+                }
+            }
+        }
+
+        let mut number = [0u8; 4];
+        (&mut number[..]).write_u32::<LittleEndian>(msg.number)?;
+        buffer.write_all(&number[..template.meta.frequency.number_len()])?;
+        for (block, block_template) in msg.blocks.iter().zip(&template.blocks) {
+            if block_template.quantity == BlockQuantity::Variable {
+                buffer.write_u8(block.instances.len() as u8)?;
+            }
+            for fields in &block.instances {
+                for (field, field_template) in fields.iter().zip(&block_template.fields) {
+                    write_field(buffer, &field.value, field_template.field_type)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn read_field<R: ?Sized>(buffer: &mut R, field_type: FieldType) -> Result<FieldValue, ReadError>
+where
+    R: Read,
+{
+    Ok(match field_type {
+        FieldType::U8 => FieldValue::U8(buffer.read_u8()?),
+        FieldType::U16 => FieldValue::U16(buffer.read_u16::<LittleEndian>()?),
+        FieldType::U32 => FieldValue::U32(buffer.read_u32::<LittleEndian>()?),
+        FieldType::U64 => FieldValue::U64(buffer.read_u64::<LittleEndian>()?),
+        FieldType::S8 => FieldValue::S8(buffer.read_i8()?),
+        FieldType::S16 => FieldValue::S16(buffer.read_i16::<LittleEndian>()?),
+        FieldType::S32 => FieldValue::S32(buffer.read_i32::<LittleEndian>()?),
+        FieldType::S64 => FieldValue::S64(buffer.read_i64::<LittleEndian>()?),
+        FieldType::F32 => FieldValue::F32(buffer.read_f32::<LittleEndian>()?),
+        FieldType::F64 => FieldValue::F64(buffer.read_f64::<LittleEndian>()?),
+        FieldType::Uuid => {
+            let mut raw = [0u8; 16];
+            buffer.read_exact(&mut raw)?;
+            FieldValue::Uuid(Uuid::from_bytes(raw))
+        }
+        FieldType::Bool => FieldValue::Bool(buffer.read_u8()? == 1),
+        FieldType::IpAddr => {
+            let mut raw = [0u8; 4];
+            buffer.read_exact(&mut raw)?;
+            FieldValue::IpAddr(Ip4Addr::from(raw))
+        }
+        FieldType::IpPort => FieldValue::IpPort(buffer.read_u16::<LittleEndian>()?),
+        FieldType::Vector3 => FieldValue::Vector3(Vector3::new(
+            buffer.read_f32::<LittleEndian>()?,
+            buffer.read_f32::<LittleEndian>()?,
+            buffer.read_f32::<LittleEndian>()?,
+        )),
+        FieldType::Vector3d => FieldValue::Vector3d(Vector3::new(
+            buffer.read_f64::<LittleEndian>()?,
+            buffer.read_f64::<LittleEndian>()?,
+            buffer.read_f64::<LittleEndian>()?,
+        )),
+        FieldType::Vector4 => FieldValue::Vector4(Vector4::new(
+            buffer.read_f32::<LittleEndian>()?,
+            buffer.read_f32::<LittleEndian>()?,
+            buffer.read_f32::<LittleEndian>()?,
+            buffer.read_f32::<LittleEndian>()?,
+        )),
+        FieldType::Quaternion => {
+            let x = buffer.read_f32::<LittleEndian>()?;
+            let y = buffer.read_f32::<LittleEndian>()?;
+            let z = buffer.read_f32::<LittleEndian>()?;
+            let w = (1. - (x * x + y * y + z * z)).max(0.).sqrt();
+            FieldValue::Quaternion(Quaternion::new(w, x, y, z))
+        }
+        FieldType::Fixed(n) => {
+            let mut raw = vec![0; n];
+            buffer.read_exact(&mut raw)?;
+            FieldValue::Fixed(raw)
+        }
+        FieldType::Variable1 | FieldType::Variable2 => {
+            let n = if field_type == FieldType::Variable1 {
+                buffer.read_u8()? as usize
+            } else {
+                buffer.read_u16::<LittleEndian>()? as usize
+            };
+            let mut raw = vec![0; n];
+            buffer.read_exact(&mut raw)?;
+            FieldValue::Variable(raw)
+        }
+    })
+}
+
+fn write_field<W: ?Sized>(
+    buffer: &mut W,
+    value: &FieldValue,
+    field_type: FieldType,
+) -> Result<(), ::std::io::Error>
+where
+    W: Write,
+{
+    match *value {
+        FieldValue::U8(v) => buffer.write_u8(v),
+        FieldValue::U16(v) => buffer.write_u16::<LittleEndian>(v),
+        FieldValue::U32(v) => buffer.write_u32::<LittleEndian>(v),
+        FieldValue::U64(v) => buffer.write_u64::<LittleEndian>(v),
+        FieldValue::S8(v) => buffer.write_i8(v),
+        FieldValue::S16(v) => buffer.write_i16::<LittleEndian>(v),
+        FieldValue::S32(v) => buffer.write_i32::<LittleEndian>(v),
+        FieldValue::S64(v) => buffer.write_i64::<LittleEndian>(v),
+        FieldValue::F32(v) => buffer.write_f32::<LittleEndian>(v),
+        FieldValue::F64(v) => buffer.write_f64::<LittleEndian>(v),
+        FieldValue::Uuid(ref v) => buffer.write_all(v.as_bytes()),
+        FieldValue::Bool(v) => buffer.write_u8(v as u8),
+        FieldValue::IpAddr(ref v) => buffer.write_all(&v.octets()),
+        FieldValue::IpPort(v) => buffer.write_u16::<LittleEndian>(v),
+        FieldValue::Vector3(ref v) => {
+            buffer.write_f32::<LittleEndian>(v.x)?;
+            buffer.write_f32::<LittleEndian>(v.y)?;
+            buffer.write_f32::<LittleEndian>(v.z)
+        }
+        FieldValue::Vector3d(ref v) => {
+            buffer.write_f64::<LittleEndian>(v.x)?;
+            buffer.write_f64::<LittleEndian>(v.y)?;
+            buffer.write_f64::<LittleEndian>(v.z)
+        }
+        FieldValue::Vector4(ref v) => {
+            buffer.write_f32::<LittleEndian>(v.x)?;
+            buffer.write_f32::<LittleEndian>(v.y)?;
+            buffer.write_f32::<LittleEndian>(v.z)?;
+            buffer.write_f32::<LittleEndian>(v.w)
+        }
+        FieldValue::Quaternion(ref v) => {
+            let norm = if v.scalar() >= 0. {
+                v.norm()
+            } else {
+                -v.norm()
+            };
+            buffer.write_f32::<LittleEndian>(v.coords[0] / norm)?;
+            buffer.write_f32::<LittleEndian>(v.coords[1] / norm)?;
+            buffer.write_f32::<LittleEndian>(v.coords[2] / norm)
+        }
+        FieldValue::Fixed(ref v) => buffer.write_all(v),
+        FieldValue::Variable(ref v) => {
+            if field_type == FieldType::Variable1 {
+                buffer.write_u8(v.len() as u8)?;
+            } else {
+                buffer.write_u16::<LittleEndian>(v.len() as u16)?;
+            }
+            buffer.write_all(v)
+        }
+    }
+}
+
+impl FieldValue {
+    /// Check if the value can be written as a field of the specified type.
+    pub fn matches(&self, field_type: FieldType) -> bool {
+        match (self, field_type) {
+            (&FieldValue::U8(_), FieldType::U8)
+            | (&FieldValue::U16(_), FieldType::U16)
+            | (&FieldValue::U32(_), FieldType::U32)
+            | (&FieldValue::U64(_), FieldType::U64)
+            | (&FieldValue::S8(_), FieldType::S8)
+            | (&FieldValue::S16(_), FieldType::S16)
+            | (&FieldValue::S32(_), FieldType::S32)
+            | (&FieldValue::S64(_), FieldType::S64)
+            | (&FieldValue::F32(_), FieldType::F32)
+            | (&FieldValue::F64(_), FieldType::F64)
+            | (&FieldValue::Uuid(_), FieldType::Uuid)
+            | (&FieldValue::Bool(_), FieldType::Bool)
+            | (&FieldValue::IpAddr(_), FieldType::IpAddr)
+            | (&FieldValue::IpPort(_), FieldType::IpPort)
+            | (&FieldValue::Vector3(_), FieldType::Vector3)
+            | (&FieldValue::Vector3d(_), FieldType::Vector3d)
+            | (&FieldValue::Vector4(_), FieldType::Vector4)
+            | (&FieldValue::Quaternion(_), FieldType::Quaternion) => true,
+            (&FieldValue::Fixed(ref v), FieldType::Fixed(n)) => v.len() == n,
+            (&FieldValue::Variable(ref v), FieldType::Variable1) => v.len() <= 255,
+            (&FieldValue::Variable(ref v), FieldType::Variable2) => v.len() <= 65535,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for FieldValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FieldValue::U8(v) => write!(f, "{}", v),
+            FieldValue::U16(v) => write!(f, "{}", v),
+            FieldValue::U32(v) => write!(f, "{}", v),
+            FieldValue::U64(v) => write!(f, "{}", v),
+            FieldValue::S8(v) => write!(f, "{}", v),
+            FieldValue::S16(v) => write!(f, "{}", v),
+            FieldValue::S32(v) => write!(f, "{}", v),
+            FieldValue::S64(v) => write!(f, "{}", v),
+            FieldValue::F32(v) => write!(f, "{}", v),
+            FieldValue::F64(v) => write!(f, "{}", v),
+            FieldValue::Uuid(ref v) => write!(f, "{}", v),
+            FieldValue::Bool(v) => write!(f, "{}", v),
+            FieldValue::IpAddr(ref v) => write!(f, "{}", v),
+            FieldValue::IpPort(v) => write!(f, "{}", v),
+            FieldValue::Vector3(ref v) => write!(f, "<{}, {}, {}>", v.x, v.y, v.z),
+            FieldValue::Vector3d(ref v) => write!(f, "<{}, {}, {}>", v.x, v.y, v.z),
+            FieldValue::Vector4(ref v) => write!(f, "<{}, {}, {}, {}>", v.x, v.y, v.z, v.w),
+            FieldValue::Quaternion(ref v) => write!(
+                f,
+                "<{}, {}, {}, {}>",
+                v.coords[0], v.coords[1], v.coords[2], v.coords[3]
+            ),
+            FieldValue::Fixed(ref v) => write_hex(f, v),
+            FieldValue::Variable(ref v) => {
+                // Most variable fields contain (zero terminated) strings.
+                let text = match v.split_last() {
+                    Some((&0, rest)) => rest,
+                    _ => &v[..],
+                };
+                match ::std::str::from_utf8(text) {
+                    Ok(s) if !s.chars().any(|c| c.is_control() && c != '\n') => {
+                        write!(f, "{:?}", s)
+                    }
+                    _ => write_hex(f, v),
+                }
+            }
+        }
+    }
+}
+
+fn write_hex(f: &mut fmt::Formatter, bytes: &[u8]) -> fmt::Result {
+    write!(f, "0x")?;
+    for b in bytes {
+        write!(f, "{:02x}", b)?;
+    }
+    Ok(())
+}
+
+/// Readable multi-line representation of the message, for logging.
+impl fmt::Display for DynamicMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        for block in &self.blocks {
+            for fields in &block.instances {
+                write!(f, "\n  [{}]", block.name)?;
+                for field in fields {
+                    write!(f, "\n    {}: {}", field.name, field.value)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use all::{
+        RegionHandshakeReply, RegionHandshakeReply_AgentData, RegionHandshakeReply_RegionInfo,
+    };
+    use {Message, MessageType};
+
+    const TEMPLATE: &'static str = "
+version 2.0
+
+// Extension message of some fork.
+{
+    TestMessage Low 30000 NotTrusted Zerocoded
+    {
+        TestBlock1      Single
+        {   Test1       U32 }
+    }
+    {
+        NeighborBlock   Multiple    2
+        {   Test0       LLVector3   }
+        {   Test1       Fixed 4     }
+    }
+    {
+        Entries         Variable
+        {   Name        Variable 1  }
+        {   Rotation    LLQuaternion}
+    }
+}
+
+{
+    RegionHandshakeReply Low 149 NotTrusted Zerocoded
+    {
+        AgentData           Single
+        {   AgentID         LLUUID  }
+        {   SessionID       LLUUID  }
+    }
+    {
+        RegionInfo          Single
+        {   Flags           U32     }
+    }
+}
+
+{
+    PacketAck Fixed 0xFFFFFFFB NotTrusted Unencoded
+    {
+        Packets         Variable
+        {   ID          U32 }
+    }
+}
+";
+
+    #[test]
+    fn parse_template() {
+        let templates = MessageTemplates::parse(TEMPLATE).unwrap();
+        assert_eq!(templates.len(), 3);
+
+        let test = templates.get_by_name("TestMessage").unwrap();
+        assert_eq!(test.meta.number, 0x3075ffff);
+        assert!(test.meta.zerocoded);
+        assert_eq!(test.blocks[1].quantity, BlockQuantity::Multiple(2));
+        assert_eq!(test.blocks[1].fields[1].field_type, FieldType::Fixed(4));
+        assert_eq!(test.blocks[2].fields[0].field_type, FieldType::Variable1);
+
+        // Numbers match the ones of the generated code.
+        for name in &["RegionHandshakeReply", "PacketAck"] {
+            let meta = templates.get_by_name(name).unwrap().meta;
+            let expected = match *name {
+                "RegionHandshakeReply" => MessageType::RegionHandshakeReply.meta(),
+                _ => MessageType::PacketAck.meta(),
+            };
+            assert_eq!(meta, expected);
+        }
+    }
+
+    #[test]
+    fn parse_errors() {
+        match MessageTemplates::parse("{\n  Broken Low 1 NotTrusted Unencoded\n  { Block Single\n")
+        {
+            Err(TemplateError::Parse { line: 3, .. }) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        match MessageTemplates::parse("{ Broken Sometimes 1 NotTrusted Unencoded }") {
+            Err(TemplateError::Parse { line: 1, .. }) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn decode_generated_message() {
+        let templates = MessageTemplates::parse(TEMPLATE).unwrap();
+        let agent_id = Uuid::from_bytes([1; 16]);
+        let msg = RegionHandshakeReply {
+            agent_data: RegionHandshakeReply_AgentData {
+                agent_id: agent_id,
+                session_id: Uuid::nil(),
+            },
+            region_info: RegionHandshakeReply_RegionInfo { flags: 7 },
+        };
+        let mut buffer = Vec::new();
+        msg.write_to(&mut buffer).unwrap();
+
+        let number = MessageType::RegionHandshakeReply.meta().number;
+        let dynamic = templates.read_message(&mut &buffer[4..], number).unwrap();
+        assert_eq!(
+            dynamic.field("AgentData", 0, "AgentID"),
+            Some(&FieldValue::Uuid(agent_id))
+        );
+        assert_eq!(
+            dynamic.field("RegionInfo", 0, "Flags"),
+            Some(&FieldValue::U32(7))
+        );
+
+        // Encoding again gives the same bytes.
+        let mut encoded = Vec::new();
+        templates.write_message(&dynamic, &mut encoded).unwrap();
+        assert_eq!(encoded, buffer);
+    }
+
+    #[test]
+    fn round_trip_unknown_message() {
+        let templates = MessageTemplates::parse(TEMPLATE).unwrap();
+        let number = templates.get_by_name("TestMessage").unwrap().meta.number;
+        let neighbor = vec![
+            DynamicField {
+                name: "Test0".to_string(),
+                value: FieldValue::Vector3(Vector3::new(1., 2., 3.)),
+            },
+            DynamicField {
+                name: "Test1".to_string(),
+                value: FieldValue::Fixed(vec![1, 2, 3, 4]),
+            },
+        ];
+        let msg = DynamicMessage {
+            name: "TestMessage".to_string(),
+            number: number,
+            blocks: vec![
+                DynamicBlock {
+                    name: "TestBlock1".to_string(),
+                    instances: vec![vec![DynamicField {
+                        name: "Test1".to_string(),
+                        value: FieldValue::U32(42),
+                    }]],
+                },
+                DynamicBlock {
+                    name: "NeighborBlock".to_string(),
+                    instances: vec![neighbor.clone(), neighbor],
+                },
+                DynamicBlock {
+                    name: "Entries".to_string(),
+                    instances: vec![vec![
+                        DynamicField {
+                            name: "Name".to_string(),
+                            value: FieldValue::Variable(b"test\0".to_vec()),
+                        },
+                        DynamicField {
+                            name: "Rotation".to_string(),
+                            value: FieldValue::Quaternion(Quaternion::new(1., 0., 0., 0.)),
+                        },
+                    ]],
+                },
+            ],
+        };
+
+        let mut buffer = Vec::new();
+        templates.write_message(&msg, &mut buffer).unwrap();
+        assert_eq!(&buffer[..4], &[0xff, 0xff, 0x75, 0x30]);
+        let read = templates.read_message(&mut &buffer[4..], number).unwrap();
+        assert_eq!(read, msg);
+
+        let text = format!("{}", read);
+        assert!(text.contains("[NeighborBlock]"));
+        assert!(text.contains("Name: \"test\""));
+    }
+
+    #[test]
+    fn write_mismatch() {
+        let templates = MessageTemplates::parse(TEMPLATE).unwrap();
+        let msg = DynamicMessage {
+            name: "PacketAck".to_string(),
+            number: MessageType::PacketAck.meta().number,
+            blocks: vec![DynamicBlock {
+                name: "Packets".to_string(),
+                instances: vec![vec![DynamicField {
+                    name: "ID".to_string(),
+                    value: FieldValue::U16(1),
+                }]],
+            }],
+        };
+        match templates.write_message(&msg, &mut Vec::new()) {
+            Err(TemplateError::Mismatch { .. }) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+}
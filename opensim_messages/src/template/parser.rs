@@ -0,0 +1,273 @@
+//! Parser for the `message_template.msg` file format.
+//!
+//! The format consists of nested brace delimited sections:
+//!
+//! ```text
+//! {
+//!     TestMessage Low 1 NotTrusted Zerocoded
+//!     {
+//!         TestBlock1 Single
+//!         {   Test1   U32 }
+//!     }
+//! }
+//! ```
+//!
+//! Everything after `//` on a line is a comment, the `version` line at the
+//! start of the file is ignored.
+
+use super::{
+    BlockQuantity, BlockTemplate, FieldTemplate, FieldType, MessageTemplate, TemplateError,
+};
+use meta::message_number;
+use {Deprecation, MessageFrequency, MessageMeta};
+
+struct Token<'a> {
+    text: &'a str,
+    line: usize,
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token<'a>>,
+    pos: usize,
+}
+
+pub(super) fn parse(input: &str) -> Result<Vec<MessageTemplate>, TemplateError> {
+    let mut parser = Parser {
+        tokens: tokenize(input),
+        pos: 0,
+    };
+
+    let mut messages = Vec::new();
+    while let Some(token) = parser.peek() {
+        if token == "version" {
+            parser.next()?;
+            parser.next()?;
+        } else {
+            messages.push(parser.parse_message()?);
+        }
+    }
+    Ok(messages)
+}
+
+fn tokenize(input: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    for (i, line) in input.lines().enumerate() {
+        let line_content = match line.find("//") {
+            Some(pos) => &line[..pos],
+            None => line,
+        };
+
+        // Braces are not necessarily separated by whitespace.
+        let mut start = None;
+        for (pos, c) in line_content.char_indices() {
+            if c.is_whitespace() || c == '{' || c == '}' {
+                if let Some(s) = start.take() {
+                    tokens.push(Token {
+                        text: &line_content[s..pos],
+                        line: i + 1,
+                    });
+                }
+                if c == '{' || c == '}' {
+                    tokens.push(Token {
+                        text: &line_content[pos..pos + 1],
+                        line: i + 1,
+                    });
+                }
+            } else if start.is_none() {
+                start = Some(pos);
+            }
+        }
+        if let Some(s) = start {
+            tokens.push(Token {
+                text: &line_content[s..],
+                line: i + 1,
+            });
+        }
+    }
+    tokens
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.pos).map(|t| t.text)
+    }
+
+    fn error<S: Into<String>>(&self, msg: S) -> TemplateError {
+        let line = match self.tokens.get(self.pos) {
+            Some(t) => t.line,
+            None => self.tokens.last().map(|t| t.line).unwrap_or(0),
+        };
+        TemplateError::Parse {
+            line: line,
+            msg: msg.into(),
+        }
+    }
+
+    fn next(&mut self) -> Result<&'a str, TemplateError> {
+        match self.tokens.get(self.pos) {
+            Some(t) => {
+                self.pos += 1;
+                Ok(t.text)
+            }
+            None => Err(self.error("unexpected end of file")),
+        }
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), TemplateError> {
+        let token = self.next()?;
+        if token == expected {
+            Ok(())
+        } else {
+            self.pos -= 1;
+            Err(self.error(format!("expected '{}', found '{}'", expected, token)))
+        }
+    }
+
+    fn parse_number(&mut self) -> Result<u32, TemplateError> {
+        let token = self.next()?;
+        let result = if token.starts_with("0x") || token.starts_with("0X") {
+            u32::from_str_radix(&token[2..], 16)
+        } else {
+            token.parse()
+        };
+        result.map_err(|_| {
+            self.pos -= 1;
+            self.error(format!("invalid number: '{}'", token))
+        })
+    }
+
+    fn parse_message(&mut self) -> Result<MessageTemplate, TemplateError> {
+        self.expect("{")?;
+        let name = self.next()?.to_string();
+        let frequency = match self.next()? {
+            "High" => MessageFrequency::High,
+            "Medium" => MessageFrequency::Medium,
+            "Low" => MessageFrequency::Low,
+            "Fixed" => MessageFrequency::Fixed,
+            other => {
+                self.pos -= 1;
+                return Err(self.error(format!("invalid frequency: '{}'", other)));
+            }
+        };
+        let id = self.parse_number()?;
+        let trusted = match self.next()? {
+            "Trusted" => true,
+            "NotTrusted" => false,
+            other => {
+                self.pos -= 1;
+                return Err(self.error(format!("invalid trust: '{}'", other)));
+            }
+        };
+        let zerocoded = match self.next()? {
+            "Zerocoded" => true,
+            "Unencoded" => false,
+            other => {
+                self.pos -= 1;
+                return Err(self.error(format!("invalid encoding: '{}'", other)));
+            }
+        };
+        let deprecation = match self.peek() {
+            Some("Deprecated") => Deprecation::Deprecated,
+            Some("UDPDeprecated") => Deprecation::UdpDeprecated,
+            Some("UDPBlackListed") => Deprecation::UdpBlackListed,
+            _ => Deprecation::NotDeprecated,
+        };
+        if deprecation != Deprecation::NotDeprecated {
+            self.next()?;
+        }
+
+        let number = message_number(frequency, id)
+            .ok_or_else(|| self.error(format!("invalid message number: {}", id)))?;
+
+        let mut blocks = Vec::new();
+        while self.peek() == Some("{") {
+            blocks.push(self.parse_block()?);
+        }
+        self.expect("}")?;
+
+        Ok(MessageTemplate {
+            name: name,
+            meta: MessageMeta {
+                frequency: frequency,
+                number: number,
+                trusted: trusted,
+                zerocoded: zerocoded,
+                deprecation: deprecation,
+            },
+            blocks: blocks,
+        })
+    }
+
+    fn parse_block(&mut self) -> Result<BlockTemplate, TemplateError> {
+        self.expect("{")?;
+        let name = self.next()?.to_string();
+        let quantity = match self.next()? {
+            "Single" => BlockQuantity::Single,
+            "Variable" => BlockQuantity::Variable,
+            "Multiple" => {
+                let count = self.parse_number()?;
+                if count == 0 || count > 255 {
+                    return Err(self.error(format!("invalid block count: {}", count)));
+                }
+                BlockQuantity::Multiple(count as u8)
+            }
+            other => {
+                self.pos -= 1;
+                return Err(self.error(format!("invalid block quantity: '{}'", other)));
+            }
+        };
+
+        let mut fields = Vec::new();
+        while self.peek() == Some("{") {
+            fields.push(self.parse_field()?);
+        }
+        self.expect("}")?;
+
+        Ok(BlockTemplate {
+            name: name,
+            quantity: quantity,
+            fields: fields,
+        })
+    }
+
+    fn parse_field(&mut self) -> Result<FieldTemplate, TemplateError> {
+        self.expect("{")?;
+        let name = self.next()?.to_string();
+        let field_type = match self.next()? {
+            "U8" => FieldType::U8,
+            "U16" => FieldType::U16,
+            "U32" => FieldType::U32,
+            "U64" => FieldType::U64,
+            "S8" => FieldType::S8,
+            "S16" => FieldType::S16,
+            "S32" => FieldType::S32,
+            "S64" => FieldType::S64,
+            "F32" => FieldType::F32,
+            "F64" => FieldType::F64,
+            "LLUUID" => FieldType::Uuid,
+            "BOOL" => FieldType::Bool,
+            "IPADDR" => FieldType::IpAddr,
+            "IPPORT" => FieldType::IpPort,
+            "LLVector3" => FieldType::Vector3,
+            "LLVector3d" => FieldType::Vector3d,
+            "LLVector4" => FieldType::Vector4,
+            "LLQuaternion" => FieldType::Quaternion,
+            "Fixed" => FieldType::Fixed(self.parse_number()? as usize),
+            "Variable" => match self.parse_number()? {
+                1 => FieldType::Variable1,
+                2 => FieldType::Variable2,
+                n => return Err(self.error(format!("invalid variable size: {}", n))),
+            },
+            other => {
+                self.pos -= 1;
+                return Err(self.error(format!("invalid field type: '{}'", other)));
+            }
+        };
+        self.expect("}")?;
+
+        Ok(FieldTemplate {
+            name: name,
+            field_type: field_type,
+        })
+    }
+}
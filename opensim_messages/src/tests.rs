@@ -0,0 +1,279 @@
+use arbitrary::Gen;
+use MessageInstance;
+
+/// Writes the message and asserts that reading it back yields the same
+/// message, consuming all written bytes, and that the encoded length was
+/// predicted correctly.
+fn assert_round_trip(msg: &MessageInstance) {
+    let mut buffer = Vec::new();
+    msg.write_to(&mut buffer).unwrap();
+    assert_eq!(
+        msg.encoded_len(),
+        buffer.len(),
+        "{:?}: wrong encoded length",
+        msg.message_type()
+    );
+
+    let meta = msg.message_type().meta();
+    let mut body = &buffer[meta.frequency.number_len()..];
+    let read = MessageInstance::read_message(&mut body, meta.number).unwrap();
+
+    assert!(
+        body.is_empty(),
+        "{:?}: {} bytes were not read",
+        msg.message_type(),
+        body.len()
+    );
+    assert!(read == *msg, "{:?}: round trip failed", msg.message_type());
+}
+
+#[test]
+fn round_trip_random() {
+    for seed in 0..16 {
+        let mut g = Gen::new(seed);
+        for msg in MessageInstance::arbitrary_all(&mut g) {
+            assert_round_trip(&msg);
+        }
+    }
+}
+
+/// All Variable fields and variable blocks empty.
+#[test]
+fn round_trip_empty() {
+    for msg in MessageInstance::arbitrary_all(&mut Gen::empty()) {
+        assert_round_trip(&msg);
+    }
+}
+
+/// All Variable fields at their length limits (255 bytes for Variable 1,
+/// 65535 bytes for Variable 2) and 255 instances of each variable block.
+#[test]
+fn round_trip_full() {
+    for msg in MessageInstance::arbitrary_all(&mut Gen::full()) {
+        assert_round_trip(&msg);
+    }
+}
+
+#[cfg(feature = "serde-serialize")]
+mod serde {
+    use arbitrary::Gen;
+    use serde_json;
+    use MessageInstance;
+
+    #[test]
+    fn json_round_trip() {
+        for seed in 0..4 {
+            let mut g = Gen::new(seed);
+            for msg in MessageInstance::arbitrary_all(&mut g) {
+                let json = serde_json::to_string(&msg).unwrap();
+                let read: MessageInstance = serde_json::from_str(&json).unwrap();
+                assert!(
+                    read == msg,
+                    "{:?}: JSON round trip failed",
+                    msg.message_type()
+                );
+            }
+        }
+    }
+
+    /// The representation is relied upon by fixtures and logs, it must not
+    /// change by accident.
+    #[test]
+    fn json_representation() {
+        use all::{ChatFromViewer, ChatFromViewer_AgentData, ChatFromViewer_ChatData};
+        use Uuid;
+
+        let msg = MessageInstance::ChatFromViewer(ChatFromViewer {
+            agent_data: ChatFromViewer_AgentData {
+                agent_id: Uuid::parse_str("6e1c1ce7-8a67-4a96-a2b4-0e7b1b0a9a35").unwrap(),
+                session_id: Uuid::nil(),
+            },
+            chat_data: ChatFromViewer_ChatData {
+                message: b"Hi\0".to_vec(),
+                type_: 1,
+                channel: 0,
+            },
+        });
+        let json = r#"{"ChatFromViewer":{"agent_data":{"agent_id":"6e1c1ce7-8a67-4a96-a2b4-0e7b1b0a9a35","session_id":"00000000-0000-0000-0000-000000000000"},"chat_data":{"message":"486900","type_":1,"channel":0}}}"#;
+        assert_eq!(serde_json::to_string(&msg).unwrap(), json);
+        assert_eq!(serde_json::from_str::<MessageInstance>(json).unwrap(), msg);
+    }
+
+    #[test]
+    fn json_representation_vectors() {
+        use all::AgentUpdate;
+
+        let mut g = Gen::new(0);
+        let msg = MessageInstance::arbitrary_all(&mut g)
+            .into_iter()
+            .filter_map(|m| match m {
+                MessageInstance::AgentUpdate(m) => Some(m),
+                _ => None,
+            })
+            .next()
+            .unwrap();
+        let value = serde_json::to_value(&msg).unwrap();
+
+        let data = &msg.agent_data;
+        let rotation = &data.body_rotation;
+        assert_eq!(
+            value["agent_data"]["body_rotation"],
+            json!([rotation.i, rotation.j, rotation.k, rotation.w])
+        );
+        let center = &data.camera_center;
+        assert_eq!(
+            value["agent_data"]["camera_center"],
+            json!([center.x, center.y, center.z])
+        );
+        assert_eq!(serde_json::from_value::<AgentUpdate>(value).unwrap(), msg);
+    }
+}
+
+/// Splitting messages with many variable blocks yields parts within the
+/// length limit.
+#[test]
+fn split_full() {
+    let max_len = 1200;
+    let mut split = 0;
+    for msg in MessageInstance::arbitrary_all(&mut Gen::full()) {
+        let message_type = msg.message_type();
+        let parts = match msg.clone().split(max_len) {
+            Ok(parts) => parts,
+            Err(unchanged) => {
+                assert!(unchanged == msg);
+                continue;
+            }
+        };
+        if parts.len() > 1 {
+            split += 1;
+        }
+        for part in &parts {
+            assert!(part.message_type() == message_type);
+            assert!(part.encoded_len() <= max_len, "{:?}", message_type);
+            assert_round_trip(part);
+        }
+    }
+    assert!(split > 0);
+}
+
+/// The parts contain all blocks in their original order and respect the
+/// block count limit.
+#[test]
+fn split_packet_ack() {
+    use all::{PacketAck, PacketAck_Packets};
+    use {Message, MAX_VARIABLE_BLOCKS};
+
+    let original = PacketAck {
+        packets: (0..600).map(|id| PacketAck_Packets { id: id }).collect(),
+    };
+    let parts = original.clone().split(10000).unwrap();
+    assert_eq!(parts.len(), 3);
+
+    let mut blocks = Vec::new();
+    for part in parts {
+        assert!(part.packets.len() <= MAX_VARIABLE_BLOCKS);
+        blocks.extend(part.packets);
+    }
+    assert!(blocks == original.packets);
+
+    let parts = original.clone().split(500).unwrap();
+    assert!(parts.iter().all(|p| p.encoded_len() <= 500));
+    assert!(original.split(5).is_err());
+}
+
+/// Messages describing a single operation are never split, even if they are
+/// too large.
+#[test]
+fn split_not_allowed() {
+    use all::{ObjectLink, ObjectLink_AgentData, ObjectLink_ObjectData};
+    use Uuid;
+
+    let msg: MessageInstance = ObjectLink {
+        agent_data: ObjectLink_AgentData {
+            agent_id: Uuid::nil(),
+            session_id: Uuid::nil(),
+        },
+        object_data: (0..400)
+            .map(|id| ObjectLink_ObjectData { object_local_id: id })
+            .collect(),
+    }
+    .into();
+    assert!(msg.encoded_len() > 1200);
+    assert!(msg.clone().split(1200).unwrap_err() == msg);
+}
+
+#[test]
+fn validate_field_too_long() {
+    use all::AgentAlertMessage;
+    use arbitrary::Arbitrary;
+    use {Message, ValidationError, WriteError};
+
+    let mut msg = AgentAlertMessage::arbitrary(&mut Gen::new(0));
+    msg.alert_data.message = vec![b'a'; 256];
+
+    let expected = ValidationError::FieldTooLong {
+        message: "AgentAlertMessage",
+        block: "AlertData",
+        field: "message",
+        len: 256,
+        max: 255,
+    };
+    assert_eq!(msg.validate(), Err(expected));
+
+    // Nothing is written for invalid messages.
+    let mut buffer = Vec::new();
+    match msg.write_to(&mut buffer) {
+        Err(WriteError::Invalid(err)) => assert_eq!(err, expected),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    assert!(buffer.is_empty());
+
+    msg.alert_data.message.pop();
+    assert_eq!(msg.validate(), Ok(()));
+}
+
+#[test]
+fn validate_block_counts() {
+    use all::{NeighborList, PacketAck, PacketAck_Packets};
+    use arbitrary::Arbitrary;
+    use {Message, ValidationError};
+
+    let msg = PacketAck {
+        packets: (0..256).map(|id| PacketAck_Packets { id: id }).collect(),
+    };
+    assert_eq!(
+        msg.validate(),
+        Err(ValidationError::TooManyBlocks {
+            message: "PacketAck",
+            block: "Packets",
+            count: 256,
+        })
+    );
+
+    let mut msg = NeighborList::arbitrary(&mut Gen::new(0));
+    msg.neighbor_block.pop();
+    assert_eq!(
+        msg.validate(),
+        Err(ValidationError::WrongBlockCount {
+            message: "NeighborList",
+            block: "NeighborBlock",
+            count: 3,
+            expected: 4,
+        })
+    );
+    assert!(MessageInstance::NeighborList(msg).validate().is_err());
+}
+
+#[test]
+fn typed_message_from_instance() {
+    use all::{PacketAck, StartPingCheck};
+    use {MessageType, TypedMessage};
+
+    let msg = PacketAck { packets: vec![] };
+    assert_eq!(PacketAck::TYPE, MessageType::PacketAck);
+    assert_eq!(PacketAck::from_instance(msg.clone().into()), Ok(msg.clone()));
+    assert_eq!(
+        StartPingCheck::from_instance(msg.clone().into()),
+        Err(MessageInstance::PacketAck(msg))
+    );
+}
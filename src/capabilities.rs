@@ -24,6 +24,13 @@ pub enum CapabilitiesError {
     Msg(String),
 }
 
+#[cfg(test)]
+impl Capabilities {
+    pub(crate) fn from_urls(urls: Urls) -> Self {
+        Capabilities { urls: urls }
+    }
+}
+
 impl Capabilities {
     #[async]
     fn build_request_body(val: llsd::data::Value) -> Result<Vec<u8>, CapabilitiesError> {
@@ -1,79 +1,265 @@
 //! Encapsulates the logic for handling incoming and outgoing packet acks.
-
-// Description of actions and events, for a potential future event based
-// implementation
-//
-// - packet out   → Socket write   → Register ack timeout
-// - packet in   → Retrieve acks and remove pending timeouts
-// TODO: This is currently the biggest problem how would I implement this
-// using Rust futures?
-//   → Queue the packet for retrieval by client application
-// - ack out
-// → register ack for timeout queue, either append it to the next packet or
-// send it in a     dedicated packet if waiting for too long.
+//!
+//! The sender thread blocks in `AckManagerRx::fetch` until one of these
+//! events happens:
+//!
+//! - A message is queued → it is sent right away.
+//! - An ack is queued → it is appended to the next packet, or sent in a
+//!   dedicated `PacketAck` if there is no other packet to send.
+//! - An incoming ack is registered → the acked message is marked successful.
+//! - A message waiting for its ack times out → it is resent.
+//! - Nothing was received from the sim for the ping interval → a
+//!   `StartPingCheck` is sent, keeping the circuit alive.
+//! - A throttled category has budget again → its next message is sent.
+//! - The stats interval elapsed → a `CircuitStats` snapshot is published.
+//! - The circuit is closed or declared dead.
+//!
+//! Acks of reliable packets and completed pings are used to estimate the
+//! round-trip time, from which the resend timeouts are derived, see the
+//! `rtt` module.
+//!
+//! Messages wait in one queue per `ThrottleCategory` until the token bucket
+//! of their category allows sending them, the categories are served in
+//! priority order. Acks and pings are never held back, and once the circuit
+//! is closed the queues are flushed regardless of the budgets.
 
 use addressable_queue::fifo::Queue as AddressableQueue;
+use circuit::events::CircuitEvent;
+use circuit::rtt::RttEstimator;
+use circuit::stats::{CircuitStats, StatsCounters};
+use circuit::throttle::{Throttle, ThrottleCategory, Throttles, THROTTLE_CATEGORIES};
 use circuit::{CircuitConfig, SendMessage, SendMessageError, SendMessageStatus};
-use messages::{MessageInstance, PacketAck, PacketAck_Packets};
-use packet::{Packet, PacketFlags};
+use messages::{
+    MessageInstance, PacketAck, PacketAck_Packets, StartPingCheck, StartPingCheck_PingID,
+};
+use packet::{self, Packet, PacketFlags, HEADER_SIZE, MTU};
 use types::SequenceNumber;
-use util::{mpsc_read_many, AtomicU32Counter};
+use util::{AtomicU32Counter, BackoffQueue, BackoffQueueState};
 
-use std::sync::mpsc;
-use std::sync::mpsc::TryRecvError;
-use std::thread;
+use crossbeam_channel::{self, Receiver, Select, SendError, Sender, TryRecvError};
+use std::cmp::min;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::io::Error as IoError;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
 pub struct AckManagerRx {
     /// Messages waiting for their confirmation.
     acks_wait: AddressableQueue<SequenceNumber, PendingMessage>,
 
-    /// Acks we have received but not processed yet.
-    acks_inc: mpsc::Receiver<SequenceNumber>,
+    /// Timeouts of the messages in `acks_wait`, entries of messages which
+    /// were acked in the meantime are skipped.
+    ack_timeouts: BackoffQueue<SequenceNumber>,
+
+    /// Acks we have received but not processed yet, with the time they
+    /// arrived.
+    acks_inc: Receiver<(SequenceNumber, Instant)>,
+
+    /// Ids of completed pings, with the time the reply arrived.
+    pings_inc: Receiver<(u8, Instant)>,
+
+    /// Send times of the pings we are waiting for.
+    pings_wait: HashMap<u8, Instant>,
+    next_ping_id: u8,
+    /// The earliest time for the next ping, which is only sent once the sim
+    /// was silent for the ping interval too.
+    next_ping: Instant,
+    /// The time the last packet from the sim arrived.
+    last_received: Arc<Mutex<Instant>>,
+
+    /// Sequence numbers of the reliable packets which were sent but neither
+    /// acked nor failed yet, for `StartPingCheck::oldest_unacked`.
+    unacked: BTreeSet<SequenceNumber>,
 
     /// Acks to be sent out.
-    acks_out: mpsc::Receiver<SequenceNumber>,
+    acks_out: Receiver<SequenceNumber>,
 
     /// Messages waiting to be sent out.
-    msgs_out: mpsc::Receiver<PendingMessage>,
+    msgs_out: Receiver<PendingMessage>,
+
+    /// Messages taken from `msgs_out` or timed out, waiting for the budget
+    /// of their category. Indexed by `ThrottleCategory`.
+    held: Vec<VecDeque<PendingMessage>>,
+    throttle: Arc<Mutex<Throttle>>,
+    /// The time the next held message can be sent, if any is held.
+    next_unthrottled: Option<Instant>,
+
+    /// Wakes up `fetch` when the circuit is closed.
+    wake: Receiver<()>,
 
     /// Copy of circuit config.
     config: CircuitConfig,
     sequence_counter: AtomicU32Counter,
+    rtt: Arc<Mutex<RttEstimator>>,
+
+    stats: Arc<StatsCounters>,
+    /// Periodic snapshots, if `CircuitConfig::stats_interval` is set.
+    stats_events: Sender<CircuitStats>,
+    next_stats: Option<Instant>,
+    events: Sender<CircuitEvent>,
+
+    /// Set once the circuit is closed, see `AckManagerTx::close`.
+    closing: Arc<AtomicBool>,
+    /// Set once the sim stopped responding, see `AckManagerTx::declare_dead`.
+    dead: Arc<AtomicBool>,
+    /// Set before the messages left in `msgs_out` are failed for the last
+    /// time, see `AckManagerTx::_queue_msg`.
+    stopped: Arc<AtomicBool>,
+}
+
+/// The result of `AckManagerRx::poll_fetch`.
+pub enum Fetch {
+    /// The next packet to be sent to the sim.
+    Packet(Packet),
+    /// Nothing to send until the provided time, unless one of the channels
+    /// watched with `AckManagerRx::watch` receives something before.
+    Idle(Instant),
+    /// The circuit is closed and everything was sent, see `fetch`.
+    Closed,
+}
+
+/// The result of `AckManagerRx::_fetch_next`.
+enum Next {
+    Packet(Packet, SendMessage),
+    Idle,
+    Closed,
 }
 
 impl AckManagerRx {
-    fn _fetch_loop(&mut self) -> (Packet, SendMessage) {
+    fn _fetch_next(&mut self) -> Next {
         loop {
+            if self.dead.load(Ordering::SeqCst) {
+                return Next::Closed;
+            }
             if let Some(pending_msg) = self._next_message() {
                 // Create packet instance and update status.
                 let mut future = pending_msg.future.clone();
+                let resend_id = match future.get_status() {
+                    SendMessageStatus::PendingAck { id, .. } => Some(id),
+                    _ => None,
+                };
                 let (packet, new_status) = self._prepare_packet(pending_msg);
                 future.update_status(new_status);
 
                 if let Some(mut packet) = packet {
-                    // Append some pending acks if there are any.
-                    let mut acks = mpsc_read_many(&self.acks_out, 255);
-                    packet.appended_acks.append(&mut acks);
+                    // Append as many pending acks as fit into the packet, each
+                    // takes four bytes plus one for the count.
+                    let room = (MTU - packet.encoded_len()).saturating_sub(1) / 4;
+                    let acks = self.acks_out.try_iter().take(min(room, 255));
+                    packet.appended_acks.extend(acks);
+                    if !packet.appended_acks.is_empty() {
+                        packet.enable_flags(PacketFlags::APPENDED_ACKS);
+                    }
 
                     // Return the packet to be sent.
-                    return (packet, future);
+                    return Next::Packet(packet, future);
+                } else if let Some(id) = resend_id {
+                    // The message failed after its last attempt.
+                    self.unacked.remove(&id);
                 }
+            } else if self.closing.load(Ordering::SeqCst) {
+                return Next::Closed;
             } else {
-                thread::sleep(Duration::from_millis(50));
+                return Next::Idle;
             }
         }
     }
 
+    /// The time until which there is nothing to do, unless any of the
+    /// channels receives an item. Then a message waiting for its ack times
+    /// out, a held message can be sent or the next ping is due.
+    fn _idle_until(&mut self) -> Instant {
+        // Only the closing flag and throttle changes matter, not the number
+        // of wake ups.
+        while self.wake.try_recv().is_ok() {}
+
+        let now = Instant::now();
+        // The circuit might have been closed after the messages were checked.
+        if self.closing.load(Ordering::SeqCst) {
+            return now;
+        }
+        let next = [self.next_unthrottled, self.next_stats]
+            .iter()
+            .filter_map(|t| *t)
+            .fold(self._ping_due(), min);
+        match self.ack_timeouts.state() {
+            BackoffQueueState::ItemReady => now,
+            BackoffQueueState::Wait(duration) => min(now + duration, next),
+            BackoffQueueState::Empty => next,
+        }
+    }
+
+    /// Block until there might be something to do, see `_idle_until`.
+    fn _wait(&self, until: Instant) {
+        let now = Instant::now();
+        if until <= now {
+            return;
+        }
+        let mut select = Select::new();
+        self.watch(&mut select);
+        let _ = select.ready_timeout(until - now);
+    }
+
+    /// Add the channels to `select` which end `Fetch::Idle` early.
+    pub fn watch<'a>(&'a self, select: &mut Select<'a>) {
+        select.recv(&self.msgs_out);
+        select.recv(&self.acks_out);
+        select.recv(&self.acks_inc);
+        select.recv(&self.pings_inc);
+        select.recv(&self.wake);
+    }
+
     /// Returns the next packet to be sent to the sim.
     ///
     /// Note that this method will block the current thread until something is
     /// available.
-    pub fn fetch(&mut self) -> Packet {
-        let (packet, future) = self._fetch_loop();
+    ///
+    /// Once the circuit is closed this returns the remaining queued messages
+    /// and acks, then `None`. Messages still waiting for their ack fail with
+    /// `SendMessageError::Closed` at this point.
+    pub fn fetch(&mut self) -> Option<Packet> {
+        loop {
+            match self.poll_fetch() {
+                Fetch::Packet(packet) => return Some(packet),
+                Fetch::Idle(until) => self._wait(until),
+                Fetch::Closed => return None,
+            }
+        }
+    }
+
+    /// Like `fetch`, but returns `Fetch::Idle` instead of blocking, so one
+    /// thread can serve the ack managers of many circuits.
+    pub fn poll_fetch(&mut self) -> Fetch {
+        let (packet, future) = match self._fetch_next() {
+            Next::Packet(packet, future) => (packet, future),
+            Next::Idle => return Fetch::Idle(self._idle_until()),
+            Next::Closed => {
+                let error = if self.dead.load(Ordering::SeqCst) {
+                    SendMessageError::RemoteDead
+                } else {
+                    SendMessageError::Closed
+                };
+                self._fail_pending(error);
+                self._update_stats();
+                return Fetch::Closed;
+            }
+        };
+
+        if let Some(category) =
+            ThrottleCategory::of(&packet.message, packet.has_flag(PacketFlags::RESENT))
+        {
+            let len = packet.encoded_len();
+            self.throttle.lock().unwrap().consume(category, len);
+        }
 
         if packet.is_reliable() {
+            self.unacked.insert(packet.sequence_number);
+
             // Put message into wait queue.
+            if let SendMessageStatus::PendingAck { timeout, .. } = future.get_status() {
+                self.ack_timeouts.insert(packet.sequence_number, timeout);
+            }
             self.acks_wait.insert(
                 packet.sequence_number,
                 PendingMessage {
@@ -83,14 +269,58 @@ impl AckManagerRx {
             );
         }
 
-        packet
+        self._update_stats();
+        Fetch::Packet(packet)
+    }
+
+    fn _update_stats(&self) {
+        let held: usize = self.held.iter().map(|queue| queue.len()).sum();
+        self.stats.set_outgoing(
+            self.msgs_out.len() + held,
+            self.acks_out.len(),
+            self.acks_wait.len(),
+        );
+    }
+
+    /// Publish a snapshot of the stats, dropping it if nobody reads them.
+    fn _emit_stats(&self) {
+        self._update_stats();
+        let rtt = self.rtt.lock().unwrap().srtt();
+        let _ = self.stats_events.try_send(self.stats.snapshot(rtt));
+    }
+
+    fn _fail_pending(&mut self, error: SendMessageError) {
+        // Messages queued after this are failed by their sender.
+        self.stopped.store(true, Ordering::SeqCst);
+        self.unacked.clear();
+        let closed = SendMessageStatus::Failure(error);
+        while let Some((_, mut msg)) = self.acks_wait.remove_head() {
+            msg.future.update_status(closed);
+        }
+        for queue in &mut self.held {
+            for mut msg in queue.drain(..) {
+                msg.future.update_status(closed);
+            }
+        }
+        while let Ok(mut msg) = self.msgs_out.try_recv() {
+            msg.future.update_status(closed);
+        }
     }
 
     fn _next_message(&mut self) -> Option<PendingMessage> {
         // Apply all available incoming acks.
-        while let Ok(ack) = self.acks_inc.try_recv() {
+        while let Ok((ack, received)) = self.acks_inc.try_recv() {
             if let Some(mut acked_msg) = self.acks_wait.remove_key(&ack) {
                 // debug!(self.logger, "incoming ack (msg found): {}", ack);
+                self.unacked.remove(&ack);
+
+                // Only first attempts are unambiguous samples of the RTT.
+                if let SendMessageStatus::PendingAck {
+                    attempt: 0, sent, ..
+                } = acked_msg.future.get_status()
+                {
+                    self._sample_rtt(sent, received);
+                }
 
                 // Mark the PendingMessage as successful.
                 acked_msg.future.update_status(SendMessageStatus::Success);
@@ -98,63 +328,168 @@ impl AckManagerRx {
                 // debug!(self.logger, "incoming ack (msg not found): {}", ack);
             }
         }
+        while let Ok((ping_id, received)) = self.pings_inc.try_recv() {
+            if let Some(sent) = self.pings_wait.remove(&ping_id) {
+                self._sample_rtt(sent, received);
+            }
+        }
 
-        // First check if there is a message waiting too long for an ack already, then
-        // check the other pending messages for sending.
-        if let Some((_, wait_oldest)) = self.acks_wait.remove_head() {
-            if wait_oldest.is_too_old() {
-                return Some(wait_oldest);
-            } else {
-                let seq_number = wait_oldest.sequence_number().unwrap();
-                self.acks_wait.insert_head(seq_number, wait_oldest);
+        // Messages waiting too long for an ack already are resent, which has
+        // priority over the other pending messages.
+        while let Some(seq_number) = self.ack_timeouts.extract() {
+            if let Some(msg) = self.acks_wait.remove_key(&seq_number) {
+                self.held[ThrottleCategory::Resend as usize].push_back(msg);
             }
         }
 
-        match self.msgs_out.try_recv() {
-            Ok(msg) => Some(msg),
-            Err(TryRecvError::Empty) => {
-                // If there are pending acks to be sent out, return a PacketAck.
-                let acks = mpsc_read_many(&self.acks_out, 255);
-                if acks.is_empty() {
-                    None
-                } else {
-                    Some(PendingMessage {
-                        message: PacketAck {
-                            packets: acks
-                                .iter()
-                                .map(|num| PacketAck_Packets { id: *num })
-                                .collect(),
-                        }
-                        .into(),
-                        future: SendMessage::new(SendMessageStatus::PendingSend {
-                            reliable: false,
-                        }),
-                    })
+        let now = Instant::now();
+        if self._ping_due() <= now && !self.closing.load(Ordering::SeqCst) {
+            self.next_ping = now + self.config.ping_interval;
+            return Some(self._ping(now));
+        }
+        if let (Some(next_stats), Some(interval)) = (self.next_stats, self.config.stats_interval) {
+            if next_stats <= now {
+                self.next_stats = Some(now + interval);
+                self._emit_stats();
+            }
+        }
+
+        loop {
+            match self.msgs_out.try_recv() {
+                Ok(msg) => match ThrottleCategory::of(&msg.message, false) {
+                    Some(category) => self.held[category as usize].push_back(msg),
+                    None => return Some(msg),
+                },
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    // All senders are gone, nobody can close the circuit anymore.
+                    self.closing.store(true, Ordering::SeqCst);
+                    break;
                 }
             }
-            // TODO: return error.
-            Err(TryRecvError::Disconnected) => panic!("unimplemented error handling"),
+        }
+        if let Some(msg) = self._next_held(now) {
+            return Some(msg);
+        }
+
+        // If there are pending acks to be sent out, return a PacketAck.
+        let acks: Vec<_> = self.acks_out.try_iter().take(255).collect();
+        if acks.is_empty() {
+            None
+        } else {
+            Some(PendingMessage {
+                message: PacketAck {
+                    packets: acks
+                        .iter()
+                        .map(|num| PacketAck_Packets { id: *num })
+                        .collect(),
+                }
+                .into(),
+                future: SendMessage::new(SendMessageStatus::PendingSend { reliable: false }),
+            })
+        }
+    }
+
+    /// The first held message, in priority order of the categories, whose
+    /// bucket allows sending it now.
+    ///
+    /// Otherwise remembers when this is the case next, for `_idle_until`.
+    fn _next_held(&mut self, now: Instant) -> Option<PendingMessage> {
+        let closing = self.closing.load(Ordering::SeqCst);
+        let mut throttle = self.throttle.lock().unwrap();
+        self.next_unthrottled = None;
+        for category in THROTTLE_CATEGORIES.iter() {
+            let queue = &mut self.held[*category as usize];
+            if queue.is_empty() {
+                continue;
+            }
+            match throttle.ready(*category, now) {
+                Ok(()) => return queue.pop_front(),
+                // Everything queued is flushed once the circuit is closed.
+                Err(_) if closing => return queue.pop_front(),
+                Err(wait) => {
+                    let next = now + wait;
+                    self.next_unthrottled = Some(match self.next_unthrottled {
+                        Some(other) => min(other, next),
+                        None => next,
+                    });
+                }
+            }
+        }
+        None
+    }
+    fn _sample_rtt(&self, sent: Instant, received: Instant) {
+        if received > sent {
+            self.rtt.lock().unwrap().sample(received - sent);
+        }
+    }
+
+    /// The time the next ping is due, if nothing is received until then.
+    fn _ping_due(&self) -> Instant {
+        let idle = *self.last_received.lock().unwrap() + self.config.ping_interval;
+        if idle > self.next_ping {
+            idle
+        } else {
+            self.next_ping
+        }
+    }
+
+    /// Create the next `StartPingCheck`, remembering when it was sent.
+    ///
+    /// Its `oldest_unacked` is filled in once the sequence number is known.
+    fn _ping(&mut self, now: Instant) -> PendingMessage {
+        let ping_id = self.next_ping_id;
+        self.next_ping_id = ping_id.wrapping_add(1);
+        // Replies to an earlier ping with the same id would give a wrong
+        // sample, but the ids only wrap after 256 pings.
+        self.pings_wait.insert(ping_id, now);
+
+        PendingMessage {
+            message: StartPingCheck {
+                ping_id: StartPingCheck_PingID {
+                    ping_id: ping_id,
+                    oldest_unacked: 0,
+                },
+            }
+            .into(),
+            future: SendMessage::new(SendMessageStatus::PendingSend { reliable: false }),
         }
     }
 
     fn _prepare_packet(&self, msg: PendingMessage) -> (Option<Packet>, SendMessageStatus) {
         let old_status = msg.future.get_status();
+        let now = Instant::now();
         match old_status {
             SendMessageStatus::PendingSend { reliable } => {
                 let seq_num = self.sequence_counter.next();
+                let mut message = msg.message;
+                if let MessageInstance::StartPingCheck(ref mut msg) = message {
+                    msg.ping_id.oldest_unacked = match self.unacked.iter().next() {
+                        Some(oldest) => *oldest,
+                        None => seq_num,
+                    };
+                }
                 let new_status = SendMessageStatus::PendingAck {
                     attempt: 0,
-                    timeout: Instant::now() + self.config.send_timeout,
+                    sent: now,
+                    timeout: now + self.rtt.lock().unwrap().rto(),
                     id: seq_num,
                 };
 
-                let mut packet = Packet::new(msg.message, seq_num);
+                let mut packet = Packet::new(message, seq_num);
                 packet.set_reliable(reliable);
-                (Some(packet), new_status)
+                match Self::_fit_packet(&mut packet) {
+                    Ok(()) => (Some(packet), new_status),
+                    Err(e) => (None, SendMessageStatus::Failure(e)),
+                }
             }
             SendMessageStatus::PendingAck { attempt, id, .. } => {
                 let attempt = attempt + 1;
                 if attempt >= self.config.send_attempts as u8 {
+                    self.stats.failed_ack();
+                    let _ = self
+                        .events
+                        .try_send(CircuitEvent::AckFailed(msg.message.message_type()));
                     (
                         None,
                         SendMessageStatus::Failure(SendMessageError::FailedAck),
@@ -162,75 +497,343 @@ impl AckManagerRx {
                 } else {
                     let new_status = SendMessageStatus::PendingAck {
                         attempt: attempt,
-                        timeout: Instant::now() + self.config.send_timeout,
+                        sent: now,
+                        timeout: now + self.rtt.lock().unwrap().backoff(attempt),
                         id: id,
                     };
+                    self.stats.resend();
                     let mut packet = Packet::new(msg.message, id);
                     packet.set_reliable(true);
                     packet.enable_flags(PacketFlags::RESENT);
-                    (Some(packet), new_status)
+                    match Self::_fit_packet(&mut packet) {
+                        Ok(()) => (Some(packet), new_status),
+                        Err(e) => (None, SendMessageStatus::Failure(e)),
+                    }
                 }
             }
             SendMessageStatus::Success | SendMessageStatus::Failure(_) => (None, old_status),
         }
     }
+
+    /// Zerocode the packet if the template asks for it and this actually
+    /// saves bytes, then make sure it fits into the MTU.
+    ///
+    /// Afterwards the message must not be changed anymore, `encoded_len` of
+    /// the packet relies on the length determined here.
+    fn _fit_packet(packet: &mut Packet) -> Result<(), SendMessageError> {
+        if packet.message.message_type().meta().zerocoded {
+            // The message was validated by `send_msg`.
+            packet.zerocode_if_smaller_unvalidated();
+        }
+        let len = packet.encoded_len();
+        if len > MTU {
+            Err(SendMessageError::TooLarge(len))
+        } else {
+            Ok(())
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct AckManagerTx {
-    acks_out: mpsc::Sender<SequenceNumber>,
-    acks_inc: mpsc::Sender<SequenceNumber>,
-    msgs_out: mpsc::Sender<PendingMessage>,
+    acks_out: Sender<SequenceNumber>,
+    acks_inc: Sender<(SequenceNumber, Instant)>,
+    pings_inc: Sender<(u8, Instant)>,
+    msgs_out: Sender<PendingMessage>,
+    wake: Sender<()>,
 
     /// Copy of circuit config.
     config: CircuitConfig,
+    rtt: Arc<Mutex<RttEstimator>>,
+    throttle: Arc<Mutex<Throttle>>,
+    stats: Arc<StatsCounters>,
+    stats_events: Receiver<CircuitStats>,
+    events_tx: Sender<CircuitEvent>,
+    events_rx: Receiver<CircuitEvent>,
+    /// The time the last `CircuitEvent::SocketError` was emitted.
+    last_socket_error: Arc<Mutex<Option<Instant>>>,
+    last_received: Arc<Mutex<Instant>>,
+
+    closing: Arc<AtomicBool>,
+    dead: Arc<AtomicBool>,
+    stopped: Arc<AtomicBool>,
 }
 
 impl AckManagerTx {
+    /// Stop accepting new messages, `AckManagerRx::fetch` returns `None`
+    /// once everything queued until now was sent.
+    pub fn close(&self) {
+        self.closing.store(true, Ordering::SeqCst);
+        // Fails if the receiving side is gone already, then there is nothing
+        // to wake up.
+        let _ = self.wake.send(());
+    }
+
+    /// Stop the circuit because the sim stopped responding.
+    ///
+    /// Unlike `close` nothing is sent anymore, all pending messages fail with
+    /// `SendMessageError::RemoteDead` right away.
+    pub fn declare_dead(&self) {
+        self.dead.store(true, Ordering::SeqCst);
+        self.close();
+    }
+
+    /// Register that a packet from the sim arrived, no pings are sent while
+    /// the sim is not idle.
+    pub fn register_received(&self) {
+        *self.last_received.lock().unwrap() = Instant::now();
+    }
+
     /// Queue an ack to be sent out as soon as possible.
-    pub fn send_ack(&self, ack: SequenceNumber) -> Result<(), mpsc::SendError<SequenceNumber>> {
+    pub fn send_ack(&self, ack: SequenceNumber) -> Result<(), SendError<SequenceNumber>> {
         // debug!(self.logger, "send_ack: {}", ack);
         self.acks_out.send(ack)
     }
 
     /// Register an incoming ack to be processed.
-    pub fn register_ack(&self, ack: SequenceNumber) -> Result<(), mpsc::SendError<SequenceNumber>> {
+    pub fn register_ack(
+        &self,
+        ack: SequenceNumber,
+    ) -> Result<(), SendError<(SequenceNumber, Instant)>> {
         // debug!(self.logger, "register_ack: {}", ack);
-        self.acks_inc.send(ack)
+        self.acks_inc.send((ack, Instant::now()))
+    }
+
+    /// Register the `CompletePingCheck` for one of our pings.
+    pub fn register_ping(&self, ping_id: u8) -> Result<(), SendError<(u8, Instant)>> {
+        self.pings_inc.send((ping_id, Instant::now()))
+    }
+
+    /// The smoothed round-trip time, `None` until it was measured.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt.lock().unwrap().srtt()
+    }
+
+    /// The counters of the circuit, shared with its reader and sender threads.
+    pub fn stats_counters(&self) -> Arc<StatsCounters> {
+        Arc::clone(&self.stats)
+    }
+
+    /// A snapshot of the stats of the circuit.
+    pub fn stats(&self) -> CircuitStats {
+        self.stats.snapshot(self.rtt())
+    }
+
+    /// The periodic stats snapshots, see `CircuitConfig::stats_interval`.
+    pub fn stats_events(&self) -> Receiver<CircuitStats> {
+        self.stats_events.clone()
+    }
+
+    /// Publish an event of the circuit, dropping it if too many events
+    /// were not read yet.
+    pub fn emit(&self, event: CircuitEvent) {
+        let _ = self.events_tx.try_send(event);
+    }
+
+    /// Report that reading from or writing to the socket failed.
+    ///
+    /// Every error is counted, but a broken socket fails on every attempt,
+    /// so only one `CircuitEvent::SocketError` is emitted per
+    /// `SOCKET_ERROR_INTERVAL`. Returns whether the error was emitted.
+    pub fn socket_error(&self, error: IoError) -> bool {
+        self.stats.socket_error();
+        let now = Instant::now();
+        {
+            let mut last = self.last_socket_error.lock().unwrap();
+            match *last {
+                Some(time) if now < time + SOCKET_ERROR_INTERVAL => return false,
+                _ => *last = Some(now),
+            }
+        }
+        self.emit(CircuitEvent::SocketError(error));
+        true
+    }
+
+    /// The events of the circuit, see `Circuit::events`.
+    pub fn events(&self) -> Receiver<CircuitEvent> {
+        self.events_rx.clone()
+    }
+
+    /// Change the budgets of our outgoing packets, `None` to stop throttling
+    /// them.
+    pub fn set_throttles(&self, throttles: Option<&Throttles>) {
+        self.throttle.lock().unwrap().set(throttles);
+        // Held messages might be sendable now.
+        let _ = self.wake.send(());
     }
 
     pub fn send_msg(&self, msg: MessageInstance, reliable: bool) -> SendMessage {
         // debug!(self.logger, "send_msg: {:?}", msg);
+        if !msg.message_type().meta().is_sendable() {
+            return SendMessage::new(SendMessageStatus::Failure(SendMessageError::NotSendable));
+        }
+        if self.dead.load(Ordering::SeqCst) {
+            return SendMessage::new(SendMessageStatus::Failure(SendMessageError::RemoteDead));
+        }
+        if self.closing.load(Ordering::SeqCst) {
+            return SendMessage::new(SendMessageStatus::Failure(SendMessageError::Closed));
+        }
+
+        // Messages which are too large or have too many blocks for one
+        // packet are split, unless zerocoding makes them fit. Only messages
+        // whose blocks are independent of each other can be split, see
+        // `MessageInstance::split`, the others fail.
+        let max_len = MTU - HEADER_SIZE;
+        let parts = match msg.validate() {
+            Ok(()) if Self::_fits(&msg, max_len) => vec![msg],
+            result => {
+                let parts = match msg.split(max_len) {
+                    Ok(parts) => parts,
+                    Err(msg) => {
+                        let error = match result {
+                            Ok(()) => SendMessageError::TooLarge(HEADER_SIZE + msg.encoded_len()),
+                            Err(e) => SendMessageError::Invalid(e),
+                        };
+                        return SendMessage::new(SendMessageStatus::Failure(error));
+                    }
+                };
+                // Splitting fixes the number of blocks, but the parts might
+                // still violate other limits.
+                if result.is_err() {
+                    for part in &parts {
+                        if let Err(e) = part.validate() {
+                            return SendMessage::new(SendMessageStatus::Failure(
+                                SendMessageError::Invalid(e),
+                            ));
+                        }
+                    }
+                }
+                parts
+            }
+        };
+        if parts.len() == 1 {
+            return self._queue_msg(parts.into_iter().next().unwrap(), reliable);
+        }
+        let futures = parts
+            .into_iter()
+            .map(|part| self._queue_msg(part, reliable))
+            .collect();
+        SendMessage::from_parts(futures)
+    }
+
+    /// Whether the valid message fits into one packet, zerocoded if its
+    /// template asks for it.
+    fn _fits(msg: &MessageInstance, max_len: usize) -> bool {
+        if msg.encoded_len() <= max_len {
+            return true;
+        }
+        msg.message_type().meta().zerocoded && packet::zerocoded_len(msg) <= max_len
+    }
+
+    fn _queue_msg(&self, msg: MessageInstance, reliable: bool) -> SendMessage {
         let future = SendMessage::new(SendMessageStatus::PendingSend { reliable: reliable });
         let p_m = PendingMessage {
             message: msg,
             future: future.clone(),
         };
 
-        self.msgs_out.send(p_m).unwrap();
+        match self.msgs_out.send(p_m) {
+            Ok(()) => {}
+            // The sender thread has exited already.
+            Err(_) => {
+                return SendMessage::new(SendMessageStatus::Failure(SendMessageError::Closed))
+            }
+        }
+
+        // The circuit might have been closed after `send_msg` checked it, and
+        // the remaining messages failed before this one was queued. Then
+        // nobody takes it from the queue anymore. If it was still taken, it
+        // failed with the same error.
+        if self.stopped.load(Ordering::SeqCst) {
+            let error = if self.dead.load(Ordering::SeqCst) {
+                SendMessageError::RemoteDead
+            } else {
+                SendMessageError::Closed
+            };
+            let mut future = future;
+            future.update_status(SendMessageStatus::Failure(error));
+            return future;
+        }
         future
     }
 }
 
+/// The number of periodic stats snapshots kept until they are read, newer
+/// ones are dropped.
+const STATS_EVENTS_CAPACITY: usize = 64;
+
+/// The number of circuit events kept until they are read, newer ones are
+/// dropped.
+const EVENTS_CAPACITY: usize = 256;
+
+/// The minimum time between two `CircuitEvent::SocketError`.
+const SOCKET_ERROR_INTERVAL: Duration = Duration::from_secs(1);
+
 /// Create a new instance of the AckManager tx and rx.
 pub fn new(config: CircuitConfig) -> (AckManagerTx, AckManagerRx) {
-    let (acks_out_tx, acks_out_rx) = mpsc::channel();
-    let (acks_inc_tx, acks_inc_rx) = mpsc::channel();
-    let (msgs_out_tx, msgs_out_rx) = mpsc::channel();
+    let (acks_out_tx, acks_out_rx) = crossbeam_channel::unbounded();
+    let (acks_inc_tx, acks_inc_rx) = crossbeam_channel::unbounded();
+    let (pings_inc_tx, pings_inc_rx) = crossbeam_channel::unbounded();
+    let (msgs_out_tx, msgs_out_rx) = crossbeam_channel::unbounded();
+    let (wake_tx, wake_rx) = crossbeam_channel::unbounded();
+    let closing = Arc::new(AtomicBool::new(false));
+    let dead = Arc::new(AtomicBool::new(false));
+    let stopped = Arc::new(AtomicBool::new(false));
+    let last_received = Arc::new(Mutex::new(Instant::now()));
+    let rtt = Arc::new(Mutex::new(RttEstimator::new(config.send_timeout)));
+    let throttle = Arc::new(Mutex::new(Throttle::new(config.throttles.as_ref())));
+    let stats = Arc::new(StatsCounters::default());
+    let next_stats = config
+        .stats_interval
+        .map(|interval| Instant::now() + interval);
+    let (stats_tx, stats_rx) = crossbeam_channel::bounded(STATS_EVENTS_CAPACITY);
+    let (events_tx, events_rx) = crossbeam_channel::bounded(EVENTS_CAPACITY);
 
     let tx = AckManagerTx {
         acks_out: acks_out_tx,
         acks_inc: acks_inc_tx,
+        pings_inc: pings_inc_tx,
         msgs_out: msgs_out_tx,
+        wake: wake_tx,
         config: config.clone(),
+        rtt: rtt.clone(),
+        throttle: throttle.clone(),
+        stats: stats.clone(),
+        stats_events: stats_rx,
+        events_tx: events_tx.clone(),
+        events_rx: events_rx,
+        last_socket_error: Arc::new(Mutex::new(None)),
+        last_received: last_received.clone(),
+        closing: closing.clone(),
+        dead: dead.clone(),
+        stopped: stopped.clone(),
     };
     let rx = AckManagerRx {
         acks_wait: AddressableQueue::new(),
+        ack_timeouts: BackoffQueue::new(),
         acks_inc: acks_inc_rx,
+        pings_inc: pings_inc_rx,
+        pings_wait: HashMap::new(),
+        next_ping_id: 0,
+        next_ping: Instant::now() + config.ping_interval,
+        last_received: last_received,
+        unacked: BTreeSet::new(),
         acks_out: acks_out_rx,
         msgs_out: msgs_out_rx,
+        held: THROTTLE_CATEGORIES.iter().map(|_| VecDeque::new()).collect(),
+        throttle: throttle,
+        next_unthrottled: None,
+        wake: wake_rx,
         config: config,
         sequence_counter: AtomicU32Counter::new(0),
+        rtt: rtt,
+        stats: stats,
+        stats_events: stats_tx,
+        next_stats: next_stats,
+        events: events_tx,
+        closing: closing,
+        dead: dead,
+        stopped: stopped,
     };
 
     (tx, rx)
@@ -242,20 +845,360 @@ pub struct PendingMessage {
     pub future: SendMessage,
 }
 
-impl PendingMessage {
-    /// Returns true if the packet has been waiting for an ack for too long by
-    /// now.
-    fn is_too_old(&self) -> bool {
-        match self.future.get_status() {
-            SendMessageStatus::PendingAck { timeout, .. } => timeout < Instant::now(),
-            _ => false,
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use messages::{
+        MessageType, StartPingCheck, StartPingCheck_PingID, UseCircuitCode,
+        UseCircuitCode_CircuitCode,
+    };
+    use types::Uuid;
+    use std::thread;
+    use std::time::Duration;
+    use test::Bencher;
+
+    fn config() -> CircuitConfig {
+        CircuitConfig {
+            send_timeout: Duration::from_millis(5000),
+            send_attempts: 5,
+            ping_interval: Duration::from_secs(60),
+            throttles: None,
+            trusted: true,
+            silence_timeout: Duration::from_secs(10),
+            dead_timeout: None,
+            stats_interval: None,
+        }
+    }
+
+    fn ping() -> StartPingCheck {
+        StartPingCheck {
+            ping_id: StartPingCheck_PingID {
+                ping_id: 1,
+                oldest_unacked: 0,
+            },
+        }
+    }
+
+    #[test]
+    fn close_flushes_acks() {
+        let (tx, mut rx) = new(config());
+        let pending = tx.send_msg(ping().into(), true);
+        assert!(rx.fetch().is_some());
+
+        tx.send_ack(42).unwrap();
+        tx.close();
+
+        // The queued ack is still sent out.
+        let packet = rx.fetch().unwrap();
+        match packet.message {
+            MessageInstance::PacketAck(msg) => assert_eq!(msg.packets[0].id, 42),
+            msg => panic!("unexpected message: {:?}", msg),
+        }
+        assert!(rx.fetch().is_none());
+
+        // The message waiting for its ack failed, new ones fail right away.
+        match pending.get_status() {
+            SendMessageStatus::Failure(SendMessageError::Closed) => {}
+            s => panic!("unexpected status: {:?}", s),
+        }
+        match tx.send_msg(ping().into(), false).get_status() {
+            SendMessageStatus::Failure(SendMessageError::Closed) => {}
+            s => panic!("unexpected status: {:?}", s),
+        }
+    }
+
+    #[test]
+    fn queued_after_close() {
+        let (tx, mut rx) = new(config());
+        tx.close();
+        assert!(rx.fetch().is_none());
+
+        // A message which passed the check in `send_msg` before the circuit
+        // was closed, but is queued after the remaining ones failed.
+        match tx._queue_msg(ping().into(), true).get_status() {
+            SendMessageStatus::Failure(SendMessageError::Closed) => {}
+            s => panic!("unexpected status: {:?}", s),
+        }
+    }
+
+    #[test]
+    fn resend_on_timeout() {
+        let (tx, mut rx) = new(CircuitConfig {
+            send_timeout: Duration::from_millis(50),
+            send_attempts: 2,
+            ping_interval: Duration::from_secs(60),
+            throttles: None,
+            trusted: true,
+            silence_timeout: Duration::from_secs(10),
+            dead_timeout: None,
+            stats_interval: None,
+        });
+        let pending = tx.send_msg(ping().into(), true);
+        let start = Instant::now();
+        let first = rx.fetch().unwrap();
+        assert!(!first.has_flag(PacketFlags::RESENT));
+
+        // Blocks until the ack timed out.
+        let second = rx.fetch().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert!(second.has_flag(PacketFlags::RESENT));
+        assert_eq!(second.sequence_number, first.sequence_number);
+
+        // Acks arriving while blocked are applied right away.
+        let handle = thread::spawn(move || {
+            let packet = rx.fetch();
+            (rx, packet)
+        });
+        tx.register_ack(first.sequence_number).unwrap();
+        tx.close();
+        let (_rx, packet) = handle.join().unwrap();
+        assert!(packet.is_none());
+        match pending.get_status() {
+            SendMessageStatus::Success => {}
+            s => panic!("unexpected status: {:?}", s),
         }
     }
 
-    fn sequence_number(&self) -> Option<SequenceNumber> {
-        match self.future.get_status() {
-            SendMessageStatus::PendingAck { id, .. } => Some(id),
-            _ => None,
+    #[test]
+    fn rtt_from_pings() {
+        let (tx, mut rx) = new(CircuitConfig {
+            ping_interval: Duration::from_millis(10),
+            ..config()
+        });
+        assert_eq!(tx.rtt(), None);
+
+        let packet = rx.fetch().unwrap();
+        match packet.message {
+            MessageInstance::StartPingCheck(msg) => {
+                tx.register_ping(msg.ping_id.ping_id).unwrap();
+            }
+            msg => panic!("unexpected message: {:?}", msg),
+        }
+        tx.close();
+        assert!(rx.fetch().is_none());
+        assert!(tx.rtt().is_some());
+    }
+
+    #[test]
+    fn adaptive_resend_timeout() {
+        let (tx, mut rx) = new(config());
+
+        // A quick ack gives a resend timeout far below the 5 s.
+        tx.send_msg(ping().into(), true);
+        let packet = rx.fetch().unwrap();
+        tx.register_ack(packet.sequence_number).unwrap();
+        tx.send_msg(ping().into(), true);
+        let start = Instant::now();
+        let first = rx.fetch().unwrap();
+        assert!(tx.rtt().unwrap() < Duration::from_millis(100));
+
+        let resent = rx.fetch().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(resent.has_flag(PacketFlags::RESENT));
+        assert_eq!(resent.sequence_number, first.sequence_number);
+    }
+
+    #[test]
+    fn throttled_send() {
+        // 100 bytes per second for task packets, with a burst of 25 bytes.
+        let mut throttles = Throttles::from_total(0.);
+        throttles.task = 800.;
+        let (tx, mut rx) = new(CircuitConfig {
+            throttles: Some(throttles),
+            ..config()
+        });
+        let msg = UseCircuitCode {
+            circuit_code: UseCircuitCode_CircuitCode {
+                code: 1,
+                session_id: Uuid::nil(),
+                id: Uuid::nil(),
+            },
+        };
+        tx.send_msg(msg.clone().into(), false);
+        tx.send_msg(msg.into(), false);
+        tx.send_msg(ping().into(), false);
+
+        // The ping is sent right away, the first message is within the burst
+        // and the second one waits until the debt of the first is paid.
+        let start = Instant::now();
+        let first = rx.fetch().unwrap();
+        assert_eq!(first.message.message_type(), MessageType::StartPingCheck);
+        let second = rx.fetch().unwrap();
+        assert_eq!(second.message.message_type(), MessageType::UseCircuitCode);
+        assert!(start.elapsed() < Duration::from_millis(100));
+        let third = rx.fetch().unwrap();
+        assert_eq!(third.message.message_type(), MessageType::UseCircuitCode);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        // Without budgets nothing is held back anymore.
+        tx.set_throttles(None);
+        tx.send_msg(third.message, false);
+        let start = Instant::now();
+        assert!(rx.fetch().is_some());
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn keepalive_and_dead() {
+        let (tx, mut rx) = new(CircuitConfig {
+            ping_interval: Duration::from_millis(10),
+            ..config()
+        });
+        let pending = tx.send_msg(ping().into(), true);
+        let sent = rx.fetch().unwrap();
+
+        // The sim is idle, the next ping tells it about the unacked packet.
+        let packet = rx.fetch().unwrap();
+        match packet.message {
+            MessageInstance::StartPingCheck(msg) => {
+                assert_eq!(msg.ping_id.oldest_unacked, sent.sequence_number);
+            }
+            msg => panic!("unexpected message: {:?}", msg),
+        }
+
+        tx.send_msg(ping().into(), false);
+        tx.declare_dead();
+        assert!(rx.fetch().is_none());
+        match pending.get_status() {
+            SendMessageStatus::Failure(SendMessageError::RemoteDead) => {}
+            s => panic!("unexpected status: {:?}", s),
+        }
+        match tx.send_msg(ping().into(), false).get_status() {
+            SendMessageStatus::Failure(SendMessageError::RemoteDead) => {}
+            s => panic!("unexpected status: {:?}", s),
         }
     }
+
+    #[test]
+    fn stats() {
+        let (tx, mut rx) = new(CircuitConfig {
+            send_timeout: Duration::from_millis(20),
+            send_attempts: 2,
+            stats_interval: Some(Duration::from_millis(10)),
+            ..config()
+        });
+        tx.send_msg(ping().into(), true);
+        tx.send_ack(1).unwrap();
+
+        // Sent with the ack appended, then resent once and failed.
+        assert!(rx.fetch().is_some());
+        assert_eq!(tx.stats().unacked, 1);
+        assert!(rx.fetch().is_some());
+        let handle = thread::spawn(move || rx.fetch());
+        thread::sleep(Duration::from_millis(50));
+        tx.close();
+        assert!(handle.join().unwrap().is_none());
+
+        let stats = tx.stats();
+        assert_eq!(stats.resends, 1);
+        assert_eq!(stats.failed_acks, 1);
+        assert_eq!(stats.unacked, 0);
+        assert!(tx.stats_events().try_recv().is_ok());
+        match tx.events().try_recv() {
+            Ok(CircuitEvent::AckFailed(MessageType::StartPingCheck)) => {}
+            e => panic!("unexpected event: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn events_bounded() {
+        use std::io::ErrorKind as IoErrorKind;
+
+        let (tx, _rx) = new(config());
+        for _ in 0..EVENTS_CAPACITY + 10 {
+            tx.emit(CircuitEvent::Connected);
+        }
+        assert_eq!(tx.events().try_iter().count(), EVENTS_CAPACITY);
+
+        // A failing socket is reported once, but every error is counted.
+        let error = || IoError::new(IoErrorKind::Other, "broken");
+        assert!(tx.socket_error(error()));
+        assert!(!tx.socket_error(error()));
+        assert!(!tx.socket_error(error()));
+        assert_eq!(tx.events().try_iter().count(), 1);
+        assert_eq!(tx.stats().socket_errors, 3);
+    }
+
+    #[test]
+    fn split_or_zerocode() {
+        use messages::{
+            EstateOwnerMessage, EstateOwnerMessage_AgentData, EstateOwnerMessage_MethodData,
+            EstateOwnerMessage_ParamList, RequestMultipleObjects, RequestMultipleObjects_AgentData,
+            RequestMultipleObjects_ObjectData,
+        };
+
+        let (tx, mut rx) = new(config());
+        let request = |count: u32| RequestMultipleObjects {
+            agent_data: RequestMultipleObjects_AgentData {
+                agent_id: Uuid::nil(),
+                session_id: Uuid::nil(),
+            },
+            object_data: (0..count)
+                .map(|id| RequestMultipleObjects_ObjectData {
+                    cache_miss_type: 0,
+                    id: id,
+                })
+                .collect(),
+        };
+
+        // Too large unless zerocoded, which makes it fit into one packet.
+        let msg: MessageInstance = request(240).into();
+        assert!(msg.encoded_len() > MTU - HEADER_SIZE);
+        tx.send_msg(msg, false);
+        let packet = rx.fetch().unwrap();
+        assert!(packet.has_flag(PacketFlags::ZEROCODED));
+        match packet.message {
+            MessageInstance::RequestMultipleObjects(msg) => assert_eq!(msg.object_data.len(), 240),
+            msg => panic!("unexpected message: {:?}", msg),
+        }
+
+        // Too many blocks for one packet, split.
+        tx.send_msg(request(300).into(), false);
+        assert!(rx.fetch().is_some());
+        assert!(rx.fetch().is_some());
+
+        // Applying an estate change in pieces would be wrong, this is never
+        // split.
+        let estate = EstateOwnerMessage {
+            agent_data: EstateOwnerMessage_AgentData {
+                agent_id: Uuid::nil(),
+                session_id: Uuid::nil(),
+                transaction_id: Uuid::nil(),
+            },
+            method_data: EstateOwnerMessage_MethodData {
+                method: b"setregioninfo".to_vec(),
+                invoice: Uuid::nil(),
+            },
+            param_list: (0..10)
+                .map(|_| EstateOwnerMessage_ParamList {
+                    parameter: vec![b'a'; 200],
+                })
+                .collect(),
+        };
+        match tx.send_msg(estate.into(), true).get_status() {
+            SendMessageStatus::Failure(SendMessageError::TooLarge(len)) => assert!(len > MTU),
+            s => panic!("unexpected status: {:?}", s),
+        }
+    }
+
+    /// Time from queueing a message until the sender thread has the packet,
+    /// this used to be up to 50 ms because of polling.
+    #[bench]
+    fn bench_send_latency(b: &mut Bencher) {
+        let (tx, mut rx) = new(config());
+        let (sent_tx, sent_rx) = crossbeam_channel::unbounded();
+        let sender = thread::spawn(move || {
+            while let Some(packet) = rx.fetch() {
+                sent_tx.send(packet.sequence_number).unwrap();
+            }
+        });
+
+        b.iter(|| {
+            tx.send_msg(ping().into(), false);
+            sent_rx.recv().unwrap()
+        });
+
+        tx.close();
+        sender.join().unwrap();
+    }
 }
@@ -0,0 +1,43 @@
+//! Events of a circuit applications can react to, see `Circuit::events`.
+
+use circuit::message_handlers;
+use messages::MessageType;
+
+use std::io::Error as IoError;
+use std::time::Duration;
+
+#[derive(Debug)]
+pub enum CircuitEvent {
+    /// The first packet from the sim arrived.
+    Connected,
+
+    /// Nothing was received from the sim for the provided duration, which is
+    /// at least `CircuitConfig::silence_timeout`.
+    ///
+    /// Only sent once until the sim is heard of again.
+    RemoteSilent(Duration),
+
+    /// Reading from or writing to the socket failed, the circuit keeps
+    /// running.
+    ///
+    /// Only sent once per second while the socket keeps failing, all errors
+    /// are counted in `CircuitStats::socket_errors`.
+    SocketError(IoError),
+
+    /// A message handler failed, the message is attached to the error.
+    HandlerError(message_handlers::Error),
+
+    /// A reliable message of the provided type was not acked after
+    /// `CircuitConfig::send_attempts`, its `SendMessage` failed.
+    AckFailed(MessageType),
+
+    /// Nothing was received from the sim for the provided duration, which is
+    /// at least `CircuitConfig::dead_timeout`.
+    ///
+    /// The circuit stopped, pending messages failed with
+    /// `SendMessageError::RemoteDead`. It still has to be closed.
+    Dead(Duration),
+
+    /// The circuit was closed, this is the last event.
+    Closed,
+}
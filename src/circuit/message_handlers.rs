@@ -1,12 +1,15 @@
 use circuit::MessageSender;
 use failure::Fail;
 use futures_cpupool::CpuPool;
-use messages::{MessageInstance, MessageType};
+use messages::all::{CompletePingCheck, CompletePingCheck_PingID, StartPingCheck};
+use messages::template::{DynamicMessage, MessageTemplates};
+use messages::{MessageInstance, MessageType, TypedMessage};
 use std::collections::HashMap;
 use tokio_core::reactor;
 
 type FilterFn = Box<Fn(&MessageInstance) -> bool + Send>;
 type HandlerFn = Box<Fn(MessageInstance, &HandlerContext) -> Result<(), Error> + Send>;
+type FallbackFn = Box<Fn(DynamicMessage, &HandlerContext) + Send>;
 
 /// A message handler which handles all messages for which filter evaluates to
 /// true.
@@ -15,15 +18,29 @@ struct FilterHandler {
     handler: HandlerFn,
 }
 
+/// A handler for messages which are not known at compile time, decoded with
+/// templates loaded at runtime.
+struct FallbackHandler {
+    templates: MessageTemplates,
+    handler: FallbackFn,
+}
+
 pub struct HandlerContext<'a> {
     pub message_sender: MessageSender,
     pub cpupool: &'a CpuPool,
     pub reactor: reactor::Remote,
 }
 
+/// The message handlers of a circuit.
+///
+/// Each message is offered to the handlers registered for its type, then to
+/// the filter handlers whose filter accepts it, in the order they were
+/// registered. A handler consumes the message by returning `Ok`, or passes it
+/// on to the next one by returning `Error::pass`.
 pub struct Handlers {
-    type_handlers: HashMap<MessageType, HandlerFn>,
+    type_handlers: HashMap<MessageType, Vec<HandlerFn>>,
     filter_handlers: Vec<FilterHandler>,
+    fallback: Option<FallbackHandler>,
 }
 
 impl Handlers {
@@ -31,16 +48,48 @@ impl Handlers {
         Handlers {
             type_handlers: HashMap::new(),
             filter_handlers: Vec::new(),
+            fallback: None,
         }
     }
 
-    /// Register a handler for all messages of a specific message type.
+    /// Register a handler for all messages of type `M`, which is called with
+    /// the message struct itself.
+    ///
+    /// It is called after the handlers registered for the same type before.
+    pub fn register<M, F>(&mut self, handler: F)
+    where
+        M: TypedMessage,
+        F: Fn(M, &HandlerContext) -> Result<(), Error> + Send + 'static,
+    {
+        let handler = move |msg: MessageInstance, context: &HandlerContext| {
+            match M::from_instance(msg) {
+                Ok(msg) => handler(msg, context),
+                Err(msg) => unreachable!(
+                    "handler of {:?} called with {:?}, handlers are looked up by message type",
+                    M::TYPE,
+                    msg.message_type()
+                ),
+            }
+        };
+        self.register_type(M::TYPE, Box::new(handler));
+    }
+
+    /// Register a handler for all messages of a type, which is called with
+    /// the `MessageInstance`.
+    ///
+    /// It is called after the handlers registered for the same type before.
     pub fn register_type(&mut self, m_type: MessageType, handler: HandlerFn) {
-        self.type_handlers.insert(m_type, handler);
+        self.type_handlers
+            .entry(m_type)
+            .or_insert_with(Vec::new)
+            .push(handler);
     }
 
     /// Register a handler for all messages for which the filter evaluates to
     /// true.
+    ///
+    /// Filter handlers are only called for messages which were not consumed
+    /// by a handler of their type.
     pub fn register_filter(&mut self, filter: FilterFn, handler: HandlerFn) {
         self.filter_handlers.push(FilterHandler {
             filter: filter,
@@ -48,24 +97,58 @@ impl Handlers {
         });
     }
 
+    /// Register a handler for messages which have no definition compiled into
+    /// this crate, but one in the provided templates.
+    ///
+    /// Without a fallback such messages are dropped by the circuit. Replaces
+    /// a previously registered fallback.
+    pub fn register_fallback(&mut self, templates: MessageTemplates, handler: FallbackFn) {
+        self.fallback = Some(FallbackHandler {
+            templates: templates,
+            handler: handler,
+        });
+    }
+
+    /// The templates of the registered fallback handler, if any.
+    pub(crate) fn fallback_templates(&self) -> Option<&MessageTemplates> {
+        self.fallback.as_ref().map(|f| &f.templates)
+    }
+
+    pub(crate) fn handle_fallback(&self, msg: DynamicMessage, context: &HandlerContext) {
+        if let Some(ref f) = self.fallback {
+            (f.handler)(msg, context);
+        }
+    }
+
     pub(crate) fn handle(
         &self,
         msg: MessageInstance,
         context: &HandlerContext,
     ) -> Result<(), Error> {
-        if let Some(h) = self.type_handlers.get(&msg.message_type()) {
-            h(msg, context)
-        } else {
-            for fh in &self.filter_handlers {
-                if (fh.filter)(&msg) {
-                    return (fh.handler)(msg, context);
+        let mut msg = msg;
+        if let Some(chain) = self.type_handlers.get(&msg.message_type()) {
+            for handler in chain {
+                match handler(msg, context) {
+                    Err(Error {
+                        msg: passed,
+                        kind: ErrorKind::NoHandler,
+                    }) => msg = passed,
+                    result => return result,
                 }
             }
-            Err(Error {
-                msg: msg,
-                kind: ErrorKind::NoHandler,
-            })
         }
+        for fh in &self.filter_handlers {
+            if (fh.filter)(&msg) {
+                match (fh.handler)(msg, context) {
+                    Err(Error {
+                        msg: passed,
+                        kind: ErrorKind::NoHandler,
+                    }) => msg = passed,
+                    result => return result,
+                }
+            }
+        }
+        Err(Error::pass(msg))
     }
 }
 
@@ -75,37 +158,176 @@ pub struct Error {
     pub kind: ErrorKind,
 }
 
+impl Error {
+    /// Pass the message on to the next handler, if there is none it is
+    /// yielded to `Circuit::read`.
+    pub fn pass<M: Into<MessageInstance>>(msg: M) -> Self {
+        Error {
+            msg: msg.into(),
+            kind: ErrorKind::NoHandler,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum ErrorKind {
+    /// No handler consumed the message.
     NoHandler,
-    // TODO: Make impossible and remove the variant.
-    WrongHandler,
     Other(Box<Fail>),
 }
 
 impl Default for Handlers {
     fn default() -> Self {
         let mut handlers = Handlers::new();
-        handlers.register_type(MessageType::StartPingCheck, Box::new(handle_ping_check));
+        handlers.register(handle_ping_check);
         handlers
     }
 }
 
-fn handle_ping_check(msg: MessageInstance, context: &HandlerContext) -> Result<(), Error> {
-    use messages::all::{CompletePingCheck, CompletePingCheck_PingID};
-
-    let start_ping_check = match msg {
-        MessageInstance::StartPingCheck(m) => Ok(m),
-        _ => Err(Error {
-            msg: msg,
-            kind: ErrorKind::WrongHandler,
-        }),
-    }?;
+fn handle_ping_check(msg: StartPingCheck, context: &HandlerContext) -> Result<(), Error> {
     let response = CompletePingCheck {
         ping_id: CompletePingCheck_PingID {
-            ping_id: start_ping_check.ping_id.ping_id,
+            ping_id: msg.ping_id.ping_id,
         },
     };
     context.message_sender.send(response, false);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use circuit::ack_manager;
+    use circuit::CircuitConfig;
+    use messages::StartPingCheck_PingID;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio_core::reactor::Core;
+
+    #[derive(Debug, Fail)]
+    #[fail(display = "handler failed")]
+    struct HandlerFailed;
+
+    fn config() -> CircuitConfig {
+        CircuitConfig {
+            send_timeout: Duration::from_millis(5000),
+            send_attempts: 5,
+            ping_interval: Duration::from_secs(60),
+            throttles: None,
+            trusted: false,
+            silence_timeout: Duration::from_secs(10),
+            dead_timeout: None,
+            stats_interval: None,
+        }
+    }
+
+    fn ping() -> MessageInstance {
+        StartPingCheck {
+            ping_id: StartPingCheck_PingID {
+                ping_id: 1,
+                oldest_unacked: 0,
+            },
+        }
+        .into()
+    }
+
+    /// Offer the message to the handlers, whatever they send goes nowhere.
+    fn handle(handlers: &Handlers, msg: MessageInstance) -> Result<(), Error> {
+        let (ackmgr_tx, _ackmgr_rx) = ack_manager::new(config());
+        let cpupool = CpuPool::new(1);
+        let core = Core::new().unwrap();
+        let context = HandlerContext {
+            message_sender: MessageSender {
+                ackmgr_tx: ackmgr_tx,
+            },
+            cpupool: &cpupool,
+            reactor: core.remote(),
+        };
+        handlers.handle(msg, &context)
+    }
+
+    #[test]
+    fn type_handler_passes() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut handlers = Handlers::new();
+        let c = calls.clone();
+        handlers.register(move |msg: StartPingCheck, _: &HandlerContext| {
+            c.lock().unwrap().push("first");
+            Err(Error::pass(msg))
+        });
+        let c = calls.clone();
+        handlers.register(move |_: StartPingCheck, _: &HandlerContext| {
+            c.lock().unwrap().push("second");
+            Ok(())
+        });
+
+        assert!(handle(&handlers, ping()).is_ok());
+        assert_eq!(*calls.lock().unwrap(), vec!["first", "second"]);
+    }
+
+    #[test]
+    fn type_handler_passes_to_filter() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut handlers = Handlers::new();
+        let c = calls.clone();
+        handlers.register(move |msg: StartPingCheck, _: &HandlerContext| {
+            c.lock().unwrap().push("type");
+            Err(Error::pass(msg))
+        });
+        let c = calls.clone();
+        handlers.register_filter(
+            Box::new(|_: &MessageInstance| false),
+            Box::new(move |_: MessageInstance, _: &HandlerContext| {
+                c.lock().unwrap().push("rejected");
+                Ok(())
+            }),
+        );
+        let c = calls.clone();
+        handlers.register_filter(
+            Box::new(|msg: &MessageInstance| msg.message_type() == MessageType::StartPingCheck),
+            Box::new(move |_: MessageInstance, _: &HandlerContext| {
+                c.lock().unwrap().push("filter");
+                Ok(())
+            }),
+        );
+
+        assert!(handle(&handlers, ping()).is_ok());
+        assert_eq!(*calls.lock().unwrap(), vec!["type", "filter"]);
+    }
+
+    #[test]
+    fn handler_error_stops_chain() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut handlers = Handlers::new();
+        let c = calls.clone();
+        handlers.register(move |msg: StartPingCheck, _: &HandlerContext| {
+            c.lock().unwrap().push("failing");
+            Err(Error {
+                msg: msg.into(),
+                kind: ErrorKind::Other(Box::new(HandlerFailed)),
+            })
+        });
+        let c = calls.clone();
+        handlers.register(move |_: StartPingCheck, _: &HandlerContext| {
+            c.lock().unwrap().push("type");
+            Ok(())
+        });
+        let c = calls.clone();
+        handlers.register_filter(
+            Box::new(|_: &MessageInstance| true),
+            Box::new(move |_: MessageInstance, _: &HandlerContext| {
+                c.lock().unwrap().push("filter");
+                Ok(())
+            }),
+        );
+
+        match handle(&handlers, ping()) {
+            Err(Error {
+                kind: ErrorKind::Other(_),
+                ..
+            }) => {}
+            result => panic!("unexpected result: {:?}", result),
+        }
+        assert_eq!(*calls.lock().unwrap(), vec!["failing"]);
+    }
+}
@@ -5,24 +5,39 @@
 //! When a message is received, it will be attempted to find a handler for this
 //! message.
 //!
-//! First it will be checked if there is a handler specific to the
-//! `MessageType` of the message, if none is found, handlers with a filter will
-//! be queried for the message (TODO implement when needed), giving the message
-//! to the first handler found to accept it.
+//! First it is given to the handlers registered for the `MessageType` of the
+//! message, then to the handlers with a filter accepting it, each of them
+//! either consumes the message or passes it on, see `Handlers`.
 //!
-//! If no handler was found for a message, it will remain in the queue and can
+//! If no handler consumed a message, it will remain in the queue and can
 //! be received from the Circuit with the `read` and `try_read` functions.
 //!
+//! Independent of the handlers, copies of the messages of a type can be
+//! observed with `Circuit::subscribe`.
+//!
+//! Messages not known to this crate are dropped, unless a fallback handler
+//! with templates loaded at runtime was registered, see
+//! `Handlers::register_fallback`. Such messages are logged and given to the
+//! fallback handler.
+//!
+//! # Events
+//!
+//! Errors inside the threads of the circuit don't panic, they are reported
+//! as `CircuitEvent` together with changes of the state of the circuit, see
+//! `Circuit::events`.
+//!
+//! # Threads
+//!
+//! Every circuit reads from and writes to its socket on threads of its own,
+//! unless it was initiated on a `CircuitRuntime` shared by many circuits,
+//! see `Circuit::initiate_on`.
+//!
 //! # Backlog (TODO)
 //!
 //! - IPv6 support (blocked by OpenSim support)
 
 // TODO:
-// - Proper shutdown of circuit
-// → This should be accompanied by a systems module providing functionality
-// to send the correct messages to the sim to make sure the agent is
-// actually disconnected from the sim and doesn't end up failing the next
-// authentication. - Figure out max packet size and apply the value to our read
+// - Figure out max packet size and apply the value to our read
 // and write buffers. - Make sure acks are not sent twice?
 // - Do acks need to be sent with a reliable packet?
 // - Make sure the code is free from deadlock and starvation.
@@ -32,29 +47,56 @@
 // never fail.
 
 use logging::Log;
-use messages::MessageInstance;
-use packet::Packet;
+use messages::{MessageInstance, MessageType};
+use packet::{Packet, MTU};
 use simulator::ConnectInfo;
-use types::SequenceNumber;
-use util::FifoCache;
+use types::Uuid;
+use util::AtomicU32Counter;
 
+use crossbeam_channel::Receiver;
 use futures_cpupool::CpuPool;
 use std::error::Error;
 use std::fmt;
-use std::io::Error as IoError;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
 use std::net::{SocketAddr, SocketAddrV4, UdpSocket};
-use std::sync::mpsc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
 use std::thread;
 use std::time::Duration;
 use tokio_core::reactor;
 
 mod ack_manager;
-use self::ack_manager::AckManagerTx;
+use self::ack_manager::{AckManagerRx, AckManagerTx};
+
+mod events;
+pub use self::events::CircuitEvent;
+
+mod reader;
+use self::reader::{CircuitReader, RECEIVE_BUFFER_SIZE};
+
+mod receive_window;
+
+mod rtt;
+
+mod runtime;
+pub use self::runtime::CircuitRuntime;
+use self::runtime::RuntimeCircuit;
+
+mod stats;
+pub use self::stats::CircuitStats;
+use self::stats::StatsCounters;
 
 mod status;
 use self::status::SendMessageStatus;
 pub use self::status::{SendMessage, SendMessageError};
 
+mod subscriptions;
+pub use self::subscriptions::Subscription;
+use self::subscriptions::Subscriptions;
+
+mod throttle;
+pub use self::throttle::{ThrottleCategory, Throttles, THROTTLE_CATEGORIES};
+
 pub mod message_handlers;
 
 #[derive(Debug)]
@@ -99,6 +141,65 @@ impl From<mpsc::TryRecvError> for ReadMessageError {
     }
 }
 
+#[derive(Debug)]
+pub enum CloseError {
+    /// The sender or reader thread of the circuit panicked.
+    ThreadPanicked,
+}
+
+impl fmt::Display for CloseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.description())
+    }
+}
+
+impl Error for CloseError {
+    fn description(&self) -> &str {
+        match *self {
+            CloseError::ThreadPanicked => "A thread of the circuit panicked.",
+        }
+    }
+}
+
+/// How long the reader thread blocks on the socket before checking whether
+/// the circuit was closed, also the interval in which a `CircuitRuntime`
+/// checks its circuits for timeouts.
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Write a packet to the socket, counting it or reporting the error.
+///
+/// Returns `false` if the socket is non-blocking and its buffer is full,
+/// then the packet was not written and has to be written again later.
+fn write_packet(
+    socket: &UdpSocket,
+    packet: &Packet,
+    log: &Log,
+    ackmgr_tx: &AckManagerTx,
+    stats: &StatsCounters,
+) -> bool {
+    // The ack manager only hands out validated packets fitting into the MTU.
+    let mut buf = [0u8; MTU];
+    let len = packet.write_to_slice_unvalidated(&mut buf).unwrap();
+
+    let result = socket.send(&buf[..len]);
+    if let Err(ref e) = result {
+        if e.kind() == IoErrorKind::WouldBlock {
+            return false;
+        }
+    }
+    log.log_packet_send(&buf[..len], packet);
+    match result {
+        Ok(_) => stats.packet_sent(len),
+        Err(e) => {
+            let message = format!("Sending packet failed: {}", e);
+            if ackmgr_tx.socket_error(e) {
+                warn!(log.slog_logger(), "{}", message);
+            }
+        }
+    }
+    true
+}
+
 /// Interface for sending messages through the circuit.
 #[derive(Clone)]
 pub struct MessageSender {
@@ -112,17 +213,74 @@ impl MessageSender {
     }
 }
 
+#[cfg(test)]
+impl MessageSender {
+    /// A sender without a circuit, for tests which only need one to exist.
+    /// Messages sent through it fail with `SendMessageError::Closed`.
+    pub(crate) fn unconnected() -> Self {
+        let (ackmgr_tx, _) = self::ack_manager::new(CircuitConfig {
+            send_timeout: Duration::from_millis(5000),
+            send_attempts: 5,
+            ping_interval: Duration::from_secs(60),
+            throttles: None,
+            trusted: false,
+            silence_timeout: Duration::from_secs(10),
+            dead_timeout: None,
+            stats_interval: None,
+        });
+        MessageSender {
+            ackmgr_tx: ackmgr_tx,
+        }
+    }
+}
+
 /// Encapsulates a so called circuit (networking link) between our viewer and a
 /// simulator.
 ///
-/// TODO:
-/// - Stop/exit functionality.
+/// The circuit runs until `close` is called, dropping it leaves its threads
+/// running.
 pub struct Circuit {
     incoming: mpsc::Receiver<MessageInstance>,
     ackmgr_tx: AckManagerTx,
+    stats: Arc<StatsCounters>,
+    subscriptions: Subscriptions,
+
+    agent_id: Uuid,
+    session_id: Uuid,
+    circuit_code: u32,
+    /// Generation of the `AgentThrottle` messages, see `set_throttles`.
+    throttle_counter: AtomicU32Counter,
+
+    driver: Driver,
+}
+
+/// What reads and sends the packets of a circuit.
+enum Driver {
+    /// Threads of the circuit itself, see `Circuit::initiate`.
+    Threads {
+        stop_reader: Arc<AtomicBool>,
+        sender_thread: thread::JoinHandle<()>,
+        reader_thread: thread::JoinHandle<()>,
+    },
+    /// A runtime shared with other circuits, see `Circuit::initiate_on`.
+    Runtime(RuntimeCircuit),
+}
+
+/// The parts of a new circuit which have to be driven by a `Driver`.
+struct Parts {
+    reader: CircuitReader,
+    ackmgr_rx: AckManagerRx,
+    message_sender: MessageSender,
 }
 
 impl Circuit {
+    /// Initiate a circuit to the sim, driven by two threads of its own and a
+    /// pool of two more threads for the message handlers.
+    ///
+    /// The message handlers are called on the reader thread. A handler may
+    /// close its circuit, the reader thread stops once the handler returned.
+    ///
+    /// For many circuits in one process consider `initiate_on` instead.
     pub fn initiate(
         connect_info: &ConnectInfo,
         config: CircuitConfig,
@@ -130,53 +288,35 @@ impl Circuit {
         reactor_remote: reactor::Remote,
         log: Log,
     ) -> Result<Circuit, IoError> {
-        let sim_address = SocketAddr::V4(SocketAddrV4::new(
-            connect_info.sim_ip,
-            connect_info.sim_port,
-        ));
-
-        // Queue for incoming messages.
-        let (incoming_tx, incoming_rx) = mpsc::channel::<MessageInstance>();
-
         // Create sockets.
-        let socket_out = UdpSocket::bind("0.0.0.0:0")?;
-        socket_out.connect(sim_address)?;
-        socket_out.set_read_timeout(None)?;
+        let socket_out = Self::connect_socket(connect_info)?;
+        socket_out.set_read_timeout(Some(READ_TIMEOUT))?;
         socket_out.set_nonblocking(false)?;
         let socket_in = socket_out.try_clone()?;
 
-        // Setup AckManager.
-        let (ackmgr_tx, mut ackmgr_rx) = self::ack_manager::new(config);
-        let ackmgr_tx_1 = ackmgr_tx;
-        let ackmgr_tx_2 = ackmgr_tx_1.clone();
-        let message_sender = MessageSender {
-            ackmgr_tx: ackmgr_tx_1.clone(),
-        };
+        let (parts, circuit) = Self::setup(connect_info, config, msg_handlers, log.clone());
+        let Parts {
+            mut reader,
+            mut ackmgr_rx,
+            message_sender,
+        } = parts;
+        let ackmgr_tx = circuit.ackmgr_tx.clone();
+        let stats = circuit.stats.clone();
+
+        let stop_reader = Arc::new(AtomicBool::new(false));
+        let stop_reader_1 = stop_reader.clone();
 
         // Create sender thread (1).
-        let log1 = log.clone();
-        thread::spawn(move || {
-            // TODO: proper shutdown mechanism
-            loop {
-                let packet = ackmgr_rx.fetch();
-                let mut buf = Vec::<u8>::new();
-                packet.write_to(&mut buf).unwrap();
-                log1.log_packet_send(&buf, &packet);
-
-                socket_out.send(&buf).unwrap();
+        let sender_thread = thread::spawn(move || {
+            // Runs until the circuit is closed and all queued packets are sent.
+            // The socket is blocking, if its buffer is full this waits.
+            while let Some(packet) = ackmgr_rx.fetch() {
+                write_packet(&socket_out, &packet, &log, &ackmgr_tx, &stats);
             }
         });
 
         // Create reader thread (2).
-        thread::spawn(move || {
-            // TODO: Determine good maximum size. If it's too be big we are wasting memory,
-            // if it's too small things will explode.
-            //
-            // → At first I wanted to make this dynamic but this turned out to not be
-            // possible, and maybe it would have been really inefficient. A
-            // workaround could be to use our own struct directly reading
-            // from a Read and using a larger array as needed?
-            let mut packet_log = FifoCache::<SequenceNumber>::new(10000);
+        let reader_thread = thread::spawn(move || {
             let cpupool = CpuPool::new(2);
             let handler_context = message_handlers::HandlerContext {
                 message_sender: message_sender,
@@ -184,69 +324,163 @@ impl Circuit {
                 reactor: reactor_remote,
             };
 
-            loop {
-                // TODO: move back up after debugging
-                let mut buf = [0u8; 4096];
-                // Read from socket in blocking way.
-                let (buf_size, _) = socket_in.recv_from(&mut buf).unwrap();
-
-                // Parse the packet.
-                let packet_res = Packet::read(&buf[..buf_size]);
-                log.log_packet_recv(&buf[..buf_size], &packet_res);
-                let packet = match packet_res {
-                    Ok(pkt) => pkt,
-                    Err(_) => continue,
-                };
-
-                // Read appended acks and send ack if requested (reliable packet).
-                {
-                    for ack in packet.appended_acks.iter() {
-                        ackmgr_tx_1.register_ack(*ack).unwrap();
-                    }
-                }
-                if packet.is_reliable() {
+            let mut buf = [0u8; RECEIVE_BUFFER_SIZE];
+            while !stop_reader_1.load(Ordering::SeqCst) {
+                // Read from socket in blocking way, waking up regularly to
+                // check whether the circuit was closed.
+                match socket_in.recv_from(&mut buf) {
+                    Ok((size, address)) => reader.datagram(&buf[..size], address, &handler_context),
+                    Err(ref e)
+                        if e.kind() == IoErrorKind::WouldBlock
+                            || e.kind() == IoErrorKind::TimedOut =>
                     {
-                        ackmgr_tx_1.send_ack(packet.sequence_number).unwrap();
-                    }
-
-                    // Check if we did receive the packet already and the remote just resent it
-                    // again anyway.
-                    let duplicate = packet_log.contains(&packet.sequence_number);
-                    packet_log.insert(packet.sequence_number);
-                    if duplicate {
-                        continue;
-                    }
-                }
-
-                match packet.message {
-                    MessageInstance::PacketAck(msg) => {
-                        // Pass the acks to the ack manager (and don't yield the packet).
-                        for packet_ack in msg.packets {
-                            ackmgr_tx_1.register_ack(packet_ack.id).unwrap();
+                        if !reader.check_silence() {
+                            break;
                         }
                     }
-                    msg => {
-                        let _ = msg_handlers.handle(msg, &handler_context).map_err(|err| {
-                            match err.kind {
-                                message_handlers::ErrorKind::NoHandler => {
-                                    // Yield the message to the incoming message channel.
-                                    incoming_tx.send(err.msg).unwrap();
-                                }
-                                _ => {
-                                    // TODO: handle the error somewhere
-                                    panic!("unhandled error: {:?}", err);
-                                }
-                            }
-                        });
-                    }
+                    Err(e) => reader.socket_error(e),
                 }
             }
         });
 
-        Ok(Circuit {
+        Ok(circuit.with_driver(Driver::Threads {
+            stop_reader: stop_reader,
+            sender_thread: sender_thread,
+            reader_thread: reader_thread,
+        }))
+    }
+
+    /// Initiate a circuit to the sim, driven by a runtime shared with other
+    /// circuits.
+    ///
+    /// The message handlers are called on the pool of the runtime, one
+    /// message after the other in the order they arrived, while the messages
+    /// of other circuits are handled in parallel. The pool is shared by all
+    /// circuits of the runtime, a handler blocking one of its threads for
+    /// long delays the other circuits too. A handler may close its circuit,
+    /// the circuit stops reading once the handler returned.
+    pub fn initiate_on(
+        runtime: &CircuitRuntime,
+        connect_info: &ConnectInfo,
+        config: CircuitConfig,
+        msg_handlers: message_handlers::Handlers,
+        log: Log,
+    ) -> Result<Circuit, IoError> {
+        let socket = Self::connect_socket(connect_info)?;
+        let (parts, circuit) = Self::setup(connect_info, config, msg_handlers, log.clone());
+        let driver = runtime.add(
+            socket,
+            parts.reader,
+            parts.ackmgr_rx,
+            parts.message_sender,
+            circuit.ackmgr_tx.clone(),
+            log,
+        )?;
+        Ok(circuit.with_driver(Driver::Runtime(driver)))
+    }
+
+    fn connect_socket(connect_info: &ConnectInfo) -> Result<UdpSocket, IoError> {
+        let sim_address = SocketAddr::V4(SocketAddrV4::new(
+            connect_info.sim_ip,
+            connect_info.sim_port,
+        ));
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(sim_address)?;
+        Ok(socket)
+    }
+
+    /// Set up the state of a new circuit, which is not driven yet.
+    fn setup(
+        connect_info: &ConnectInfo,
+        config: CircuitConfig,
+        msg_handlers: message_handlers::Handlers,
+        log: Log,
+    ) -> (Parts, CircuitBuilder) {
+        let sim_address = SocketAddr::V4(SocketAddrV4::new(
+            connect_info.sim_ip,
+            connect_info.sim_port,
+        ));
+
+        // Queue for incoming messages.
+        let (incoming_tx, incoming_rx) = mpsc::channel::<MessageInstance>();
+
+        // Setup AckManager.
+        let (ackmgr_tx, ackmgr_rx) = self::ack_manager::new(config.clone());
+        let message_sender = MessageSender {
+            ackmgr_tx: ackmgr_tx.clone(),
+        };
+        let subscriptions = Subscriptions::new();
+        let reader = CircuitReader::new(
+            sim_address,
+            &config,
+            ackmgr_tx.clone(),
+            msg_handlers,
+            incoming_tx,
+            subscriptions.clone(),
+            log,
+        );
+
+        let parts = Parts {
+            reader: reader,
+            ackmgr_rx: ackmgr_rx,
+            message_sender: message_sender,
+        };
+        let circuit = CircuitBuilder {
             incoming: incoming_rx,
-            ackmgr_tx: ackmgr_tx_2,
-        })
+            stats: ackmgr_tx.stats_counters(),
+            ackmgr_tx: ackmgr_tx,
+            subscriptions: subscriptions,
+            agent_id: connect_info.agent_id,
+            session_id: connect_info.session_id,
+            circuit_code: connect_info.circuit_code,
+        };
+        (parts, circuit)
+    }
+
+    /// Close the circuit and wait for its threads to finish.
+    ///
+    /// First the reader thread is stopped, then all messages and acks queued
+    /// so far are sent. Messages still waiting for an ack fail with
+    /// `SendMessageError::Closed`, as do messages sent afterwards through a
+    /// `MessageSender` of this circuit.
+    ///
+    /// This doesn't tell the sim anything, use `Simulator::logout` to end the
+    /// session of the agent. Finally `CircuitEvent::Closed` is sent.
+    pub fn close(self) -> Result<(), CloseError> {
+        let ackmgr_tx = self.ackmgr_tx;
+        let (reader, sender) = match self.driver {
+            Driver::Threads {
+                stop_reader,
+                sender_thread,
+                reader_thread,
+            } => {
+                stop_reader.store(true, Ordering::SeqCst);
+                // Called by a message handler the reader thread stops once the
+                // handler returned, it can't wait for itself.
+                let reader = if thread::current().id() == reader_thread.thread().id() {
+                    true
+                } else {
+                    reader_thread.join().is_ok()
+                };
+
+                // Only now that no more acks are queued by the reader thread the
+                // remaining ones can be flushed.
+                ackmgr_tx.close();
+                (reader, sender_thread.join().is_ok())
+            }
+            Driver::Runtime(driver) => {
+                let reader = driver.stop_reader();
+                ackmgr_tx.close();
+                (reader, driver.join_sender())
+            }
+        };
+        ackmgr_tx.emit(CircuitEvent::Closed);
+
+        if reader && sender {
+            Ok(())
+        } else {
+            Err(CloseError::ThreadPanicked)
+        }
     }
 
     pub fn message_sender(&self) -> MessageSender {
@@ -262,6 +496,18 @@ impl Circuit {
     /// necessarily poll it for progress to be made. It will be handed over
     /// to the sender threads of this Circuit and you will be able to confirm
     /// it has finished successfully or failed by polling the returned future.
+    ///
+    /// Messages which are deprecated or blacklisted by the message template
+    /// are not sent, the returned future fails right away. The same happens
+    /// for messages violating the limits of their template, e.g. with
+    /// variable fields which are too long, with
+    /// `SendMessageError::Invalid`.
+    ///
+    /// Messages which don't fit into a packet of `packet::MTU` bytes are
+    /// split into multiple packets if they have exactly one variable block,
+    /// see `MessageInstance::split`, the future completes once all parts were
+    /// sent. Other messages which are too large fail with
+    /// `SendMessageError::TooLarge` instead of being sent.
     pub fn send<M: Into<MessageInstance>>(&self, msg: M, reliable: bool) -> SendMessage {
         self.ackmgr_tx.send_msg(msg.into(), reliable)
     }
@@ -272,29 +518,241 @@ impl Circuit {
     /// until there is one available, or if there is a timeout specified it will
     /// wait at most for the specified duration before returning an error.
     pub fn read(&self, timeout: Option<Duration>) -> Result<MessageInstance, ReadMessageError> {
-        match timeout {
-            Some(t) => Ok(self.incoming.recv_timeout(t)?),
-            None => Ok(self.incoming.recv()?),
-        }
+        let msg = match timeout {
+            Some(t) => self.incoming.recv_timeout(t)?,
+            None => self.incoming.recv()?,
+        };
+        self.stats.incoming_read();
+        Ok(msg)
+    }
+
+    /// The smoothed round-trip time to the sim, `None` until it was measured.
+    ///
+    /// It is measured from the acks of reliable packets and the replies to the
+    /// `StartPingCheck` messages sent while the circuit is idle, see
+    /// `CircuitConfig::ping_interval`.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.ackmgr_tx.rtt()
+    }
+
+    /// Change the bandwidth budgets of the circuit.
+    ///
+    /// Our outgoing packets are scheduled according to the budgets right
+    /// away, and the sim is told to respect them with an `AgentThrottle`
+    /// message, whose future is returned. Circuits with budgets in their
+    /// `CircuitConfig` still have to call this once after connecting, as the
+    /// sim only accepts `AgentThrottle` from agents which completed the
+    /// movement into the region.
+    pub fn set_throttles(&self, throttles: Throttles) -> SendMessage {
+        self.ackmgr_tx.set_throttles(Some(&throttles));
+        let message = throttles.to_message(
+            self.agent_id,
+            self.session_id,
+            self.circuit_code,
+            self.throttle_counter.next(),
+        );
+        self.send(message, true)
     }
 
     /// Trys reading a message and returns it if one is available right away.
     ///
     /// Otherwise this won't block the current thread and None will be returned.
     pub fn try_read(&self) -> Result<MessageInstance, ReadMessageError> {
-        Ok(self.incoming.try_recv()?)
+        let msg = self.incoming.try_recv()?;
+        self.stats.incoming_read();
+        Ok(msg)
+    }
+
+    /// Observe the received messages of a type.
+    ///
+    /// The returned stream yields a copy of every such message before it is
+    /// given to the message handlers, so it can be used without taking the
+    /// messages away from the services. `PacketAck` and `CompletePingCheck`
+    /// are consumed by the circuit itself and never show up.
+    ///
+    /// Up to 256 messages are kept until they are read, newer ones are
+    /// dropped so a slow subscriber never holds up the circuit.
+    pub fn subscribe(&self, m_type: MessageType) -> Subscription {
+        self.subscriptions.subscribe(m_type)
+    }
+
+    /// The events of the circuit, like errors in its threads or the sim not
+    /// responding anymore.
+    ///
+    /// Every event is received only once, even if this was called multiple
+    /// times. Up to 256 events are kept until they are read, newer ones are
+    /// dropped. Socket errors are reported at most once per second.
+    pub fn events(&self) -> Receiver<CircuitEvent> {
+        self.ackmgr_tx.events()
+    }
+
+    /// A snapshot of the packet counters, queue depths and round-trip time
+    /// of the circuit.
+    pub fn stats(&self) -> CircuitStats {
+        self.ackmgr_tx.stats()
+    }
+
+    /// Snapshots of the stats sent every `CircuitConfig::stats_interval`,
+    /// nothing is ever received if it is not set.
+    ///
+    /// Up to 64 snapshots are kept until they are read, newer ones are
+    /// dropped.
+    pub fn stats_events(&self) -> Receiver<CircuitStats> {
+        self.ackmgr_tx.stats_events()
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct CircuitConfig {
-    /// The number of seconds before an unconfirmed packet becomes invalid.
-    /// If multiple attempts are allowed, each single attempt will get at most
-    /// this time before
-    /// timing out.
+    /// The maximum time a single attempt to send a reliable packet waits for
+    /// its ack before timing out.
+    ///
+    /// Once the round-trip time was measured the timeout is derived from it
+    /// instead, doubling with every attempt, but it never exceeds this value.
     pub send_timeout: Duration,
 
     /// The number of times resending an unacknowledged packet before reporting
     /// it as failure.
     pub send_attempts: usize,
+
+    /// The interval in which `StartPingCheck` messages are sent while nothing
+    /// is received from the sim, to keep the circuit alive and measure the
+    /// round-trip time.
+    pub ping_interval: Duration,
+
+    /// Bandwidth budgets for our outgoing packets, `None` to send them as fast
+    /// as possible. See `Circuit::set_throttles` for telling the sim.
+    pub throttles: Option<Throttles>,
+
+    /// The time without any packet from the sim after which
+    /// `CircuitEvent::RemoteSilent` is sent.
+    pub silence_timeout: Duration,
+
+    /// Whether the remote is a trusted peer, allowed to send messages the
+    /// template marks as trusted. Only sims are trusted peers of each other,
+    /// a viewer's circuit is not trusted and drops such messages.
+    pub trusted: bool,
+
+    /// The time without any packet from the sim after which the circuit is
+    /// declared dead, see `CircuitEvent::Dead`. `None` to never give up.
+    pub dead_timeout: Option<Duration>,
+
+    /// The interval in which snapshots of the stats are sent to
+    /// `Circuit::stats_events`, `None` to not send any.
+    pub stats_interval: Option<Duration>,
+}
+
+/// A circuit whose driver was not created yet, see `Circuit::setup`.
+struct CircuitBuilder {
+    incoming: mpsc::Receiver<MessageInstance>,
+    ackmgr_tx: AckManagerTx,
+    stats: Arc<StatsCounters>,
+    subscriptions: Subscriptions,
+    agent_id: Uuid,
+    session_id: Uuid,
+    circuit_code: u32,
+}
+
+impl CircuitBuilder {
+    fn with_driver(self, driver: Driver) -> Circuit {
+        Circuit {
+            incoming: self.incoming,
+            ackmgr_tx: self.ackmgr_tx,
+            stats: self.stats,
+            subscriptions: self.subscriptions,
+            agent_id: self.agent_id,
+            session_id: self.session_id,
+            circuit_code: self.circuit_code,
+            throttle_counter: AtomicU32Counter::new(0),
+            driver: driver,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use logging::LogLevel;
+    use messages::{
+        StartPingCheck, StartPingCheck_PingID, UseCircuitCode, UseCircuitCode_CircuitCode,
+    };
+    use types::Ip4Addr;
+
+    use crossbeam_channel;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::sync::Mutex;
+    use url::Url;
+
+    fn config() -> CircuitConfig {
+        CircuitConfig {
+            send_timeout: Duration::from_millis(5000),
+            send_attempts: 5,
+            ping_interval: Duration::from_secs(60),
+            throttles: None,
+            trusted: false,
+            silence_timeout: Duration::from_secs(10),
+            dead_timeout: None,
+            stats_interval: None,
+        }
+    }
+
+    #[test]
+    fn close_from_handler() {
+        let sim = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sim.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let connect_info = ConnectInfo {
+            capabilities_seed: Url::parse("http://127.0.0.1/").unwrap(),
+            agent_id: Uuid::nil(),
+            session_id: Uuid::nil(),
+            circuit_code: 0,
+            sim_ip: Ip4Addr::new(127, 0, 0, 1),
+            sim_port: sim.local_addr().unwrap().port(),
+        };
+        let dir = env::temp_dir().join(format!("opensim-circuit-close-{}", process::id()));
+        let log = Log::new_dir(dir.clone(), LogLevel::Events).unwrap();
+
+        // The handler closes the circuit on the reader thread.
+        let slot: Arc<Mutex<Option<Circuit>>> = Arc::new(Mutex::new(None));
+        let (closed_tx, closed_rx) = crossbeam_channel::bounded(1);
+        let mut handlers = message_handlers::Handlers::new();
+        let handler_slot = slot.clone();
+        handlers.register(move |_: StartPingCheck, _: &message_handlers::HandlerContext| {
+            if let Some(circuit) = handler_slot.lock().unwrap().take() {
+                let _ = closed_tx.send(circuit.close().is_ok());
+            }
+            Ok(())
+        });
+
+        let core = reactor::Core::new().unwrap();
+        let circuit =
+            Circuit::initiate(&connect_info, config(), handlers, core.remote(), log.clone())
+                .unwrap();
+        let message = UseCircuitCode {
+            circuit_code: UseCircuitCode_CircuitCode {
+                code: 0,
+                session_id: Uuid::nil(),
+                id: Uuid::nil(),
+            },
+        };
+        circuit.send(message, false);
+        *slot.lock().unwrap() = Some(circuit);
+        let mut buf = [0u8; RECEIVE_BUFFER_SIZE];
+        let (_, address) = sim.recv_from(&mut buf).unwrap();
+
+        let ping = StartPingCheck {
+            ping_id: StartPingCheck_PingID {
+                ping_id: 1,
+                oldest_unacked: 0,
+            },
+        };
+        let mut packet = Vec::new();
+        Packet::new(ping, 1).write_to_vec(&mut packet).unwrap();
+        sim.send_to(&packet, address).unwrap();
+        assert_eq!(closed_rx.recv_timeout(Duration::from_secs(5)), Ok(true));
+
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
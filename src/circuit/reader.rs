@@ -0,0 +1,520 @@
+//! Processing of the datagrams received from the sim, independent of the
+//! thread or runtime reading them from the socket.
+
+use circuit::ack_manager::AckManagerTx;
+use circuit::message_handlers::{self, HandlerContext};
+use circuit::receive_window::{Arrival, ReceiveWindow};
+use circuit::stats::StatsCounters;
+use circuit::subscriptions::Subscriptions;
+use circuit::{CircuitConfig, CircuitEvent};
+use logging::{Log, Logger};
+use messages::{MessageInstance, ReadError};
+use packet::{DynamicPacket, Packet, PacketHeader, ReadPacketError};
+use types::SequenceNumber;
+
+use std::io::Error as IoError;
+use std::net::SocketAddr;
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+
+/// The size of the buffers datagrams are received into.
+// TODO: Determine good maximum size. If it's too be big we are wasting memory,
+// if it's too small things will explode.
+//
+// → At first I wanted to make this dynamic but this turned out to not be
+// possible, and maybe it would have been really inefficient. A
+// workaround could be to use our own struct directly reading
+// from a Read and using a larger array as needed?
+pub const RECEIVE_BUFFER_SIZE: usize = 4096;
+
+/// How far the sequence number of a packet may be ahead of the highest one
+/// so far before it is flagged, this is more than a sim sends in minutes.
+const MAX_SEQUENCE_JUMP: SequenceNumber = 100_000;
+
+/// The receiving half of a circuit.
+///
+/// It is fed with the datagrams read from the socket and regularly asked to
+/// check for timeouts while the socket is idle.
+pub struct CircuitReader {
+    sim_address: SocketAddr,
+    trusted: bool,
+    silence_timeout: Duration,
+    dead_timeout: Option<Duration>,
+
+    ackmgr_tx: AckManagerTx,
+    msg_handlers: message_handlers::Handlers,
+    incoming: mpsc::Sender<MessageInstance>,
+    subscriptions: Subscriptions,
+    stats: Arc<StatsCounters>,
+    log: Log,
+    logger: Logger,
+
+    receive_window: ReceiveWindow,
+    highest_sequence: Option<SequenceNumber>,
+    connected: bool,
+    last_received: Instant,
+    silent: bool,
+}
+
+impl CircuitReader {
+    pub fn new(
+        sim_address: SocketAddr,
+        config: &CircuitConfig,
+        ackmgr_tx: AckManagerTx,
+        msg_handlers: message_handlers::Handlers,
+        incoming: mpsc::Sender<MessageInstance>,
+        subscriptions: Subscriptions,
+        log: Log,
+    ) -> Self {
+        CircuitReader {
+            sim_address: sim_address,
+            trusted: config.trusted,
+            silence_timeout: config.silence_timeout,
+            dead_timeout: config.dead_timeout,
+            stats: ackmgr_tx.stats_counters(),
+            ackmgr_tx: ackmgr_tx,
+            msg_handlers: msg_handlers,
+            incoming: incoming,
+            subscriptions: subscriptions,
+            logger: log.slog_logger(),
+            log: log,
+            receive_window: ReceiveWindow::new(),
+            highest_sequence: None,
+            connected: false,
+            last_received: Instant::now(),
+            silent: false,
+        }
+    }
+
+    /// Check whether the sim was silent for too long, to be called whenever
+    /// reading from the socket timed out.
+    ///
+    /// Returns `false` once the circuit is dead, then nothing is to be read
+    /// anymore.
+    pub fn check_silence(&mut self) -> bool {
+        let elapsed = self.last_received.elapsed();
+        if !self.silent && elapsed >= self.silence_timeout {
+            self.silent = true;
+            self.ackmgr_tx.emit(CircuitEvent::RemoteSilent(elapsed));
+        }
+        match self.dead_timeout {
+            Some(timeout) if elapsed >= timeout => {
+                warn!(self.logger, "Sim did not respond for {:?}.", elapsed);
+                self.ackmgr_tx.declare_dead();
+                self.ackmgr_tx.emit(CircuitEvent::Dead(elapsed));
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Reading from the socket failed.
+    pub fn socket_error(&mut self, error: IoError) {
+        let message = format!("Receiving packet failed: {}", error);
+        if self.ackmgr_tx.socket_error(error) {
+            warn!(self.logger, "{}", message);
+        }
+    }
+
+    /// Process a datagram received from `address`.
+    pub fn datagram(&mut self, buf: &[u8], address: SocketAddr, context: &HandlerContext) {
+        if address != self.sim_address {
+            debug!(self.logger, "Dropped packet from foreign address: {}", address);
+            self.stats.foreign_packet();
+            return;
+        }
+        self.stats.packet_received(buf.len());
+
+        self.last_received = Instant::now();
+        self.ackmgr_tx.register_received();
+        self.silent = false;
+        if !self.connected {
+            self.connected = true;
+            self.ackmgr_tx.emit(CircuitEvent::Connected);
+        }
+
+        // Parse the packet.
+        let packet_res = Packet::read(buf);
+        self.log.log_packet_recv(buf, &packet_res);
+        let packet = match packet_res {
+            Ok(pkt) => pkt,
+            Err(ReadPacketError::ReadError(ReadError::UnknownMessageNumber(_))) => {
+                self.unknown_message(buf, context);
+                return;
+            }
+            Err(_) => {
+                self.stats.decode_error();
+                return;
+            }
+        };
+
+        // Only trusted peers may send messages the template marks as
+        // trusted, don't even process the acks of such packets.
+        if packet.message.message_type().meta().trusted && !self.trusted {
+            warn!(
+                self.logger,
+                "Dropped trusted message on untrusted circuit: {:?}",
+                packet.message.message_type()
+            );
+            self.stats.untrusted_message();
+            return;
+        }
+        if self.register_packet(
+            packet.sequence_number,
+            packet.is_reliable(),
+            &packet.appended_acks,
+        ) {
+            return;
+        }
+
+        match packet.message {
+            MessageInstance::PacketAck(msg) => {
+                // Pass the acks to the ack manager (and don't yield the packet).
+                for packet_ack in msg.packets {
+                    let _ = self.ackmgr_tx.register_ack(packet_ack.id);
+                }
+            }
+            MessageInstance::CompletePingCheck(msg) => {
+                // Reply to one of our pings, used to measure the RTT.
+                let _ = self.ackmgr_tx.register_ping(msg.ping_id.ping_id);
+            }
+            msg => {
+                self.subscriptions.publish(&msg);
+                let err = match self.msg_handlers.handle(msg, context) {
+                    Ok(()) => return,
+                    Err(err) => err,
+                };
+                match err.kind {
+                    message_handlers::ErrorKind::NoHandler => {
+                        // Yield the message to the incoming message channel,
+                        // unless the circuit was dropped.
+                        self.stats.incoming_queued();
+                        if self.incoming.send(err.msg).is_err() {
+                            self.stats.incoming_read();
+                        }
+                    }
+                    _ => {
+                        warn!(self.logger, "Message handler failed: {:?}", err);
+                        self.ackmgr_tx.emit(CircuitEvent::HandlerError(err));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Process a packet whose message is not known to this crate.
+    ///
+    /// Its header is still valid, so a reliable packet is acked even if the
+    /// message can't be decoded with the fallback templates either, otherwise
+    /// the sim would keep resending it.
+    fn unknown_message(&mut self, buf: &[u8], context: &HandlerContext) {
+        let header = match PacketHeader::read(buf) {
+            Ok(header) => header,
+            Err(_) => {
+                self.stats.decode_error();
+                return;
+            }
+        };
+        if self.register_packet(
+            header.sequence_number,
+            header.is_reliable(),
+            &header.appended_acks,
+        ) {
+            return;
+        }
+
+        let packet = match self.msg_handlers.fallback_templates() {
+            Some(templates) => DynamicPacket::read(buf, templates),
+            None => {
+                self.stats.decode_error();
+                return;
+            }
+        };
+        match packet {
+            Ok(packet) => {
+                info!(self.logger, "Received message without definition:\n{}", packet.message);
+                self.msg_handlers.handle_fallback(packet.message, context);
+            }
+            Err(_) => self.stats.decode_error(),
+        }
+    }
+
+    /// Check the sequence number of a received packet, register its appended
+    /// acks and queue its own ack if it is reliable.
+    ///
+    /// Returns `true` if it is a duplicate which should be dropped.
+    fn register_packet(
+        &mut self,
+        seq: SequenceNumber,
+        reliable: bool,
+        appended_acks: &[SequenceNumber],
+    ) -> bool {
+        if !check_sequence(&mut self.highest_sequence, seq) {
+            warn!(self.logger, "Sequence number jumped to {}.", seq);
+            self.stats.sequence_jump();
+        }
+
+        // Registering acks only fails once the sender thread exited, then
+        // there is nobody to send them anyway.
+        for ack in appended_acks {
+            let _ = self.ackmgr_tx.register_ack(*ack);
+        }
+        if reliable {
+            let _ = self.ackmgr_tx.send_ack(seq);
+        }
+
+        // Check if we did receive the packet already and the remote just resent it
+        // again anyway, e.g. because our ack got lost.
+        register_arrival(&mut self.receive_window, &self.stats, seq)
+    }
+}
+
+/// Check the sequence number of a received packet against the highest one
+/// so far, which is updated unless the packet is implausibly far ahead.
+///
+/// Returns `false` for such a jump. Sequence numbers wrap around, anything
+/// within half of the range behind the highest one is considered older.
+fn check_sequence(highest: &mut Option<SequenceNumber>, seq: SequenceNumber) -> bool {
+    let prev = match *highest {
+        Some(prev) => prev,
+        None => {
+            *highest = Some(seq);
+            return true;
+        }
+    };
+    let ahead = seq.wrapping_sub(prev);
+    if ahead > MAX_SEQUENCE_JUMP && ahead < SequenceNumber::max_value() / 2 {
+        false
+    } else {
+        if ahead < SequenceNumber::max_value() / 2 {
+            *highest = Some(seq);
+        }
+        true
+    }
+}
+
+/// Register a received packet with the window and count how it arrived.
+///
+/// Returns `true` if it is a duplicate which should be dropped.
+fn register_arrival(
+    window: &mut ReceiveWindow,
+    stats: &StatsCounters,
+    seq: SequenceNumber,
+) -> bool {
+    match window.insert(seq) {
+        Arrival::InOrder { skipped } => stats.skipped(skipped as usize),
+        Arrival::Reordered => stats.reordered(),
+        Arrival::Duplicate => {
+            stats.duplicate();
+            return true;
+        }
+        // Most likely a resent packet whose ack got lost, but without knowing
+        // better it is treated as new like before the window moved on.
+        Arrival::Stale => {}
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use circuit::ack_manager::{self, AckManagerRx, Fetch};
+    use circuit::{CircuitStats, MessageSender};
+    use futures_cpupool::CpuPool;
+    use logging::LogLevel;
+    use messages::{
+        ActivateGroup, ActivateGroup_AgentData, AddCircuitCode, AddCircuitCode_CircuitCode,
+    };
+    use types::Uuid;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+    use tokio_core::reactor::Core;
+
+    fn config() -> CircuitConfig {
+        CircuitConfig {
+            send_timeout: Duration::from_millis(5000),
+            send_attempts: 5,
+            ping_interval: Duration::from_secs(60),
+            throttles: None,
+            trusted: false,
+            silence_timeout: Duration::from_secs(10),
+            dead_timeout: None,
+            stats_interval: None,
+        }
+    }
+
+    fn sim_address() -> SocketAddr {
+        "127.0.0.1:9000".parse().unwrap()
+    }
+
+    /// A reader for a circuit to `sim_address`, fed directly with datagrams.
+    struct TestReader {
+        reader: CircuitReader,
+        ackmgr_tx: AckManagerTx,
+        ackmgr_rx: AckManagerRx,
+        incoming: mpsc::Receiver<MessageInstance>,
+        cpupool: CpuPool,
+        core: Core,
+        log_dir: PathBuf,
+    }
+
+    impl TestReader {
+        fn new(name: &str, config: CircuitConfig) -> Self {
+            let log_dir =
+                env::temp_dir().join(format!("opensim-reader-{}-{}", name, process::id()));
+            let log = Log::new_dir(log_dir.clone(), LogLevel::Events).unwrap();
+            let (ackmgr_tx, ackmgr_rx) = ack_manager::new(config.clone());
+            let (incoming_tx, incoming_rx) = mpsc::channel();
+            let reader = CircuitReader::new(
+                sim_address(),
+                &config,
+                ackmgr_tx.clone(),
+                message_handlers::Handlers::new(),
+                incoming_tx,
+                Subscriptions::new(),
+                log,
+            );
+            TestReader {
+                reader: reader,
+                ackmgr_tx: ackmgr_tx,
+                ackmgr_rx: ackmgr_rx,
+                incoming: incoming_rx,
+                cpupool: CpuPool::new(1),
+                core: Core::new().unwrap(),
+                log_dir: log_dir,
+            }
+        }
+
+        fn datagram(&mut self, buf: &[u8], address: SocketAddr) {
+            let context = HandlerContext {
+                message_sender: MessageSender {
+                    ackmgr_tx: self.ackmgr_tx.clone(),
+                },
+                cpupool: &self.cpupool,
+                reactor: self.core.remote(),
+            };
+            self.reader.datagram(buf, address, &context);
+        }
+
+        fn stats(&self) -> CircuitStats {
+            self.ackmgr_tx.stats()
+        }
+
+        /// The acks queued to be sent to the sim so far.
+        fn sent_acks(&mut self) -> Vec<SequenceNumber> {
+            match self.ackmgr_rx.poll_fetch() {
+                Fetch::Packet(packet) => match packet.message {
+                    MessageInstance::PacketAck(msg) => msg.packets.iter().map(|p| p.id).collect(),
+                    msg => panic!("unexpected message: {:?}", msg),
+                },
+                _ => Vec::new(),
+            }
+        }
+    }
+
+    impl Drop for TestReader {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.log_dir);
+        }
+    }
+
+    fn reliable_datagram<M: Into<MessageInstance>>(msg: M, seq: SequenceNumber) -> Vec<u8> {
+        let mut packet = Packet::new(msg, seq);
+        packet.set_reliable(true);
+        let mut buf = Vec::new();
+        packet.write_to_vec(&mut buf).unwrap();
+        buf
+    }
+
+    fn activate_group() -> ActivateGroup {
+        ActivateGroup {
+            agent_data: ActivateGroup_AgentData {
+                agent_id: Uuid::nil(),
+                session_id: Uuid::nil(),
+                group_id: Uuid::nil(),
+            },
+        }
+    }
+
+    #[test]
+    fn foreign_address_dropped() {
+        let mut reader = TestReader::new("foreign", config());
+
+        let data = reliable_datagram(activate_group(), 1);
+        reader.datagram(&data, "127.0.0.1:9001".parse().unwrap());
+        let stats = reader.stats();
+        assert_eq!(stats.foreign_packets, 1);
+        assert_eq!(stats.packets_received, 0);
+        assert!(reader.sent_acks().is_empty());
+        assert!(reader.incoming.try_recv().is_err());
+
+        // The same packet from the sim is processed.
+        reader.datagram(&data, sim_address());
+        assert_eq!(reader.stats().packets_received, 1);
+        assert_eq!(reader.sent_acks(), vec![1]);
+        assert!(reader.incoming.try_recv().is_ok());
+    }
+
+    #[test]
+    fn trusted_message_dropped() {
+        let circuit_code = || AddCircuitCode {
+            circuit_code: AddCircuitCode_CircuitCode {
+                code: 1,
+                session_id: Uuid::nil(),
+                agent_id: Uuid::nil(),
+            },
+        };
+
+        // Trusted messages are only exchanged among sims.
+        let mut reader = TestReader::new("untrusted", config());
+        reader.datagram(&reliable_datagram(circuit_code(), 1), sim_address());
+        assert_eq!(reader.stats().untrusted_messages, 1);
+        assert!(reader.sent_acks().is_empty());
+        assert!(reader.incoming.try_recv().is_err());
+
+        let mut reader = TestReader::new(
+            "trusted",
+            CircuitConfig {
+                trusted: true,
+                ..config()
+            },
+        );
+        reader.datagram(&reliable_datagram(circuit_code(), 1), sim_address());
+        assert_eq!(reader.stats().untrusted_messages, 0);
+        assert_eq!(reader.sent_acks(), vec![1]);
+        assert!(reader.incoming.try_recv().is_ok());
+    }
+
+    #[test]
+    fn unknown_message_acked() {
+        let mut reader = TestReader::new("unknown", config());
+
+        // Reliable packet with a message this crate doesn't know, there are
+        // no fallback templates either.
+        let data = [0x40, 0, 0, 0, 3, 0, 0xff, 0xff, 0xea, 0x60, 0x2a, 0, 0, 0];
+        reader.datagram(&data, sim_address());
+        assert_eq!(reader.stats().decode_errors, 1);
+        assert_eq!(reader.sent_acks(), vec![3]);
+        assert!(reader.incoming.try_recv().is_err());
+    }
+
+    #[test]
+    fn sequence_jumps() {
+        let mut highest = None;
+        assert!(check_sequence(&mut highest, 10));
+        assert!(check_sequence(&mut highest, 5));
+        assert!(check_sequence(&mut highest, 10 + MAX_SEQUENCE_JUMP));
+        assert_eq!(highest, Some(10 + MAX_SEQUENCE_JUMP));
+
+        // The highest one is kept after a jump.
+        assert!(!check_sequence(&mut highest, 20 + 2 * MAX_SEQUENCE_JUMP));
+        assert_eq!(highest, Some(10 + MAX_SEQUENCE_JUMP));
+
+        // Wrapping around is fine.
+        let mut highest = Some(SequenceNumber::max_value());
+        assert!(check_sequence(&mut highest, 3));
+        assert_eq!(highest, Some(3));
+    }
+}
@@ -0,0 +1,196 @@
+//! Tracking of the sequence numbers of received packets.
+//!
+//! The sequence numbers of the last `WINDOW_SIZE` packets are kept in a ring
+//! of bits, so checking for duplicates takes constant time. Packets skipped
+//! or arriving out of order are detected on the way.
+
+use types::SequenceNumber;
+
+/// The number of sequence numbers behind the highest one which are
+/// remembered, more than a busy sim sends in several seconds.
+const WINDOW_SIZE: u32 = 16384;
+
+const WORDS: usize = WINDOW_SIZE as usize / 64;
+
+/// How a received packet relates to the ones received before.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Arrival {
+    /// The packet is newer than all others, the contained number of
+    /// sequence numbers in between were skipped.
+    InOrder { skipped: u32 },
+    /// The packet is older than the newest one, but was not received before.
+    Reordered,
+    /// The packet was received before.
+    Duplicate,
+    /// The packet is too old to tell whether it was received before.
+    Stale,
+}
+
+#[cfg(test)]
+impl Arrival {
+    pub fn is_duplicate(&self) -> bool {
+        *self == Arrival::Duplicate
+    }
+}
+
+pub struct ReceiveWindow {
+    /// The highest sequence number so far, `None` before the first packet.
+    highest: Option<SequenceNumber>,
+    /// Bit `seq % WINDOW_SIZE` is set if `seq` was received, valid for the
+    /// sequence numbers up to `WINDOW_SIZE` behind `highest`.
+    bits: [u64; WORDS],
+}
+
+impl ReceiveWindow {
+    pub fn new() -> Self {
+        ReceiveWindow {
+            highest: None,
+            bits: [0; WORDS],
+        }
+    }
+
+    /// Register a received packet.
+    pub fn insert(&mut self, seq: SequenceNumber) -> Arrival {
+        let highest = match self.highest {
+            Some(highest) => highest,
+            None => {
+                self.highest = Some(seq);
+                self.set(seq);
+                return Arrival::InOrder { skipped: 0 };
+            }
+        };
+
+        // Sequence numbers wrap around, anything within half of the range
+        // ahead of the highest one is considered newer.
+        let ahead = seq.wrapping_sub(highest);
+        if ahead == 0 {
+            Arrival::Duplicate
+        } else if ahead < SequenceNumber::max_value() / 2 {
+            if ahead >= WINDOW_SIZE {
+                self.bits = [0; WORDS];
+            } else {
+                for skipped in 1..ahead {
+                    self.clear(highest.wrapping_add(skipped));
+                }
+            }
+            self.highest = Some(seq);
+            self.set(seq);
+            Arrival::InOrder { skipped: ahead - 1 }
+        } else if highest.wrapping_sub(seq) >= WINDOW_SIZE {
+            Arrival::Stale
+        } else if self.get(seq) {
+            Arrival::Duplicate
+        } else {
+            self.set(seq);
+            Arrival::Reordered
+        }
+    }
+
+    fn get(&self, seq: SequenceNumber) -> bool {
+        let bit = seq % WINDOW_SIZE;
+        self.bits[bit as usize / 64] & (1 << (bit % 64)) != 0
+    }
+
+    fn set(&mut self, seq: SequenceNumber) {
+        let bit = seq % WINDOW_SIZE;
+        self.bits[bit as usize / 64] |= 1 << (bit % 64);
+    }
+
+    fn clear(&mut self, seq: SequenceNumber) {
+        let bit = seq % WINDOW_SIZE;
+        self.bits[bit as usize / 64] &= !(1 << (bit % 64));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test::Bencher;
+    use util::FifoCache;
+
+    #[test]
+    fn arrivals() {
+        let mut window = ReceiveWindow::new();
+        assert_eq!(window.insert(1), Arrival::InOrder { skipped: 0 });
+        assert_eq!(window.insert(2), Arrival::InOrder { skipped: 0 });
+        assert_eq!(window.insert(5), Arrival::InOrder { skipped: 2 });
+        assert_eq!(window.insert(3), Arrival::Reordered);
+        assert_eq!(window.insert(3), Arrival::Duplicate);
+        assert_eq!(window.insert(5), Arrival::Duplicate);
+        assert_eq!(window.insert(4), Arrival::Reordered);
+
+        // Bits of the skipped sequence numbers are cleared when the window
+        // moves on, even if they were set one round earlier.
+        let skipped = WINDOW_SIZE - 2;
+        assert_eq!(window.insert(4 + WINDOW_SIZE), Arrival::InOrder { skipped });
+        assert_eq!(window.insert(3 + WINDOW_SIZE), Arrival::Reordered);
+        assert_eq!(window.insert(4), Arrival::Stale);
+
+        let skipped = WINDOW_SIZE;
+        assert_eq!(window.insert(5 + 2 * WINDOW_SIZE), Arrival::InOrder { skipped });
+        assert_eq!(window.insert(4 + 2 * WINDOW_SIZE), Arrival::Reordered);
+    }
+
+    #[test]
+    fn wrapping() {
+        let mut window = ReceiveWindow::new();
+        let max = SequenceNumber::max_value();
+        assert_eq!(window.insert(max - 1), Arrival::InOrder { skipped: 0 });
+        assert_eq!(window.insert(1), Arrival::InOrder { skipped: 2 });
+        assert_eq!(window.insert(max), Arrival::Reordered);
+        assert_eq!(window.insert(max - 1), Arrival::Duplicate);
+    }
+
+    /// The sequence numbers of a sim sending object updates: mostly in order
+    /// with some reordering and some resent packets.
+    fn sequence(i: u32) -> SequenceNumber {
+        match i % 100 {
+            10 => i + 1,
+            11 => i - 1,
+            50 => i - 20,
+            _ => i,
+        }
+    }
+
+    /// 1000 packets with the `FifoCache` the circuit used before.
+    #[bench]
+    fn bench_fifo_cache(b: &mut Bencher) {
+        let mut cache = FifoCache::new(10000);
+        for i in 0..10000 {
+            cache.insert(sequence(i));
+        }
+        let mut next = 10000;
+        b.iter(|| {
+            let mut duplicates = 0;
+            for i in next..next + 1000 {
+                let seq = sequence(i);
+                if cache.contains(&seq) {
+                    duplicates += 1;
+                }
+                cache.insert(seq);
+            }
+            next += 1000;
+            duplicates
+        });
+    }
+
+    /// 1000 packets with the `ReceiveWindow`.
+    #[bench]
+    fn bench_receive_window(b: &mut Bencher) {
+        let mut window = ReceiveWindow::new();
+        for i in 0..10000 {
+            window.insert(sequence(i));
+        }
+        let mut next = 10000;
+        b.iter(|| {
+            let mut duplicates = 0;
+            for i in next..next + 1000 {
+                if window.insert(sequence(i)).is_duplicate() {
+                    duplicates += 1;
+                }
+            }
+            next += 1000;
+            duplicates
+        });
+    }
+}
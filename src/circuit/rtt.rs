@@ -0,0 +1,117 @@
+//! Round-trip time estimation and resend timeouts, following RFC 6298.
+//!
+//! Samples come from acks of reliable packets which were not resent (Karn's
+//! algorithm) and from `StartPingCheck`/`CompletePingCheck` pairs.
+
+use std::cmp::{max, min};
+use std::time::Duration;
+
+/// Lower bound of the resend timeout, acks are not always sent right away.
+const MIN_RTO: Duration = Duration::from_millis(200);
+
+/// Clock granularity as used in the RTO formula.
+const GRANULARITY: Duration = Duration::from_millis(10);
+
+pub struct RttEstimator {
+    /// Smoothed round-trip time, `None` until the first sample.
+    srtt: Option<Duration>,
+    /// Round-trip time variation.
+    rttvar: Duration,
+    /// Current resend timeout.
+    rto: Duration,
+    /// Resend timeout before the first sample and upper bound of all
+    /// timeouts, including backoff.
+    max_rto: Duration,
+}
+
+impl RttEstimator {
+    pub fn new(max_rto: Duration) -> Self {
+        RttEstimator {
+            srtt: None,
+            rttvar: Duration::from_millis(0),
+            rto: max_rto,
+            max_rto: max_rto,
+        }
+    }
+
+    /// Update the estimate with a measured round-trip time.
+    pub fn sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let delta = if srtt > rtt { srtt - rtt } else { rtt - srtt };
+                self.rttvar = (self.rttvar * 3 + delta) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+        let rto = self.srtt.unwrap() + max(GRANULARITY, self.rttvar * 4);
+        self.rto = min(max(rto, MIN_RTO), self.max_rto);
+    }
+
+    /// The smoothed round-trip time, `None` if there was no sample yet.
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    /// Timeout of the first attempt to send a reliable packet.
+    pub fn rto(&self) -> Duration {
+        self.rto
+    }
+
+    /// Timeout of the provided attempt (0 being the first one), doubling with
+    /// every resend.
+    pub fn backoff(&self, attempt: u8) -> Duration {
+        2u32.checked_pow(attempt as u32)
+            .and_then(|factor| self.rto.checked_mul(factor))
+            .map(|rto| min(rto, self.max_rto))
+            .unwrap_or(self.max_rto)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn initial_rto() {
+        let rtt = RttEstimator::new(Duration::from_secs(5));
+        assert_eq!(rtt.srtt(), None);
+        assert_eq!(rtt.rto(), Duration::from_secs(5));
+        assert_eq!(rtt.backoff(3), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn samples() {
+        let mut rtt = RttEstimator::new(Duration::from_secs(5));
+        rtt.sample(Duration::from_millis(100));
+        assert_eq!(rtt.srtt(), Some(Duration::from_millis(100)));
+        // 100 ms + 4 * 50 ms
+        assert_eq!(rtt.rto(), Duration::from_millis(300));
+
+        rtt.sample(Duration::from_millis(180));
+        // rttvar: (3 * 50 + 80) / 4 = 57.5, srtt: (7 * 100 + 180) / 8 = 110
+        assert_eq!(rtt.srtt(), Some(Duration::from_millis(110)));
+        assert_eq!(rtt.rto(), Duration::from_millis(340));
+
+        assert_eq!(rtt.backoff(0), Duration::from_millis(340));
+        assert_eq!(rtt.backoff(1), Duration::from_millis(680));
+        // 16 * 340 ms is capped.
+        assert_eq!(rtt.backoff(4), Duration::from_secs(5));
+        assert_eq!(rtt.backoff(200), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn rto_bounds() {
+        let mut rtt = RttEstimator::new(Duration::from_secs(1));
+        for _ in 0..20 {
+            rtt.sample(Duration::from_millis(1));
+        }
+        assert_eq!(rtt.rto(), MIN_RTO);
+
+        rtt.sample(Duration::from_secs(10));
+        assert_eq!(rtt.rto(), Duration::from_secs(1));
+    }
+}
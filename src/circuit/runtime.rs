@@ -0,0 +1,825 @@
+//! Driving many circuits with a fixed number of threads, see
+//! `CircuitRuntime`.
+
+use circuit::ack_manager::{AckManagerRx, AckManagerTx, Fetch};
+use circuit::message_handlers::HandlerContext;
+use circuit::reader::{CircuitReader, RECEIVE_BUFFER_SIZE};
+use circuit::stats::StatsCounters;
+use circuit::{write_packet, MessageSender, READ_TIMEOUT};
+use logging::Log;
+use packet::Packet;
+
+use crossbeam_channel::{self, Receiver, Select, Sender, TryRecvError};
+use futures::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures::{task, Async, Future, Poll, Stream};
+use futures_cpupool::CpuPool;
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::net::{self, SocketAddr};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
+use tokio_core::net::UdpSocket;
+use tokio_core::reactor::{self, Core, Interval};
+
+/// The number of datagrams read, processed or sent for one circuit before
+/// the next circuit gets its turn.
+const BATCH_SIZE: usize = 32;
+
+/// The number of datagrams, errors and ticks of a circuit waiting for the
+/// pool, once it is reached further datagrams are dropped like the network
+/// would, see `CircuitStats::dropped_datagrams`.
+const QUEUE_CAPACITY: usize = 256;
+
+/// How long the sender thread waits before writing a packet again, which
+/// did not fit into the buffer of its socket.
+const WRITE_RETRY: Duration = Duration::from_millis(1);
+
+/// Drives the circuits initiated with `Circuit::initiate_on`, with the same
+/// number of threads regardless of the number of circuits.
+///
+/// One thread runs a reactor reading the datagrams from the sockets of all
+/// circuits, a pool of threads decodes them and calls the message handlers,
+/// and one thread sends the packets of all circuits. The datagrams of one
+/// circuit are processed one after the other in the order they were read,
+/// the ones of different circuits in parallel. In comparison every circuit
+/// initiated with `Circuit::initiate` runs four threads of its own.
+///
+/// If the buffer of a socket is full, the sender thread writes the packet
+/// again shortly afterwards and meanwhile sends the packets of the other
+/// circuits. The sender thread of a circuit with threads of its own blocks
+/// until the packet was written instead.
+///
+/// The threads stop once the runtime and all of its circuits are gone, the
+/// circuits still have to be closed.
+#[derive(Clone)]
+pub struct CircuitRuntime {
+    reactor: UnboundedSender<ReactorCommand>,
+    remote: reactor::Remote,
+    sender: Sender<SenderCircuit>,
+    cpupool: CpuPool,
+    dead: UnboundedSender<usize>,
+    next_id: Arc<AtomicUsize>,
+}
+
+impl CircuitRuntime {
+    /// Start the threads of the runtime, `pool_threads` is the size of the
+    /// pool decoding the datagrams of all circuits, which is shared by the
+    /// message handlers too, see `HandlerContext::cpupool`.
+    pub fn new(pool_threads: usize) -> Result<CircuitRuntime, IoError> {
+        let cpupool = CpuPool::new(pool_threads);
+        let (reactor_tx, reactor_rx) = mpsc::unbounded();
+        let (dead_tx, dead_rx) = mpsc::unbounded();
+        let (remote_tx, remote_rx) = crossbeam_channel::bounded(1);
+        let reactor_cpupool = cpupool.clone();
+        thread::spawn(move || {
+            let setup = Core::new().and_then(|core| {
+                let tick = Interval::new(READ_TIMEOUT, &core.handle())?;
+                Ok((core, tick))
+            });
+            let (mut core, tick) = match setup {
+                Ok(setup) => setup,
+                Err(e) => {
+                    let _ = remote_tx.send(Err(e));
+                    return;
+                }
+            };
+            let _ = remote_tx.send(Ok(core.remote()));
+
+            let reactor = Reactor {
+                commands: reactor_rx,
+                commands_open: true,
+                dead: dead_rx,
+                circuits: Vec::new(),
+                tick: tick,
+                cpupool: reactor_cpupool,
+                handle: core.handle(),
+            };
+            let _ = core.run(reactor);
+        });
+        let remote = match remote_rx.recv() {
+            Ok(remote) => remote?,
+            Err(_) => return Err(IoError::new(IoErrorKind::Other, "Reactor thread panicked.")),
+        };
+
+        let (sender_tx, sender_rx) = crossbeam_channel::unbounded();
+        thread::spawn(move || send_loop(sender_rx));
+
+        Ok(CircuitRuntime {
+            reactor: reactor_tx,
+            remote: remote,
+            sender: sender_tx,
+            cpupool: cpupool,
+            dead: dead_tx,
+            next_id: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    /// The reactor given to the message handlers, see
+    /// `HandlerContext::reactor`.
+    pub fn remote(&self) -> reactor::Remote {
+        self.remote.clone()
+    }
+
+    /// Start driving a circuit, see `Circuit::initiate_on`.
+    pub(super) fn add(
+        &self,
+        socket: net::UdpSocket,
+        reader: CircuitReader,
+        ackmgr_rx: AckManagerRx,
+        message_sender: MessageSender,
+        ackmgr_tx: AckManagerTx,
+        log: Log,
+    ) -> Result<RuntimeCircuit, IoError> {
+        // The reactor makes the socket non-blocking, which the clone shares.
+        let socket_out = socket.try_clone()?;
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let (reader_stopped_tx, reader_stopped_rx) = crossbeam_channel::bounded(1);
+        let (sender_done_tx, sender_done_rx) = crossbeam_channel::bounded(1);
+        let stopped = || IoError::new(IoErrorKind::Other, "The runtime is not running anymore.");
+
+        let work = Arc::new(CircuitWork {
+            id: id,
+            queue: Mutex::new(WorkQueue {
+                items: VecDeque::new(),
+                scheduled: false,
+                worker: None,
+                stopped: false,
+                stopped_tx: Some(reader_stopped_tx),
+            }),
+            decoder: Mutex::new(Decoder {
+                reader: reader,
+                message_sender: message_sender,
+                cpupool: self.cpupool.clone(),
+                reactor: self.remote.clone(),
+                dead: self.dead.clone(),
+            }),
+            stats: ackmgr_tx.stats_counters(),
+        });
+        let reader = NewCircuit {
+            socket: socket,
+            work: work.clone(),
+        };
+        self.reactor
+            .unbounded_send(ReactorCommand::Add(reader))
+            .map_err(|_| stopped())?;
+
+        let sender = SenderCircuit {
+            ackmgr_rx: ackmgr_rx,
+            socket: socket_out,
+            log: log,
+            stats: ackmgr_tx.stats_counters(),
+            ackmgr_tx: ackmgr_tx,
+            pending: None,
+            done: sender_done_tx,
+        };
+        if self.sender.send(sender).is_err() {
+            let _ = self.reactor.unbounded_send(ReactorCommand::Remove(id));
+            return Err(stopped());
+        }
+
+        Ok(RuntimeCircuit {
+            reactor: self.reactor.clone(),
+            work: work,
+            reader_stopped: reader_stopped_rx,
+            sender_done: sender_done_rx,
+        })
+    }
+}
+
+/// A circuit driven by a `CircuitRuntime`.
+pub(super) struct RuntimeCircuit {
+    reactor: UnboundedSender<ReactorCommand>,
+    work: Arc<CircuitWork>,
+    reader_stopped: Receiver<()>,
+    sender_done: Receiver<()>,
+}
+
+impl RuntimeCircuit {
+    /// Stop reading from the socket of the circuit, the datagrams which were
+    /// not processed yet are dropped. Returns `false` if a message handler
+    /// of the circuit panicked.
+    ///
+    /// In a message handler of the circuit this returns right away. Nothing
+    /// is processed anymore once the handler returned, waiting for it would
+    /// never end.
+    pub fn stop_reader(&self) -> bool {
+        let _ = self.reactor.unbounded_send(ReactorCommand::Remove(self.work.id));
+        if !self.work.stop() {
+            return true;
+        }
+        self.reader_stopped.recv().is_ok()
+    }
+
+    /// Wait until the circuit was closed and everything queued was sent,
+    /// returns `false` if the sender thread panicked.
+    pub fn join_sender(self) -> bool {
+        self.sender_done.recv().is_ok()
+    }
+}
+
+/// Read by the reactor, to be processed on the pool.
+enum Inbound {
+    Datagram(Vec<u8>, SocketAddr),
+    SocketError(IoError),
+    /// The reactor's interval elapsed, check the circuit for timeouts.
+    Tick,
+}
+
+/// The state of a circuit shared by the reactor, the tasks of the pool
+/// processing it and the `RuntimeCircuit`.
+struct CircuitWork {
+    id: usize,
+    queue: Mutex<WorkQueue>,
+    /// Only locked by the task processing the circuit.
+    decoder: Mutex<Decoder>,
+    stats: Arc<StatsCounters>,
+}
+
+struct WorkQueue {
+    items: VecDeque<Inbound>,
+    /// Whether a task of the pool processes the items, there is at most one
+    /// per circuit which keeps them in order.
+    scheduled: bool,
+    /// The thread of that task while it processes an item.
+    worker: Option<ThreadId>,
+    stopped: bool,
+    /// Signalled once the circuit was stopped and no task processes it
+    /// anymore, dropped if a message handler panicked.
+    stopped_tx: Option<Sender<()>>,
+}
+
+struct Decoder {
+    reader: CircuitReader,
+    message_sender: MessageSender,
+    cpupool: CpuPool,
+    reactor: reactor::Remote,
+    /// Tells the reactor that the circuit died.
+    dead: UnboundedSender<usize>,
+}
+
+impl CircuitWork {
+    /// Queue an item, and start a task on the pool processing the queue
+    /// unless there is one already.
+    fn push(work: &Arc<CircuitWork>, item: Inbound, cpupool: &CpuPool) {
+        {
+            let mut queue = work.queue.lock().unwrap();
+            if queue.stopped {
+                return;
+            }
+            if queue.items.len() >= QUEUE_CAPACITY {
+                match item {
+                    Inbound::Datagram(..) => work.stats.dropped_datagram(),
+                    Inbound::SocketError(_) => work.stats.socket_error(),
+                    // There will be another one.
+                    Inbound::Tick => {}
+                }
+                return;
+            }
+            queue.items.push_back(item);
+            if queue.scheduled {
+                return;
+            }
+            queue.scheduled = true;
+        }
+        CircuitWork::spawn(work.clone(), cpupool);
+    }
+
+    fn spawn(work: Arc<CircuitWork>, cpupool: &CpuPool) {
+        let task_cpupool = cpupool.clone();
+        cpupool
+            .spawn_fn(move || {
+                CircuitWork::process(work, &task_cpupool);
+                Ok::<(), ()>(())
+            })
+            .forget();
+    }
+
+    /// Process up to `BATCH_SIZE` items, then let the tasks of the other
+    /// circuits run before continuing.
+    fn process(work: Arc<CircuitWork>, cpupool: &CpuPool) {
+        for _ in 0..BATCH_SIZE {
+            let item = {
+                let mut queue = work.queue.lock().unwrap();
+                match queue.items.pop_front() {
+                    Some(item) => {
+                        queue.worker = Some(thread::current().id());
+                        item
+                    }
+                    None => {
+                        queue.scheduled = false;
+                        if queue.stopped {
+                            if let Some(stopped_tx) = queue.stopped_tx.take() {
+                                let _ = stopped_tx.send(());
+                            }
+                        }
+                        return;
+                    }
+                }
+            };
+
+            // A panicking message handler stops its circuit, like it stops
+            // the reader thread of a circuit with threads of its own.
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                work.decoder.lock().unwrap().process(item)
+            }));
+            let alive = {
+                let mut queue = work.queue.lock().unwrap();
+                queue.worker = None;
+                match result {
+                    Ok(alive) => alive,
+                    Err(_) => {
+                        queue.items.clear();
+                        queue.stopped = true;
+                        queue.stopped_tx = None;
+                        false
+                    }
+                }
+            };
+            if !alive {
+                work.stop();
+                // After a panic the decoder is poisoned, but its sender is
+                // still fine.
+                let decoder = work.decoder.lock().unwrap_or_else(|e| e.into_inner());
+                let _ = decoder.dead.unbounded_send(work.id);
+            }
+        }
+        CircuitWork::spawn(work, cpupool);
+    }
+
+    /// Stop processing the circuit and drop the queued items.
+    ///
+    /// Returns whether the caller has to wait for the item being processed,
+    /// which is not the case if it is processed by the calling thread.
+    fn stop(&self) -> bool {
+        let mut queue = self.queue.lock().unwrap();
+        queue.stopped = true;
+        queue.items.clear();
+        // A task waiting for its turn on the pool won't process anything
+        // anymore, waiting for it could block the pool's only thread.
+        if queue.worker.is_none() {
+            if let Some(stopped_tx) = queue.stopped_tx.take() {
+                let _ = stopped_tx.send(());
+            }
+        }
+        queue.worker != Some(thread::current().id())
+    }
+}
+
+impl Decoder {
+    /// Returns `false` once the circuit is dead.
+    fn process(&mut self, item: Inbound) -> bool {
+        match item {
+            Inbound::Datagram(buf, address) => {
+                let context = HandlerContext {
+                    message_sender: self.message_sender.clone(),
+                    cpupool: &self.cpupool,
+                    reactor: self.reactor.clone(),
+                };
+                self.reader.datagram(&buf, address, &context);
+                true
+            }
+            Inbound::SocketError(e) => {
+                self.reader.socket_error(e);
+                true
+            }
+            Inbound::Tick => self.reader.check_silence(),
+        }
+    }
+}
+
+enum ReactorCommand {
+    Add(NewCircuit),
+    Remove(usize),
+}
+
+/// A circuit to be added to the reactor.
+struct NewCircuit {
+    socket: net::UdpSocket,
+    work: Arc<CircuitWork>,
+}
+
+struct ReactorCircuit {
+    socket: UdpSocket,
+    work: Arc<CircuitWork>,
+}
+
+/// The future run by the reactor thread, reading from the sockets of all
+/// circuits whenever any of them becomes readable and handing the datagrams
+/// to the pool.
+struct Reactor {
+    commands: UnboundedReceiver<ReactorCommand>,
+    commands_open: bool,
+    /// The circuits which died on the pool.
+    dead: UnboundedReceiver<usize>,
+    circuits: Vec<ReactorCircuit>,
+    /// Wakes up the reactor to check the circuits for timeouts.
+    tick: Interval,
+    cpupool: CpuPool,
+    handle: reactor::Handle,
+}
+
+impl Reactor {
+    fn add(&mut self, circuit: NewCircuit) {
+        let NewCircuit { socket, work } = circuit;
+        match UdpSocket::from_socket(socket, &self.handle) {
+            Ok(socket) => self.circuits.push(ReactorCircuit {
+                socket: socket,
+                work: work,
+            }),
+            Err(e) => {
+                work.decoder.lock().unwrap().reader.socket_error(e);
+                work.stop();
+            }
+        }
+    }
+
+    fn remove(&mut self, id: usize) {
+        // Circuits which died were removed already.
+        if let Some(index) = self.circuits.iter().position(|c| c.work.id == id) {
+            let circuit = self.circuits.swap_remove(index);
+            circuit.work.stop();
+        }
+    }
+}
+
+impl Future for Reactor {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        while self.commands_open {
+            match self.commands.poll() {
+                Ok(Async::Ready(Some(ReactorCommand::Add(circuit)))) => self.add(circuit),
+                Ok(Async::Ready(Some(ReactorCommand::Remove(id)))) => self.remove(id),
+                Ok(Async::Ready(None)) | Err(()) => self.commands_open = false,
+                Ok(Async::NotReady) => break,
+            }
+        }
+        // The runtime keeps a sender, this never ends.
+        while let Ok(Async::Ready(Some(id))) = self.dead.poll() {
+            self.remove(id);
+        }
+
+        // Polling the interval until it is not ready registers the reactor
+        // for the next tick.
+        let mut tick = false;
+        while let Ok(Async::Ready(Some(()))) = self.tick.poll() {
+            tick = true;
+        }
+
+        let mut buf = [0u8; RECEIVE_BUFFER_SIZE];
+        let mut again = false;
+        for circuit in &self.circuits {
+            let mut drained = false;
+            for _ in 0..BATCH_SIZE {
+                // Not being ready registers the reactor to be woken up once
+                // the socket is readable.
+                match circuit.socket.recv_from(&mut buf) {
+                    Ok((size, address)) => {
+                        let datagram = Inbound::Datagram(buf[..size].to_vec(), address);
+                        CircuitWork::push(&circuit.work, datagram, &self.cpupool);
+                    }
+                    Err(ref e) if e.kind() == IoErrorKind::WouldBlock => {
+                        drained = true;
+                        break;
+                    }
+                    Err(e) => {
+                        CircuitWork::push(&circuit.work, Inbound::SocketError(e), &self.cpupool);
+                        break;
+                    }
+                }
+            }
+            // Come back to the socket after the other circuits.
+            again = again || !drained;
+            if tick {
+                CircuitWork::push(&circuit.work, Inbound::Tick, &self.cpupool);
+            }
+        }
+        if again {
+            task::current().notify();
+        }
+
+        if !self.commands_open && self.circuits.is_empty() {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+/// A circuit whose packets are sent by the sender thread.
+struct SenderCircuit {
+    ackmgr_rx: AckManagerRx,
+    socket: net::UdpSocket,
+    log: Log,
+    ackmgr_tx: AckManagerTx,
+    stats: Arc<StatsCounters>,
+    /// The packet which did not fit into the buffer of the socket.
+    pending: Option<Packet>,
+    /// Signalled once the circuit was closed and everything was sent.
+    done: Sender<()>,
+}
+
+/// Send the packets of all circuits, until the runtime is gone and all of
+/// its circuits were closed.
+fn send_loop(new_circuits: Receiver<SenderCircuit>) {
+    let mut circuits: Vec<SenderCircuit> = Vec::new();
+    let mut open = true;
+    loop {
+        while open {
+            match new_circuits.try_recv() {
+                Ok(circuit) => circuits.push(circuit),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => open = false,
+            }
+        }
+
+        let mut next: Option<Instant> = None;
+        let mut index = 0;
+        while index < circuits.len() {
+            match send_batch(&mut circuits[index]) {
+                Some(until) => {
+                    next = Some(next.map_or(until, |next| min(next, until)));
+                    index += 1;
+                }
+                None => {
+                    let circuit = circuits.swap_remove(index);
+                    let _ = circuit.done.send(());
+                }
+            }
+        }
+        if !open && circuits.is_empty() {
+            break;
+        }
+
+        // Block until any of the circuits might have something to send.
+        let mut select = Select::new();
+        if open {
+            select.recv(&new_circuits);
+        }
+        for circuit in &circuits {
+            circuit.ackmgr_rx.watch(&mut select);
+        }
+        let now = Instant::now();
+        match next {
+            Some(until) if until <= now => {}
+            Some(until) => {
+                let _ = select.ready_timeout(until - now);
+            }
+            None => {
+                select.ready();
+            }
+        }
+    }
+}
+
+/// Send up to `BATCH_SIZE` packets of the circuit.
+///
+/// Returns the time until which it is idle, or `None` once it is closed.
+fn send_batch(circuit: &mut SenderCircuit) -> Option<Instant> {
+    for _ in 0..BATCH_SIZE {
+        let packet = match circuit.pending.take() {
+            Some(packet) => packet,
+            None => match circuit.ackmgr_rx.poll_fetch() {
+                Fetch::Packet(packet) => packet,
+                Fetch::Idle(until) => return Some(until),
+                Fetch::Closed => return None,
+            },
+        };
+        let written = write_packet(
+            &circuit.socket,
+            &packet,
+            &circuit.log,
+            &circuit.ackmgr_tx,
+            &circuit.stats,
+        );
+        if !written {
+            // The socket is non-blocking, waiting for it would hold up the
+            // other circuits.
+            circuit.pending = Some(packet);
+            return Some(Instant::now() + WRITE_RETRY);
+        }
+    }
+    // There might be more, but the other circuits come first.
+    Some(Instant::now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use circuit::{message_handlers, Circuit, CircuitConfig};
+    use logging::LogLevel;
+    use messages::{
+        MessageInstance, StartPingCheck, StartPingCheck_PingID, UseCircuitCode,
+        UseCircuitCode_CircuitCode,
+    };
+    use packet::Packet;
+    use simulator::ConnectInfo;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use types::{Ip4Addr, Uuid};
+    use url::Url;
+
+    fn config() -> CircuitConfig {
+        CircuitConfig {
+            send_timeout: Duration::from_millis(5000),
+            send_attempts: 5,
+            ping_interval: Duration::from_secs(60),
+            throttles: None,
+            trusted: false,
+            silence_timeout: Duration::from_secs(10),
+            dead_timeout: None,
+            stats_interval: None,
+        }
+    }
+
+    #[test]
+    fn circuits_on_runtime() {
+        // A fake sim replying to the pings of all circuits.
+        let sim = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        sim.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let connect_info = ConnectInfo {
+            capabilities_seed: Url::parse("http://127.0.0.1/").unwrap(),
+            agent_id: Uuid::nil(),
+            session_id: Uuid::nil(),
+            circuit_code: 0,
+            sim_ip: Ip4Addr::new(127, 0, 0, 1),
+            sim_port: sim.local_addr().unwrap().port(),
+        };
+        let dir = env::temp_dir().join(format!("opensim-runtime-{}", process::id()));
+        let log = Log::new_dir(dir.clone(), LogLevel::Events).unwrap();
+
+        let runtime = CircuitRuntime::new(1).unwrap();
+        let circuits: Vec<_> = (0..3)
+            .map(|_| {
+                let handlers = message_handlers::Handlers::default();
+                Circuit::initiate_on(&runtime, &connect_info, config(), handlers, log.clone())
+                    .unwrap()
+            })
+            .collect();
+
+        let mut buf = [0u8; RECEIVE_BUFFER_SIZE];
+        for (i, circuit) in circuits.iter().enumerate() {
+            // The sim learns the address of the circuit from its first packet.
+            let message = UseCircuitCode {
+                circuit_code: UseCircuitCode_CircuitCode {
+                    code: i as u32,
+                    session_id: Uuid::nil(),
+                    id: Uuid::nil(),
+                },
+            };
+            circuit.send(message, false);
+            let (size, address) = sim.recv_from(&mut buf).unwrap();
+            match Packet::read(&buf[..size]).unwrap().message {
+                MessageInstance::UseCircuitCode(msg) => assert_eq!(msg.circuit_code.code, i as u32),
+                msg => panic!("unexpected message: {:?}", msg),
+            }
+
+            // Pings from the sim are answered by the default handler.
+            let ping = StartPingCheck {
+                ping_id: StartPingCheck_PingID {
+                    ping_id: i as u8,
+                    oldest_unacked: 0,
+                },
+            };
+            let mut packet = Vec::new();
+            Packet::new(ping, 1).write_to_vec(&mut packet).unwrap();
+            sim.send_to(&packet, address).unwrap();
+            let (size, _) = sim.recv_from(&mut buf).unwrap();
+            match Packet::read(&buf[..size]).unwrap().message {
+                MessageInstance::CompletePingCheck(msg) => assert_eq!(msg.ping_id.ping_id, i as u8),
+                msg => panic!("unexpected message: {:?}", msg),
+            }
+        }
+
+        for circuit in circuits {
+            circuit.close().unwrap();
+        }
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn close_from_handler() {
+        let sim = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        sim.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let connect_info = ConnectInfo {
+            capabilities_seed: Url::parse("http://127.0.0.1/").unwrap(),
+            agent_id: Uuid::nil(),
+            session_id: Uuid::nil(),
+            circuit_code: 0,
+            sim_ip: Ip4Addr::new(127, 0, 0, 1),
+            sim_port: sim.local_addr().unwrap().port(),
+        };
+        let dir = env::temp_dir().join(format!("opensim-runtime-close-{}", process::id()));
+        let log = Log::new_dir(dir.clone(), LogLevel::Events).unwrap();
+
+        // The handler closes the circuit while it is being processed.
+        let slot: Arc<Mutex<Option<Circuit>>> = Arc::new(Mutex::new(None));
+        let (closed_tx, closed_rx) = crossbeam_channel::bounded(1);
+        let mut handlers = message_handlers::Handlers::new();
+        let handler_slot = slot.clone();
+        handlers.register(move |_: StartPingCheck, _: &HandlerContext| {
+            if let Some(circuit) = handler_slot.lock().unwrap().take() {
+                let _ = closed_tx.send(circuit.close().is_ok());
+            }
+            Ok(())
+        });
+
+        let runtime = CircuitRuntime::new(1).unwrap();
+        let circuit =
+            Circuit::initiate_on(&runtime, &connect_info, config(), handlers, log.clone()).unwrap();
+        let message = UseCircuitCode {
+            circuit_code: UseCircuitCode_CircuitCode {
+                code: 0,
+                session_id: Uuid::nil(),
+                id: Uuid::nil(),
+            },
+        };
+        circuit.send(message, false);
+        *slot.lock().unwrap() = Some(circuit);
+        let mut buf = [0u8; RECEIVE_BUFFER_SIZE];
+        let (_, address) = sim.recv_from(&mut buf).unwrap();
+
+        let ping = StartPingCheck {
+            ping_id: StartPingCheck_PingID {
+                ping_id: 1,
+                oldest_unacked: 0,
+            },
+        };
+        let mut packet = Vec::new();
+        Packet::new(ping, 1).write_to_vec(&mut packet).unwrap();
+        sim.send_to(&packet, address).unwrap();
+        assert_eq!(closed_rx.recv_timeout(Duration::from_secs(5)), Ok(true));
+
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn messages_in_order() {
+        let sim = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        sim.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let connect_info = ConnectInfo {
+            capabilities_seed: Url::parse("http://127.0.0.1/").unwrap(),
+            agent_id: Uuid::nil(),
+            session_id: Uuid::nil(),
+            circuit_code: 0,
+            sim_ip: Ip4Addr::new(127, 0, 0, 1),
+            sim_port: sim.local_addr().unwrap().port(),
+        };
+        let dir = env::temp_dir().join(format!("opensim-runtime-order-{}", process::id()));
+        let log = Log::new_dir(dir.clone(), LogLevel::Events).unwrap();
+
+        // With several threads in the pool the messages of a circuit are
+        // still handled one after the other.
+        let (pings_tx, pings_rx) = crossbeam_channel::unbounded();
+        let mut handlers = message_handlers::Handlers::new();
+        handlers.register(move |msg: StartPingCheck, _: &HandlerContext| {
+            let _ = pings_tx.send(msg.ping_id.ping_id);
+            Ok(())
+        });
+
+        let runtime = CircuitRuntime::new(4).unwrap();
+        let circuit =
+            Circuit::initiate_on(&runtime, &connect_info, config(), handlers, log.clone()).unwrap();
+        let message = UseCircuitCode {
+            circuit_code: UseCircuitCode_CircuitCode {
+                code: 0,
+                session_id: Uuid::nil(),
+                id: Uuid::nil(),
+            },
+        };
+        circuit.send(message, false);
+        let mut buf = [0u8; RECEIVE_BUFFER_SIZE];
+        let (_, address) = sim.recv_from(&mut buf).unwrap();
+
+        for ping_id in 0..100u8 {
+            let ping = StartPingCheck {
+                ping_id: StartPingCheck_PingID {
+                    ping_id: ping_id,
+                    oldest_unacked: 0,
+                },
+            };
+            let mut packet = Vec::new();
+            Packet::new(ping, u32::from(ping_id) + 1)
+                .write_to_vec(&mut packet)
+                .unwrap();
+            sim.send_to(&packet, address).unwrap();
+        }
+        for ping_id in 0..100u8 {
+            assert_eq!(pings_rx.recv_timeout(Duration::from_secs(5)), Ok(ping_id));
+        }
+
+        circuit.close().unwrap();
+        drop(log);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
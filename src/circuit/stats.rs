@@ -0,0 +1,186 @@
+//! Counters describing the health of a circuit, see `Circuit::stats`.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Snapshot of the statistics of a circuit.
+///
+/// The counters start when the circuit is initiated, the queue depths are
+/// updated whenever the sender thread wakes up.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CircuitStats {
+    /// Packets written to the socket, including resends.
+    pub packets_sent: usize,
+    pub bytes_sent: usize,
+    /// Datagrams read from the socket, including the ones which could not be
+    /// decoded.
+    pub packets_received: usize,
+    pub bytes_received: usize,
+
+    /// Reliable packets sent again because their ack timed out.
+    pub resends: usize,
+    /// Packets the sim sent again although we received them already, these
+    /// are dropped.
+    pub duplicates: usize,
+    /// Sequence numbers skipped by the sim's packets, i.e. packets which were
+    /// lost or arrived later, see `reordered`.
+    pub skipped: usize,
+    /// Packets which arrived after a newer one.
+    pub reordered: usize,
+    /// Reliable messages which were not acked after
+    /// `CircuitConfig::send_attempts`.
+    pub failed_acks: usize,
+    /// Datagrams which could not be decoded, including messages unknown to
+    /// this crate if there are no fallback templates.
+    pub decode_errors: usize,
+    /// Datagrams from another address than the sim, these are dropped and
+    /// not included in `packets_received`.
+    pub foreign_packets: usize,
+    /// Messages requiring a trusted circuit which arrived on an untrusted
+    /// one, these are dropped.
+    pub untrusted_messages: usize,
+    /// Packets whose sequence number was implausibly far ahead of the
+    /// previous ones, these are still processed.
+    pub sequence_jumps: usize,
+    /// Failed reads from and writes to the socket, including the ones not
+    /// reported as `CircuitEvent::SocketError`.
+    pub socket_errors: usize,
+    /// Datagrams dropped by a `CircuitRuntime` because its pool could not
+    /// keep up with them, these are not included in `packets_received`.
+    pub dropped_datagrams: usize,
+
+    /// Received messages waiting to be read with `Circuit::read`.
+    pub incoming_queue: usize,
+    /// Messages waiting to be sent, including the ones held back by the
+    /// throttles.
+    pub outgoing_queue: usize,
+    /// Acks waiting to be sent.
+    pub acks_queue: usize,
+    /// Reliable messages waiting for their ack.
+    pub unacked: usize,
+
+    /// The smoothed round-trip time, see `Circuit::rtt`.
+    pub rtt: Option<Duration>,
+}
+
+/// The counters behind `CircuitStats`, shared by the threads of a circuit.
+#[derive(Default)]
+pub(crate) struct StatsCounters {
+    packets_sent: AtomicUsize,
+    bytes_sent: AtomicUsize,
+    packets_received: AtomicUsize,
+    bytes_received: AtomicUsize,
+    resends: AtomicUsize,
+    duplicates: AtomicUsize,
+    skipped: AtomicUsize,
+    reordered: AtomicUsize,
+    failed_acks: AtomicUsize,
+    decode_errors: AtomicUsize,
+    foreign_packets: AtomicUsize,
+    untrusted_messages: AtomicUsize,
+    sequence_jumps: AtomicUsize,
+    socket_errors: AtomicUsize,
+    dropped_datagrams: AtomicUsize,
+    incoming_queue: AtomicUsize,
+    outgoing_queue: AtomicUsize,
+    acks_queue: AtomicUsize,
+    unacked: AtomicUsize,
+}
+
+impl StatsCounters {
+    pub fn packet_sent(&self, len: usize) {
+        self.packets_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(len, Ordering::Relaxed);
+    }
+
+    pub fn packet_received(&self, len: usize) {
+        self.packets_received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received.fetch_add(len, Ordering::Relaxed);
+    }
+
+    pub fn resend(&self) {
+        self.resends.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn duplicate(&self) {
+        self.duplicates.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn skipped(&self, count: usize) {
+        self.skipped.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn reordered(&self) {
+        self.reordered.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn failed_ack(&self) {
+        self.failed_acks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn decode_error(&self) {
+        self.decode_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn foreign_packet(&self) {
+        self.foreign_packets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn untrusted_message(&self) {
+        self.untrusted_messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn sequence_jump(&self) {
+        self.sequence_jumps.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn socket_error(&self) {
+        self.socket_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dropped_datagram(&self) {
+        self.dropped_datagrams.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A message was put into the incoming queue.
+    pub fn incoming_queued(&self) {
+        self.incoming_queue.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A message was taken from the incoming queue.
+    pub fn incoming_read(&self) {
+        self.incoming_queue.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Update the depths of the queues of the sender thread.
+    pub fn set_outgoing(&self, outgoing: usize, acks: usize, unacked: usize) {
+        self.outgoing_queue.store(outgoing, Ordering::Relaxed);
+        self.acks_queue.store(acks, Ordering::Relaxed);
+        self.unacked.store(unacked, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self, rtt: Option<Duration>) -> CircuitStats {
+        CircuitStats {
+            packets_sent: self.packets_sent.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            packets_received: self.packets_received.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            resends: self.resends.load(Ordering::Relaxed),
+            duplicates: self.duplicates.load(Ordering::Relaxed),
+            skipped: self.skipped.load(Ordering::Relaxed),
+            reordered: self.reordered.load(Ordering::Relaxed),
+            failed_acks: self.failed_acks.load(Ordering::Relaxed),
+            decode_errors: self.decode_errors.load(Ordering::Relaxed),
+            foreign_packets: self.foreign_packets.load(Ordering::Relaxed),
+            untrusted_messages: self.untrusted_messages.load(Ordering::Relaxed),
+            sequence_jumps: self.sequence_jumps.load(Ordering::Relaxed),
+            socket_errors: self.socket_errors.load(Ordering::Relaxed),
+            dropped_datagrams: self.dropped_datagrams.load(Ordering::Relaxed),
+            incoming_queue: self.incoming_queue.load(Ordering::Relaxed),
+            outgoing_queue: self.outgoing_queue.load(Ordering::Relaxed),
+            acks_queue: self.acks_queue.load(Ordering::Relaxed),
+            unacked: self.unacked.load(Ordering::Relaxed),
+            rtt: rtt,
+        }
+    }
+}
@@ -1,5 +1,6 @@
 //! Contains the status types used for the circuit module.
 
+use messages::ValidationError;
 use types::SequenceNumber;
 
 use futures::{self, Async, Future, Poll};
@@ -7,10 +8,17 @@ use std::sync::{Arc, Mutex, RwLock};
 use std::time::Instant;
 
 /// The `Future` returned by the `Circuit`'s send method.
+///
+/// If the message was split into multiple packets, it completes once all of
+/// them were sent (and acknowledged if reliable), or fails as soon as one of
+/// them fails.
 #[derive(Debug)]
 pub struct SendMessage {
     status: Arc<RwLock<SendMessageStatus>>,
     task: Arc<Mutex<Option<futures::task::Task>>>,
+    /// The futures of the parts if the message was split, in this case the
+    /// own status is not used.
+    parts: Vec<SendMessage>,
 }
 
 impl SendMessage {
@@ -18,6 +26,7 @@ impl SendMessage {
         SendMessage {
             status: Arc::clone(&self.status),
             task: Arc::clone(&self.task),
+            parts: self.parts.iter().map(|p| p.clone()).collect(),
         }
     }
 
@@ -25,6 +34,15 @@ impl SendMessage {
         SendMessage {
             status: Arc::new(RwLock::new(status)),
             task: Arc::new(Mutex::new(None)),
+            parts: Vec::new(),
+        }
+    }
+
+    /// Combine the futures of the parts of a split message.
+    pub(crate) fn from_parts(parts: Vec<SendMessage>) -> Self {
+        SendMessage {
+            parts: parts,
+            ..SendMessage::new(SendMessageStatus::PendingSend { reliable: false })
         }
     }
 
@@ -51,6 +69,21 @@ impl Future for SendMessage {
     type Error = SendMessageError;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if !self.parts.is_empty() {
+            // Poll all parts, so each of them will notify the current task.
+            let mut ready = true;
+            for part in &mut self.parts {
+                if part.poll()?.is_not_ready() {
+                    ready = false;
+                }
+            }
+            return Ok(if ready {
+                Async::Ready(())
+            } else {
+                Async::NotReady
+            });
+        }
+
         match *self.status.read().unwrap() {
             SendMessageStatus::PendingSend { .. } | SendMessageStatus::PendingAck { .. } => {
                 let mut task = self.task.lock().unwrap();
@@ -70,17 +103,44 @@ impl Future for SendMessage {
 pub enum SendMessageError {
     /// Remote failed to acknowledge the packet.
     FailedAck,
+    /// The message is deprecated or blacklisted by the message template and
+    /// was not sent.
+    NotSendable,
+    /// The encoded packet has the contained number of bytes, more than the
+    /// MTU, and was not sent.
+    TooLarge(usize),
+    /// The message violates a limit of its template and was not sent.
+    Invalid(ValidationError),
+    /// The circuit was closed before the message was sent or acknowledged.
+    Closed,
+    /// The sim stopped responding and the circuit was declared dead, see
+    /// `CircuitConfig::dead_timeout`.
+    RemoteDead,
 }
 
 impl ::std::fmt::Display for SendMessageError {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> Result<(), ::std::fmt::Error> {
-        write!(f, "ack failed.")
+        match *self {
+            SendMessageError::FailedAck => write!(f, "ack failed."),
+            SendMessageError::NotSendable => write!(f, "message not sendable."),
+            SendMessageError::TooLarge(len) => write!(f, "packet too large: {} bytes.", len),
+            SendMessageError::Invalid(ref e) => write!(f, "invalid message: {}", e),
+            SendMessageError::Closed => write!(f, "circuit closed."),
+            SendMessageError::RemoteDead => write!(f, "remote stopped responding."),
+        }
     }
 }
 
 impl ::std::error::Error for SendMessageError {
     fn description(&self) -> &str {
-        "Ack failed."
+        match *self {
+            SendMessageError::FailedAck => "Ack failed.",
+            SendMessageError::NotSendable => "Message is deprecated or blacklisted.",
+            SendMessageError::TooLarge(_) => "Packet is larger than the MTU.",
+            SendMessageError::Invalid(_) => "Message violates its template.",
+            SendMessageError::Closed => "Circuit was closed.",
+            SendMessageError::RemoteDead => "Remote stopped responding.",
+        }
     }
 }
 
@@ -92,10 +152,12 @@ pub enum SendMessageStatus {
     /// Has been sent but not acknowledged yet.
     /// The attempt variant describes the number of attempts already made.
     /// (0 → this is the first attempt, 1 → 2nd attempt, etc.)
+    /// sent: holds the time the current attempt was sent.
     /// timeout: holds the time after which the current attemt is considered
     /// timed out.
     PendingAck {
         attempt: u8,
+        sent: Instant,
         timeout: Instant,
         id: SequenceNumber,
     },
@@ -113,3 +175,47 @@ impl SendMessageStatus {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::{self, Notify};
+
+    struct NoopNotify;
+
+    impl Notify for NoopNotify {
+        fn notify(&self, _: usize) {}
+    }
+
+    fn poll(future: SendMessage) -> Poll<(), SendMessageError> {
+        executor::spawn(future).poll_future_notify(&Arc::new(NoopNotify), 0)
+    }
+
+    #[test]
+    fn split_message_waits_for_all_parts() {
+        let pending = || SendMessageStatus::PendingSend { reliable: true };
+        let mut part1 = SendMessage::new(pending());
+        let mut part2 = SendMessage::new(pending());
+        let future = SendMessage::from_parts(vec![part1.clone(), part2.clone()]);
+
+        assert!(poll(future.clone()).unwrap().is_not_ready());
+        part1.update_status(SendMessageStatus::Success);
+        assert!(poll(future.clone()).unwrap().is_not_ready());
+        part2.update_status(SendMessageStatus::Success);
+        assert!(poll(future).unwrap().is_ready());
+    }
+
+    #[test]
+    fn split_message_fails_with_any_part() {
+        let pending = || SendMessageStatus::PendingSend { reliable: true };
+        let part1 = SendMessage::new(pending());
+        let mut part2 = SendMessage::new(pending());
+        let future = SendMessage::from_parts(vec![part1.clone(), part2.clone()]);
+
+        part2.update_status(SendMessageStatus::Failure(SendMessageError::FailedAck));
+        match poll(future) {
+            Err(SendMessageError::FailedAck) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+}
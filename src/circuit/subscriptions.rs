@@ -0,0 +1,128 @@
+//! Observing received messages without taking them from the handlers, see
+//! `Circuit::subscribe`.
+
+use messages::{MessageInstance, MessageType};
+
+use futures::sync::mpsc::{self, Receiver, Sender};
+use futures::{Poll, Stream};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// The number of messages kept for each subscription until they are read,
+/// newer ones are dropped.
+const SUBSCRIPTION_CAPACITY: usize = 256;
+
+/// A `Stream` of the received messages of one type.
+///
+/// The stream ends once the circuit is closed, dropping it ends the
+/// subscription.
+pub struct Subscription {
+    receiver: Receiver<MessageInstance>,
+}
+
+impl Stream for Subscription {
+    type Item = MessageInstance;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.receiver.poll()
+    }
+}
+
+/// The subscriptions of a circuit, shared with its reader thread.
+#[derive(Clone)]
+pub(crate) struct Subscriptions {
+    senders: Arc<Mutex<HashMap<MessageType, Vec<Sender<MessageInstance>>>>>,
+}
+
+impl Subscriptions {
+    pub fn new() -> Self {
+        Subscriptions {
+            senders: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn subscribe(&self, m_type: MessageType) -> Subscription {
+        let (sender, receiver) = mpsc::channel(SUBSCRIPTION_CAPACITY);
+        self.senders
+            .lock()
+            .unwrap()
+            .entry(m_type)
+            .or_insert_with(Vec::new)
+            .push(sender);
+        Subscription { receiver: receiver }
+    }
+
+    /// Give a copy of the message to all of its subscriptions, dropping the
+    /// ones which ended.
+    ///
+    /// Subscriptions which are not read fast enough miss the message, the
+    /// reader thread never waits for them.
+    pub fn publish(&self, msg: &MessageInstance) {
+        let mut senders = self.senders.lock().unwrap();
+        let m_type = msg.message_type();
+        let ended = match senders.get_mut(&m_type) {
+            Some(subscribers) => {
+                let mut index = 0;
+                while index < subscribers.len() {
+                    match subscribers[index].try_send(msg.clone()) {
+                        Err(ref e) if e.is_disconnected() => {
+                            subscribers.remove(index);
+                        }
+                        // Sent, or dropped because the subscription is full.
+                        _ => index += 1,
+                    }
+                }
+                subscribers.is_empty()
+            }
+            None => return,
+        };
+        if ended {
+            senders.remove(&m_type);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use messages::{PacketAck, StartPingCheck, StartPingCheck_PingID};
+
+    #[test]
+    fn publish() {
+        let subscriptions = Subscriptions::new();
+        let sub1 = subscriptions.subscribe(MessageType::StartPingCheck);
+        let sub2 = subscriptions.subscribe(MessageType::StartPingCheck);
+        let msg: MessageInstance = StartPingCheck {
+            ping_id: StartPingCheck_PingID {
+                ping_id: 1,
+                oldest_unacked: 0,
+            },
+        }
+        .into();
+        subscriptions.publish(&msg);
+        subscriptions.publish(&PacketAck { packets: vec![] }.into());
+        drop(sub2);
+        subscriptions.publish(&msg);
+        drop(subscriptions);
+
+        // Both messages of the type, then the end of the stream.
+        let received: Vec<_> = sub1.wait().collect::<Result<_, _>>().unwrap();
+        assert_eq!(received, vec![msg.clone(), msg]);
+    }
+
+    #[test]
+    fn publish_full() {
+        let subscriptions = Subscriptions::new();
+        let sub = subscriptions.subscribe(MessageType::PacketAck);
+        for _ in 0..SUBSCRIPTION_CAPACITY + 10 {
+            subscriptions.publish(&PacketAck { packets: vec![] }.into());
+        }
+        // The subscription is kept, the surplus messages are dropped.
+        assert_eq!(subscriptions.senders.lock().unwrap().len(), 1);
+        drop(subscriptions);
+
+        // Each sender has one guaranteed slot in addition to the capacity.
+        assert_eq!(sub.wait().count(), SUBSCRIPTION_CAPACITY + 1);
+    }
+}
@@ -0,0 +1,278 @@
+//! Bandwidth budgets of a circuit.
+//!
+//! The budgets are used in two ways: Our outgoing packets are scheduled with
+//! one token bucket per category, and the sim is told to respect them for the
+//! packets it sends us with an `AgentThrottle` message, see
+//! `Throttles::to_message`.
+
+use messages::{AgentThrottle, AgentThrottle_AgentData, AgentThrottle_Throttle, MessageInstance};
+use types::Uuid;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use std::time::{Duration, Instant};
+
+/// The traffic categories of the protocol, in the order they are encoded in
+/// `AgentThrottle`.
+///
+/// The order is also the priority of the categories when scheduling our
+/// outgoing packets, so resends go out before new traffic.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ThrottleCategory {
+    /// Resending unacknowledged packets.
+    Resend = 0,
+    /// `LayerData` terrain.
+    Land = 1,
+    /// `LayerData` wind.
+    Wind = 2,
+    /// `LayerData` clouds.
+    Cloud = 3,
+    /// Everything else, mostly object updates.
+    Task = 4,
+    Texture = 5,
+    Asset = 6,
+}
+
+pub const THROTTLE_CATEGORIES: [ThrottleCategory; 7] = [
+    ThrottleCategory::Resend,
+    ThrottleCategory::Land,
+    ThrottleCategory::Wind,
+    ThrottleCategory::Cloud,
+    ThrottleCategory::Task,
+    ThrottleCategory::Texture,
+    ThrottleCategory::Asset,
+];
+
+impl ThrottleCategory {
+    /// The category of an outgoing message, `None` for acks and pings which
+    /// are never held back since delaying them causes resends and wrong
+    /// round-trip times.
+    pub fn of(message: &MessageInstance, resent: bool) -> Option<ThrottleCategory> {
+        match *message {
+            MessageInstance::PacketAck(_)
+            | MessageInstance::StartPingCheck(_)
+            | MessageInstance::CompletePingCheck(_) => None,
+            _ if resent => Some(ThrottleCategory::Resend),
+            MessageInstance::RequestImage(_) => Some(ThrottleCategory::Texture),
+            MessageInstance::TransferRequest(_)
+            | MessageInstance::AssetUploadRequest(_)
+            | MessageInstance::RequestXfer(_)
+            | MessageInstance::SendXferPacket(_)
+            | MessageInstance::ConfirmXferPacket(_)
+            | MessageInstance::AbortXfer(_) => Some(ThrottleCategory::Asset),
+            _ => Some(ThrottleCategory::Task),
+        }
+    }
+}
+
+/// Bandwidth budget of each category in bits per second.
+///
+/// A budget of zero only applies to the sim, our own packets of such a
+/// category are not held back.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Throttles {
+    pub resend: f32,
+    pub land: f32,
+    pub wind: f32,
+    pub cloud: f32,
+    pub task: f32,
+    pub texture: f32,
+    pub asset: f32,
+}
+
+impl Throttles {
+    /// Split a total budget in bits per second like the viewer does by
+    /// default.
+    pub fn from_total(bits_per_second: f32) -> Self {
+        Throttles {
+            resend: bits_per_second * 0.10,
+            land: bits_per_second * 0.10,
+            wind: bits_per_second * 0.02,
+            cloud: bits_per_second * 0.02,
+            task: bits_per_second * 0.31,
+            texture: bits_per_second * 0.31,
+            asset: bits_per_second * 0.14,
+        }
+    }
+
+    pub fn get(&self, category: ThrottleCategory) -> f32 {
+        match category {
+            ThrottleCategory::Resend => self.resend,
+            ThrottleCategory::Land => self.land,
+            ThrottleCategory::Wind => self.wind,
+            ThrottleCategory::Cloud => self.cloud,
+            ThrottleCategory::Task => self.task,
+            ThrottleCategory::Texture => self.texture,
+            ThrottleCategory::Asset => self.asset,
+        }
+    }
+
+    pub fn total(&self) -> f32 {
+        THROTTLE_CATEGORIES.iter().map(|c| self.get(*c)).sum()
+    }
+
+    /// Create the `AgentThrottle` message telling the sim about the budgets.
+    ///
+    /// `gen_counter` has to increase with every update, so the sim can drop
+    /// outdated ones arriving late.
+    pub fn to_message(
+        &self,
+        agent_id: Uuid,
+        session_id: Uuid,
+        circuit_code: u32,
+        gen_counter: u32,
+    ) -> AgentThrottle {
+        let mut throttles = Vec::with_capacity(4 * THROTTLE_CATEGORIES.len());
+        for category in THROTTLE_CATEGORIES.iter() {
+            // Writing to a Vec never fails.
+            throttles
+                .write_f32::<LittleEndian>(self.get(*category))
+                .unwrap();
+        }
+
+        AgentThrottle {
+            agent_data: AgentThrottle_AgentData {
+                agent_id: agent_id,
+                session_id: session_id,
+                circuit_code: circuit_code,
+            },
+            throttle: AgentThrottle_Throttle {
+                gen_counter: gen_counter,
+                throttles: throttles,
+            },
+        }
+    }
+}
+
+/// The most a bucket can save up while its category is idle, in seconds of
+/// its rate.
+const MAX_BURST: f64 = 0.25;
+
+struct TokenBucket {
+    /// Bytes per second.
+    rate: f64,
+    /// Available bytes, negative after sending a packet larger than the
+    /// remaining budget.
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, now: Instant) {
+        if now > self.updated {
+            let elapsed = now - self.updated;
+            let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+            self.tokens = (self.tokens + elapsed * self.rate).min(self.rate * MAX_BURST);
+            self.updated = now;
+        }
+    }
+}
+
+/// Token buckets for the outgoing packets of a circuit.
+///
+/// A packet can be sent as long as its bucket is not in debt, so a packet
+/// larger than the budget of `MAX_BURST` doesn't block its category forever.
+/// Its size is only taken from the bucket once it was sent, see `consume`.
+pub(crate) struct Throttle {
+    /// `None` if sending is not limited.
+    buckets: Option<Vec<TokenBucket>>,
+}
+
+impl Throttle {
+    pub fn new(throttles: Option<&Throttles>) -> Self {
+        let mut throttle = Throttle { buckets: None };
+        throttle.set(throttles);
+        throttle
+    }
+
+    /// Change the budgets, unused tokens are kept.
+    pub fn set(&mut self, throttles: Option<&Throttles>) {
+        let now = Instant::now();
+        let old = self.buckets.take();
+        self.buckets = throttles.map(|throttles| {
+            THROTTLE_CATEGORIES
+                .iter()
+                .map(|category| {
+                    let rate = throttles.get(*category).max(0.) as f64 / 8.;
+                    let tokens = match old {
+                        Some(ref old) => old[*category as usize].tokens.min(rate * MAX_BURST),
+                        None => rate * MAX_BURST,
+                    };
+                    TokenBucket {
+                        rate: rate,
+                        tokens: tokens,
+                        updated: now,
+                    }
+                })
+                .collect()
+        });
+    }
+
+    /// Whether a packet of the category can be sent now, i.e. its bucket is
+    /// not in debt.
+    ///
+    /// Otherwise returns the time until this is possible.
+    pub fn ready(&mut self, category: ThrottleCategory, now: Instant) -> Result<(), Duration> {
+        let bucket = match self.buckets {
+            Some(ref mut buckets) => &mut buckets[category as usize],
+            None => return Ok(()),
+        };
+        if bucket.rate <= 0. {
+            return Ok(());
+        }
+        bucket.refill(now);
+        if bucket.tokens >= 0. {
+            Ok(())
+        } else {
+            // Round up, so the bucket is not in debt anymore afterwards.
+            let secs = -bucket.tokens / bucket.rate;
+            Err(Duration::new(secs as u64, (secs.fract() * 1e9) as u32 + 1))
+        }
+    }
+
+    /// Take the `len` bytes of a sent packet from the bucket of the category.
+    pub fn consume(&mut self, category: ThrottleCategory, len: usize) {
+        if let Some(ref mut buckets) = self.buckets {
+            buckets[category as usize].tokens -= len as f64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn agent_throttle_message() {
+        let throttles = Throttles::from_total(1000.);
+        assert!((throttles.total() - 1000.).abs() < 0.01);
+
+        let msg = throttles.to_message(Uuid::nil(), Uuid::nil(), 42, 3);
+        assert_eq!(msg.agent_data.circuit_code, 42);
+        assert_eq!(msg.throttle.gen_counter, 3);
+        assert_eq!(msg.throttle.throttles.len(), 28);
+        // Resend comes first, 100 bits per second.
+        assert_eq!(&msg.throttle.throttles[..4], &[0, 0, 0xc8, 0x42]);
+    }
+
+    #[test]
+    fn bucket_debt() {
+        // 80 bits per second, i.e. 10 bytes per second and a burst of 2.5.
+        let mut throttles = Throttles::from_total(0.);
+        throttles.task = 80.;
+        let mut throttle = Throttle::new(Some(&throttles));
+        let now = Instant::now();
+
+        assert_eq!(throttle.ready(ThrottleCategory::Task, now), Ok(()));
+        throttle.consume(ThrottleCategory::Task, 5);
+        let wait = throttle.ready(ThrottleCategory::Task, now).unwrap_err();
+        assert!(wait > Duration::from_millis(249) && wait < Duration::from_millis(251));
+        assert_eq!(throttle.ready(ThrottleCategory::Task, now + wait), Ok(()));
+
+        // Categories without a budget and disabled throttles never wait.
+        throttle.consume(ThrottleCategory::Texture, 1000);
+        assert_eq!(throttle.ready(ThrottleCategory::Texture, now), Ok(()));
+        let mut throttle = Throttle::new(None);
+        throttle.consume(ThrottleCategory::Task, 1000);
+        assert_eq!(throttle.ready(ThrottleCategory::Task, now), Ok(()));
+    }
+}
@@ -125,11 +125,9 @@ pub fn extract_land_patches(
 
     // Read patch_group_header
     let group_header = PatchGroupHeader::read(&mut reader)?;
-    // TODO This assertion should not be nescessary.
-    assert_eq!(
-        group_header.layer_type.land_layer(),
-        Some(expected_layer_type)
-    );
+    if group_header.layer_type.land_layer() != Some(expected_layer_type) {
+        return Err(ExtractSurfaceError::WrongLayerType(group_header.layer_type));
+    }
 
     let mut decoded_patches = Vec::new();
     loop {
@@ -3,6 +3,7 @@
 #![allow(dead_code)]
 #![feature(generators)]
 #![feature(proc_macro_hygiene)]
+#![cfg_attr(test, feature(test))]
 
 extern crate addressable_queue;
 #[macro_use]
@@ -31,6 +32,8 @@ extern crate simple_disk_cache;
 extern crate slog;
 extern crate slog_async;
 extern crate slog_term;
+#[cfg(test)]
+extern crate test;
 extern crate tokio_core;
 extern crate url;
 extern crate xmlrpc;
@@ -49,12 +49,16 @@ pub struct Log {
 pub enum LogLevel {
     /// This logs everything including every received and sent message.
     Debug,
+    /// This logs everything except the messages, which is more reasonable
+    /// for many circuits.
+    Events,
 }
 
 impl Log {
     pub fn new_dir<P: Into<PathBuf>>(dir: P, level: LogLevel) -> Result<Self, IoError> {
         let inner = match level {
-            LogLevel::Debug => Arc::new(DebugDirLogger::new(dir.into())?),
+            LogLevel::Debug => Arc::new(DebugDirLogger::new(dir.into(), true)?),
+            LogLevel::Events => Arc::new(DebugDirLogger::new(dir.into(), false)?),
         };
         Ok(Log { inner: inner })
     }
@@ -101,6 +105,8 @@ trait LogPacket {
 struct DebugDirLogger {
     /// Target directory.
     dir: PathBuf,
+    /// Whether packets are logged or only the text log is written.
+    packets: bool,
 
     recv_counter: AtomicU32Counter,
     send_counter: AtomicU32Counter,
@@ -140,7 +146,7 @@ impl DebugDirLogger {
     /// yourself, but be aware,
     /// that with this uncompressed logging you can quickly accumulate lots of
     /// data.
-    fn new(dir: PathBuf) -> Result<Self, IoError> {
+    fn new(dir: PathBuf, packets: bool) -> Result<Self, IoError> {
         Self::assert_empty_dir(dir.join("recv"))?;
         Self::assert_empty_dir(dir.join("send"))?;
 
@@ -156,6 +162,7 @@ impl DebugDirLogger {
 
         Ok(DebugDirLogger {
             dir: dir,
+            packets: packets,
             recv_counter: AtomicU32Counter::new(0),
             send_counter: AtomicU32Counter::new(0),
             index_recv: Mutex::new(File::create(index_recv_path)?),
@@ -253,11 +260,17 @@ impl slog::Drain for DebugDirLogger {
 
 impl LogPacket for DebugDirLogger {
     fn log_packet_recv(&self, raw_data: &[u8], packet: &Result<Packet, ReadPacketError>) {
+        if !self.packets {
+            return;
+        }
         self.try_log_recv(raw_data, packet)
             .expect("failed logging packet recv.");
     }
 
     fn log_packet_send(&self, raw_data: &[u8], packet: &Packet) {
+        if !self.packets {
+            return;
+        }
         self.try_log_send(raw_data, packet)
             .expect("failed logging message send.");
     }
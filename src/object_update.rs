@@ -3,6 +3,8 @@
 use types::{Quaternion, Uuid, Vector3, Vector4};
 use util::bitsreader::{BytesReader, LittleEndian};
 
+use std::cmp::min;
+
 #[derive(Debug)]
 pub struct ObjectData {
     pub local_id: u32,
@@ -142,7 +144,7 @@ fn read_uuid<R: BytesReader>(reader: &mut R) -> Result<Uuid, ::util::bitsreader:
 
 // TODO: It's really bad that the Read bound has to be added here, but
 // otherwise it really does not work due to some issue with generics.
-fn read_texture_entry<R: BytesReader + ::std::io::Read>(
+pub fn read_texture_entry<R: BytesReader + ::std::io::Read>(
     reader: &mut R,
 ) -> Result<TextureEntry, ::util::bitsreader::ReadError> {
     // TODO
@@ -161,9 +163,13 @@ fn read_texture_entry<R: BytesReader + ::std::io::Read>(
                 }
 
                 let value = $read;
-                for i in 0..bitset_size {
-                    if i >= partial.len() {
-                        partial[i] = PartialFaceProperties::new();
+                // Only 32 faces fit into the bitset.
+                for i in 0..min(bitset_size, 32) {
+                    if bitset & (1 << i) == 0 {
+                        continue;
+                    }
+                    while i >= partial.len() {
+                        partial.push(PartialFaceProperties::new());
                     }
                     partial[i].$f_name = Some(value.clone());
                 }
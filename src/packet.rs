@@ -1,10 +1,23 @@
-use messages::MessageInstance;
+use messages::template::{DynamicMessage, MessageTemplates};
+use messages::{MessageInstance, ValidationError, WriteError};
 use types::SequenceNumber;
 
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
 use failure::Fail;
 use std::io::{Read, Write};
 
+/// Size of the packet header, not including extra header bytes.
+pub(crate) const HEADER_SIZE: usize = 6;
+
+/// Maximum size a message may have after zerocoding was expanded.
+const MAX_MESSAGE_SIZE: usize = 8192;
+
+/// Maximum size of a packet we send, including header and appended acks.
+///
+/// This is the value used by the viewers (`MTUBYTES`), larger packets risk
+/// being fragmented or dropped on the way.
+pub const MTU: usize = 1200;
+
 bitflags! {
     pub struct PacketFlags: u8 {
         /// There are acks appended to the packet.
@@ -39,6 +52,10 @@ pub struct Packet {
     /// (including zero)
     /// of elements.
     pub appended_acks: Vec<SequenceNumber>,
+
+    /// Length of the zerocoded message, remembered by `zerocode_if_smaller`
+    /// so `encoded_len` doesn't have to encode the message again.
+    zerocoded_len: Option<usize>,
 }
 
 impl Packet {
@@ -48,27 +65,41 @@ impl Packet {
             flags: PacketFlags::empty(),
             sequence_number: seq_number,
             appended_acks: Vec::new(),
+            zerocoded_len: None,
         }
     }
 
     /// Write the packet (including both body and header) to a buffer
     /// in its current form.
     ///
+    /// Nothing is written if the message is not valid according to its
+    /// template.
+    ///
     /// # Protocol documentation
     /// * http://lib.openmetaverse.co/wiki/Protocol_(network)
     /// * http://wiki.secondlife.com/wiki/Packet_Layout
-    pub fn write_to<W: Write>(&self, buffer: &mut W) -> Result<(), ::std::io::Error> {
+    pub fn write_to<W: Write>(&self, buffer: &mut W) -> Result<(), WritePacketError> {
+        self.message.validate()?;
+        self.write_unvalidated(buffer)
+    }
+
+    /// Write the packet like `write_to`, but without validating the message.
+    fn write_unvalidated<W: Write>(&self, buffer: &mut W) -> Result<(), WritePacketError> {
         // Assert: PACKET_APPENDED_ACKS flag set <-> self.appended_acks is empty.
         debug_assert!(
             !(self.flags.contains(PacketFlags::APPENDED_ACKS) && self.appended_acks.is_empty())
         );
-        // TODO: Zero coded writing not implemented yet.
-        assert!(!self.flags.contains(PacketFlags::ZEROCODED));
-
         buffer.write_u8(self.flags.bits())?;
         buffer.write_u32::<BigEndian>(self.sequence_number)?;
         buffer.write(&[0])?;
-        self.message.write_to(buffer)?;
+        if self.flags.contains(PacketFlags::ZEROCODED) {
+            // Only the message is zerocoded, header and appended acks are not.
+            let mut writer = ZerocodedWriter::new(&mut *buffer);
+            self.message.write_unvalidated(&mut writer)?;
+            writer.finish()?;
+        } else {
+            self.message.write_unvalidated(buffer)?;
+        }
         for ack in &self.appended_acks {
             buffer.write_u32::<BigEndian>(*ack)?;
         }
@@ -78,48 +109,118 @@ impl Packet {
         Ok(())
     }
 
-    /// Try to read a packet from the provided buffer.
+    /// Write the packet to the start of `buffer`, returning the number of
+    /// bytes written.
     ///
-    /// Note: Pass a slice only containing the relevant bytes, truncate all
-    /// other noise
-    /// from previous reads etc from the slice!
-    pub fn read<'a>(buf: &'a [u8]) -> Result<Packet, ReadPacketError> {
-        let mut reader = PacketReader::new(buf);
+    /// Fails without writing anything if the packet is larger than the
+    /// buffer or the `MTU`, so a `[u8; MTU]` can be reused for all packets.
+    pub fn write_to_slice(&self, buffer: &mut [u8]) -> Result<usize, WritePacketError> {
+        self.message.validate()?;
+        self.write_to_slice_unvalidated(buffer)
+    }
 
-        let flags = PacketFlags::from_bits(reader.read_u8()?).unwrap();
-        let sequence_num = reader.read_u32::<BigEndian>()?;
+    /// Write the packet like `write_to_slice`, but without validating the
+    /// message, for packets whose message was validated before.
+    pub(crate) fn write_to_slice_unvalidated(
+        &self,
+        buffer: &mut [u8],
+    ) -> Result<usize, WritePacketError> {
+        let len = self.check_len(buffer.len())?;
+        self.write_unvalidated(&mut &mut buffer[..len])?;
+        Ok(len)
+    }
 
-        // Skip extra header if present, since we don't expect it.
-        let extra_bytes = reader.read_u8()? as usize;
-        if extra_bytes > 0 {
-            reader.skip_bytes(extra_bytes)?;
-        }
+    /// Write the packet to `buffer`, replacing its previous content and
+    /// reusing its allocation. Returns the number of bytes written.
+    ///
+    /// Fails without writing anything if the packet is larger than the `MTU`.
+    pub fn write_to_vec(&self, buffer: &mut Vec<u8>) -> Result<usize, WritePacketError> {
+        self.message.validate()?;
+        let len = self.check_len(MTU)?;
+        buffer.clear();
+        buffer.reserve(len);
+        self.write_unvalidated(buffer)?;
+        Ok(len)
+    }
 
-        // Read message.
-        if flags.contains(PacketFlags::ZEROCODED) {
-            reader.zerocoding_enabled = true;
+    fn check_len(&self, max: usize) -> Result<usize, WritePacketError> {
+        let len = self.encoded_len();
+        let max = ::std::cmp::min(max, MTU);
+        if len > max {
+            Err(WritePacketError::TooLarge { len: len, max: max })
+        } else {
+            Ok(len)
         }
-        let message_num = reader.read_message_number()?;
-        let message = MessageInstance::read_message(&mut reader, message_num)?;
+    }
 
-        // Read appended ACKs if there are supposed to be any.
-        let mut acks = Vec::new();
-        if flags.contains(PacketFlags::APPENDED_ACKS) {
-            let n_acks = reader.peek_last_byte() as usize;
-            acks.reserve(n_acks);
-            for _ in 0..n_acks {
-                acks.push(reader.read_u32::<BigEndian>()?);
+    /// Returns the number of bytes `write_to` writes for the packet in its
+    /// current form.
+    ///
+    /// This doesn't allocate, but for zerocoded packets the message has to be
+    /// encoded to determine its length, unless `zerocode_if_smaller` did so
+    /// already.
+    pub fn encoded_len(&self) -> usize {
+        let body_len = if self.flags.contains(PacketFlags::ZEROCODED) {
+            match self.zerocoded_len {
+                Some(len) => len,
+                None => zerocoded_len(&self.message),
             }
-        }
+        } else {
+            self.message.encoded_len()
+        };
+        let acks_len = if self.appended_acks.is_empty() {
+            0
+        } else {
+            self.appended_acks.len() * 4 + 1
+        };
+        HEADER_SIZE + body_len + acks_len
+    }
+
+    /// Try to read a packet from the provided buffer.
+    ///
+    /// Note: Pass a slice only containing the relevant bytes, truncate all
+    /// other noise
+    /// from previous reads etc from the slice!
+    pub fn read<'a>(buf: &'a [u8]) -> Result<Packet, ReadPacketError> {
+        let parts = PacketParts::read(buf)?;
+        let message = parts.read_body(|reader, message_num| {
+            Ok(MessageInstance::read_message(reader, message_num)?)
+        })?;
 
         Ok(Packet {
             message: message,
-            flags: flags,
-            sequence_number: sequence_num,
-            appended_acks: acks,
+            flags: parts.flags,
+            sequence_number: parts.sequence_number,
+            appended_acks: parts.appended_acks,
+            zerocoded_len: None,
         })
     }
 
+    /// Enable zerocoding for this packet if this makes it smaller, otherwise
+    /// disable it.
+    ///
+    /// Returns true if zerocoding was enabled, fails if the message is not
+    /// valid according to its template. The zerocoded length is remembered
+    /// for `encoded_len`, so the message must not be changed afterwards.
+    pub fn zerocode_if_smaller(&mut self) -> Result<bool, WritePacketError> {
+        self.message.validate()?;
+        Ok(self.zerocode_if_smaller_unvalidated())
+    }
+
+    /// Zerocode the packet like `zerocode_if_smaller`, but without validating
+    /// the message, for packets whose message was validated before.
+    pub(crate) fn zerocode_if_smaller_unvalidated(&mut self) -> bool {
+        let zerocoded_len = zerocoded_len(&self.message);
+        self.zerocoded_len = Some(zerocoded_len);
+        let smaller = zerocoded_len < self.message.encoded_len();
+        if smaller {
+            self.enable_flags(PacketFlags::ZEROCODED);
+        } else {
+            self.disable_flags(PacketFlags::ZEROCODED);
+        }
+        smaller
+    }
+
     /// Enable the provided flags.
     pub fn enable_flags(&mut self, flags: PacketFlags) {
         self.flags.insert(flags);
@@ -157,6 +258,21 @@ pub enum ReadPacketError {
 
     #[fail(display = "Reading the received message failed: {}", 0)]
     ReadError(::messages::ReadError),
+
+    #[fail(display = "The packet is too short to contain a header.")]
+    TruncatedHeader,
+
+    #[fail(display = "Invalid packet flags: {:#010b}", _0)]
+    BadFlags(u8),
+
+    #[fail(display = "The packet is too short to contain {} appended acks.", _0)]
+    AckCountOverrun(usize),
+
+    #[fail(display = "The message was followed by {} unexpected bytes.", _0)]
+    TrailingGarbage(usize),
+
+    #[fail(display = "The zerocoded message expands beyond the maximum message size.")]
+    ZerocodeOverflow,
 }
 
 impl From<::std::io::Error> for ReadPacketError {
@@ -171,6 +287,246 @@ impl From<::messages::ReadError> for ReadPacketError {
     }
 }
 
+#[derive(Debug, Fail)]
+pub enum WritePacketError {
+    #[fail(display = "There was an IO error while writing the data: {}", _0)]
+    IoError(#[cause] ::std::io::Error),
+
+    #[fail(display = "The packet has {} bytes, only {} are allowed.", len, max)]
+    TooLarge { len: usize, max: usize },
+
+    #[fail(display = "The message is invalid: {}", _0)]
+    Invalid(#[cause] ValidationError),
+}
+
+impl From<::std::io::Error> for WritePacketError {
+    fn from(e: ::std::io::Error) -> Self {
+        WritePacketError::IoError(e)
+    }
+}
+
+impl From<ValidationError> for WritePacketError {
+    fn from(e: ValidationError) -> Self {
+        WritePacketError::Invalid(e)
+    }
+}
+
+impl From<WriteError> for WritePacketError {
+    fn from(e: WriteError) -> Self {
+        match e {
+            WriteError::IoError(e) => WritePacketError::IoError(e),
+            WriteError::Invalid(e) => WritePacketError::Invalid(e),
+        }
+    }
+}
+
+/// Returns the length of the message after zerocoding.
+///
+/// The message is not validated, the result is meaningless for messages
+/// which are not valid according to their template.
+pub(crate) fn zerocoded_len(message: &MessageInstance) -> usize {
+    let mut writer = ZerocodedWriter::new(::std::io::sink());
+    // Writing to a sink never fails.
+    message.write_unvalidated(&mut writer).unwrap();
+    writer.finish().unwrap()
+}
+
+/// A packet containing a message which is not known at compile time, decoded
+/// with a template loaded at runtime instead.
+#[derive(Debug)]
+pub struct DynamicPacket {
+    pub message: DynamicMessage,
+    pub flags: PacketFlags,
+    pub sequence_number: SequenceNumber,
+    pub appended_acks: Vec<SequenceNumber>,
+}
+
+impl DynamicPacket {
+    /// Try to read a packet from the provided buffer, decoding the message
+    /// with the provided templates.
+    pub fn read<'a>(
+        buf: &'a [u8],
+        templates: &MessageTemplates,
+    ) -> Result<DynamicPacket, ReadPacketError> {
+        let parts = PacketParts::read(buf)?;
+        let message = parts
+            .read_body(|reader, message_num| Ok(templates.read_message(reader, message_num)?))?;
+
+        Ok(DynamicPacket {
+            message: message,
+            flags: parts.flags,
+            sequence_number: parts.sequence_number,
+            appended_acks: parts.appended_acks,
+        })
+    }
+
+    pub fn is_reliable(&self) -> bool {
+        self.flags.contains(PacketFlags::RELIABLE)
+    }
+}
+
+/// The header and appended acks of a packet, which can be read even if its
+/// message can't be decoded.
+#[derive(Debug)]
+pub struct PacketHeader {
+    pub flags: PacketFlags,
+    pub sequence_number: SequenceNumber,
+    pub appended_acks: Vec<SequenceNumber>,
+}
+
+impl PacketHeader {
+    /// Read the header and the appended acks of the packet in the buffer,
+    /// without looking at the message.
+    pub fn read(buf: &[u8]) -> Result<PacketHeader, ReadPacketError> {
+        let parts = PacketParts::read(buf)?;
+        Ok(PacketHeader {
+            flags: parts.flags,
+            sequence_number: parts.sequence_number,
+            appended_acks: parts.appended_acks,
+        })
+    }
+
+    pub fn is_reliable(&self) -> bool {
+        self.flags.contains(PacketFlags::RELIABLE)
+    }
+}
+
+/// Header and appended acks of a packet, separated from the message body.
+struct PacketParts<'a> {
+    flags: PacketFlags,
+    sequence_number: SequenceNumber,
+    appended_acks: Vec<SequenceNumber>,
+    body: &'a [u8],
+}
+
+impl<'a> PacketParts<'a> {
+    fn read(buf: &'a [u8]) -> Result<Self, ReadPacketError> {
+        // Read the header.
+        if buf.len() < HEADER_SIZE {
+            return Err(ReadPacketError::TruncatedHeader);
+        }
+        let flags = PacketFlags::from_bits(buf[0]).ok_or(ReadPacketError::BadFlags(buf[0]))?;
+        let sequence_number = BigEndian::read_u32(&buf[1..5]);
+
+        // Skip extra header if present, since we don't expect it.
+        let body_start = HEADER_SIZE + buf[5] as usize;
+        if buf.len() < body_start {
+            return Err(ReadPacketError::TruncatedHeader);
+        }
+
+        // Read appended ACKs if there are supposed to be any.
+        // They are located at the end of the packet and never zerocoded,
+        // so they have to be split off before reading the message.
+        let mut acks = Vec::new();
+        let mut body_end = buf.len();
+        if flags.contains(PacketFlags::APPENDED_ACKS) {
+            let n_acks = buf[buf.len() - 1] as usize;
+            let acks_size = n_acks * 4 + 1;
+            if body_start + acks_size > buf.len() {
+                return Err(ReadPacketError::AckCountOverrun(n_acks));
+            }
+            body_end = buf.len() - acks_size;
+            acks = buf[body_end..buf.len() - 1]
+                .chunks(4)
+                .map(BigEndian::read_u32)
+                .collect();
+        }
+
+        Ok(PacketParts {
+            flags: flags,
+            sequence_number: sequence_number,
+            appended_acks: acks,
+            body: &buf[body_start..body_end],
+        })
+    }
+
+    /// Read the message number and then the message using the provided
+    /// function.
+    fn read_body<M, F>(&self, read_message: F) -> Result<M, ReadPacketError>
+    where
+        F: FnOnce(&mut PacketReader, u32) -> Result<M, ReadPacketError>,
+    {
+        let mut reader = PacketReader::new(self.body);
+        reader.zerocoding_enabled = self.flags.contains(PacketFlags::ZEROCODED);
+        let message = match reader
+            .read_message_number()
+            .map_err(ReadPacketError::from)
+            .and_then(|message_num| read_message(&mut reader, message_num))
+        {
+            Ok(msg) => msg,
+            Err(_) if reader.overflow => return Err(ReadPacketError::ZerocodeOverflow),
+            Err(e) => return Err(e),
+        };
+
+        // Make sure the message consumed the whole body.
+        let remaining = reader.remaining();
+        if remaining > 0 {
+            return Err(ReadPacketError::TrailingGarbage(remaining));
+        }
+        Ok(message)
+    }
+}
+
+/// Zerocodes everything written to it before passing it on to the inner
+/// writer.
+///
+/// Each run of zero bytes is replaced by a single zero byte followed by the
+/// length of the run, runs longer than 255 bytes are split up. The last run
+/// is only written by `finish`.
+struct ZerocodedWriter<W: Write> {
+    inner: W,
+    zeros: u8,
+    written: usize,
+}
+
+impl<W: Write> ZerocodedWriter<W> {
+    fn new(inner: W) -> Self {
+        ZerocodedWriter {
+            inner: inner,
+            zeros: 0,
+            written: 0,
+        }
+    }
+
+    fn flush_zeros(&mut self) -> Result<(), ::std::io::Error> {
+        if self.zeros > 0 {
+            self.inner.write_all(&[0, self.zeros])?;
+            self.written += 2;
+            self.zeros = 0;
+        }
+        Ok(())
+    }
+
+    /// Write the pending run of zeros, returns the total number of bytes
+    /// written to the inner writer.
+    fn finish(mut self) -> Result<usize, ::std::io::Error> {
+        self.flush_zeros()?;
+        Ok(self.written)
+    }
+}
+
+impl<W: Write> Write for ZerocodedWriter<W> {
+    fn write(&mut self, data: &[u8]) -> Result<usize, ::std::io::Error> {
+        for &byte in data {
+            if byte == 0 {
+                if self.zeros == 255 {
+                    self.flush_zeros()?;
+                }
+                self.zeros += 1;
+            } else {
+                self.flush_zeros()?;
+                self.inner.write_u8(byte)?;
+                self.written += 1;
+            }
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> Result<(), ::std::io::Error> {
+        self.inner.flush()
+    }
+}
+
 /// Used internally to read the content of packages.
 /// Provides transparent reading of zerocoded content.
 /// Memory: O(1)
@@ -185,6 +541,10 @@ struct PacketReader<'a> {
     /// are yet pending
     /// to be read on the next invocation of read.
     pending_zerobytes: u8,
+    /// Number of bytes read so far (after zerocoding was expanded).
+    expanded: usize,
+    /// Set if the expanded message would exceed `MAX_MESSAGE_SIZE`.
+    overflow: bool,
 }
 
 impl<'a> PacketReader<'a> {
@@ -194,17 +554,19 @@ impl<'a> PacketReader<'a> {
             pointer: 0,
             zerocoding_enabled: false,
             pending_zerobytes: 0,
+            expanded: 0,
+            overflow: false,
         }
     }
 
-    /// Return the content of the very last byte of the message.
-    fn peek_last_byte(&self) -> u8 {
-        self.buf[self.buf.len() - 1]
+    /// Returns the number of bytes which were not read yet.
+    fn remaining(&self) -> usize {
+        self.buf.len() - self.pointer + self.pending_zerobytes as usize
     }
 
     #[inline]
     fn has_index(&self, index: usize) -> bool {
-        (self.buf.len() - index) > 0
+        index < self.buf.len()
     }
 
     /// Skips the provided number of bytes.
@@ -254,13 +616,20 @@ impl<'a> Read for PacketReader<'a> {
 
             // Try to read as many bytes as requested.
             while buf.len() - read_bytes > 0 {
-                if self.pending_zerobytes > 0 {
+                let has_byte = self.pending_zerobytes > 0 || self.has_index(self.pointer);
+                if has_byte && self.expanded + read_bytes + 1 > MAX_MESSAGE_SIZE {
+                    self.overflow = true;
+                    return Err(::std::io::Error::new(
+                        ::std::io::ErrorKind::InvalidData,
+                        "Zerocoded message expands beyond the maximum message size.",
+                    ));
+                } else if self.pending_zerobytes > 0 {
                     buf[read_bytes] = 0;
                     self.pending_zerobytes -= 1;
                     read_bytes += 1;
                 } else if !self.has_index(self.pointer) {
                     // The reader has read through all of the buffer.
-                    return Ok(read_bytes);
+                    break;
                 } else {
                     if self.buf[self.pointer] == 0 {
                         if self.has_index(self.pointer + 1) {
@@ -282,6 +651,7 @@ impl<'a> Read for PacketReader<'a> {
                 }
             }
 
+            self.expanded += read_bytes;
             Ok(read_bytes)
         } else {
             // Determine the number of bytes to be read.
@@ -303,6 +673,18 @@ impl<'a> Read for PacketReader<'a> {
 mod tests {
     use super::*;
 
+    fn write_zerocoded<W: Write>(data: &[u8], buffer: &mut W) -> Result<(), ::std::io::Error> {
+        let mut writer = ZerocodedWriter::new(buffer);
+        writer.write_all(data)?;
+        writer.finish().map(|_| ())
+    }
+
+    fn zerocoded_len(data: &[u8]) -> usize {
+        let mut writer = ZerocodedWriter::new(::std::io::sink());
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
     #[test]
     fn read_simple() {
         let data: [u8; 6] = [2, 4, 6, 8, 10, 12];
@@ -360,6 +742,273 @@ mod tests {
         assert_eq!(buffer, [0, 0, 1]);
     }
 
+    #[test]
+    fn write_zerocoded_runs() {
+        let data = [1, 0, 0, 0, 2, 0];
+        let mut buffer = Vec::new();
+        write_zerocoded(&data, &mut buffer).unwrap();
+        assert_eq!(buffer, vec![1, 0, 3, 2, 0, 1]);
+        assert_eq!(zerocoded_len(&data), buffer.len());
+    }
+
+    #[test]
+    fn write_zerocoded_long_run() {
+        let data = [0u8; 300];
+        let mut buffer = Vec::new();
+        write_zerocoded(&data, &mut buffer).unwrap();
+        assert_eq!(buffer, vec![0, 255, 0, 45]);
+        assert_eq!(zerocoded_len(&data), buffer.len());
+
+        let mut reader = PacketReader::new(&buffer);
+        reader.zerocoding_enabled = true;
+        let mut decoded = Vec::new();
+        reader.read_to_end(&mut decoded).unwrap();
+        assert_eq!(&decoded[..], &data[..]);
+    }
+
+    #[test]
+    fn packet_zerocoded_roundtrip() {
+        use messages::all::{UseCircuitCode, UseCircuitCode_CircuitCode};
+        use types::Uuid;
+
+        let message = UseCircuitCode {
+            circuit_code: UseCircuitCode_CircuitCode {
+                code: 42,
+                session_id: Uuid::nil(),
+                id: Uuid::nil(),
+            },
+        };
+        let mut packet = Packet::new(message, 7);
+        packet.appended_acks = vec![1, 2];
+        packet.enable_flags(PacketFlags::APPENDED_ACKS);
+        assert!(packet.zerocode_if_smaller().unwrap());
+
+        let mut plain = Vec::new();
+        Packet::new(packet.message.clone(), 7)
+            .write_to(&mut plain)
+            .unwrap();
+        let mut buffer = Vec::new();
+        packet.write_to(&mut buffer).unwrap();
+        assert!(buffer.len() < plain.len());
+        assert_eq!(packet.encoded_len(), buffer.len());
+
+        let read = Packet::read(&buffer).unwrap();
+        assert!(read.has_flag(PacketFlags::ZEROCODED));
+        assert_eq!(read.sequence_number, 7);
+        assert_eq!(read.appended_acks, vec![1, 2]);
+        match read.message {
+            MessageInstance::UseCircuitCode(msg) => {
+                assert_eq!(msg.circuit_code.code, 42);
+                assert_eq!(msg.circuit_code.session_id, Uuid::nil());
+            }
+            _ => panic!("wrong message type"),
+        }
+    }
+
+    #[test]
+    fn packet_encoded_len() {
+        use messages::all::{UseCircuitCode, UseCircuitCode_CircuitCode};
+        use types::Uuid;
+
+        let message = UseCircuitCode {
+            circuit_code: UseCircuitCode_CircuitCode {
+                code: 42,
+                session_id: Uuid::nil(),
+                id: Uuid::nil(),
+            },
+        };
+        let mut packet = Packet::new(message, 7);
+        let mut buffer = Vec::new();
+        packet.write_to(&mut buffer).unwrap();
+        assert_eq!(packet.encoded_len(), buffer.len());
+
+        packet.appended_acks = vec![1, 2];
+        packet.enable_flags(PacketFlags::APPENDED_ACKS);
+        packet.enable_flags(PacketFlags::ZEROCODED);
+        buffer.clear();
+        packet.write_to(&mut buffer).unwrap();
+        assert_eq!(packet.encoded_len(), buffer.len());
+
+        let mut slice = [0u8; MTU];
+        assert_eq!(packet.write_to_slice(&mut slice).unwrap(), buffer.len());
+        assert_eq!(&slice[..buffer.len()], &buffer[..]);
+        let mut vec = vec![0xff; 2000];
+        assert_eq!(packet.write_to_vec(&mut vec).unwrap(), buffer.len());
+        assert_eq!(vec, buffer);
+    }
+
+    #[test]
+    fn packet_too_large() {
+        use messages::all::{ChatFromViewer, ChatFromViewer_AgentData, ChatFromViewer_ChatData};
+        use types::Uuid;
+
+        let message = ChatFromViewer {
+            agent_data: ChatFromViewer_AgentData {
+                agent_id: Uuid::nil(),
+                session_id: Uuid::nil(),
+            },
+            chat_data: ChatFromViewer_ChatData {
+                message: vec![b'a'; 2000],
+                type_: 1,
+                channel: 0,
+            },
+        };
+        let packet = Packet::new(message, 1);
+        let len = packet.encoded_len();
+        assert!(len > MTU);
+
+        let mut slice = [0u8; MTU];
+        match packet.write_to_slice(&mut slice) {
+            Err(WritePacketError::TooLarge { len: l, max: MTU }) => assert_eq!(l, len),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        match packet.write_to_slice(&mut slice[..10]) {
+            Err(WritePacketError::TooLarge { max: 10, .. }) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        let mut vec = vec![1, 2, 3];
+        assert!(packet.write_to_vec(&mut vec).is_err());
+        assert_eq!(vec, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn packet_invalid_message() {
+        use messages::all::{PacketAck, PacketAck_Packets};
+        use messages::ValidationError;
+
+        let message = PacketAck {
+            packets: (0..256).map(|id| PacketAck_Packets { id: id }).collect(),
+        };
+        let mut packet = Packet::new(message, 1);
+        packet.enable_flags(PacketFlags::ZEROCODED);
+
+        let mut buffer = Vec::new();
+        match packet.write_to(&mut buffer) {
+            Err(WritePacketError::Invalid(ValidationError::TooManyBlocks {
+                count: 256, ..
+            })) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert!(buffer.is_empty());
+        assert!(packet.zerocode_if_smaller().is_err());
+    }
+
+    #[test]
+    fn packet_truncated_header() {
+        match Packet::read(&[]) {
+            Err(ReadPacketError::TruncatedHeader) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        // Extra header length pointing behind the end of the packet.
+        match Packet::read(&[0, 0, 0, 0, 1, 10, 0xff]) {
+            Err(ReadPacketError::TruncatedHeader) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn packet_bad_flags() {
+        match Packet::read(&[0x01, 0, 0, 0, 1, 0, 0xff]) {
+            Err(ReadPacketError::BadFlags(0x01)) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn packet_ack_count_overrun() {
+        // StartPingCheck claiming 200 appended acks.
+        let data = [0x10, 0, 0, 0, 1, 0, 0x01, 0, 0, 0, 0, 0, 200];
+        match Packet::read(&data) {
+            Err(ReadPacketError::AckCountOverrun(200)) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn packet_trailing_garbage() {
+        // StartPingCheck followed by two extra bytes.
+        let data = [0, 0, 0, 0, 1, 0, 0x01, 5, 0, 0, 0, 0, 0xaa, 0xbb];
+        match Packet::read(&data) {
+            Err(ReadPacketError::TrailingGarbage(2)) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert!(Packet::read(&data[..12]).is_ok());
+    }
+
+    #[test]
+    fn packet_zerocode_overflow() {
+        // ObjectUpdate with 255 blocks consisting only of zero runs.
+        let mut data = vec![0x80, 0, 0, 0, 1, 0, 0x0c, 0, 10, 0xff];
+        for _ in 0..100 {
+            data.extend_from_slice(&[0, 255]);
+        }
+        match Packet::read(&data) {
+            Err(ReadPacketError::ZerocodeOverflow) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn reader_max_message_size() {
+        // Runs of zeros expanding to exactly the maximum message size.
+        let mut data = Vec::new();
+        for _ in 0..MAX_MESSAGE_SIZE / 256 {
+            data.extend_from_slice(&[0, 255]);
+        }
+        data.extend_from_slice(&[0, (MAX_MESSAGE_SIZE / 256) as u8]);
+        let mut reader = PacketReader::new(&data);
+        reader.zerocoding_enabled = true;
+        let mut decoded = Vec::new();
+        reader.read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded.len(), MAX_MESSAGE_SIZE);
+
+        // One more byte is too much.
+        data.push(1);
+        let mut reader = PacketReader::new(&data);
+        reader.zerocoding_enabled = true;
+        assert!(reader.read_to_end(&mut Vec::new()).is_err());
+        assert!(reader.overflow);
+    }
+
+    #[test]
+    fn dynamic_packet_unknown_message() {
+        use messages::template::FieldValue;
+
+        let templates = MessageTemplates::parse(
+            "{
+                FutureMessage Low 60000 NotTrusted Zerocoded
+                {
+                    Data Single
+                    {   Value   U32 }
+                }
+            }",
+        )
+        .unwrap();
+
+        // Reliable and zerocoded, with one appended ack.
+        let data = [0xd0, 0, 0, 0, 3, 0, 0xff, 0xff, 0xea, 0x60, 0x2a, 0, 3, 0, 0, 0, 9, 1];
+        match Packet::read(&data) {
+            Err(ReadPacketError::ReadError(::messages::ReadError::UnknownMessageNumber(_))) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        // The header can be read without the template.
+        let header = PacketHeader::read(&data).unwrap();
+        assert!(header.is_reliable());
+        assert_eq!(header.sequence_number, 3);
+        assert_eq!(header.appended_acks, vec![9]);
+
+        let packet = DynamicPacket::read(&data, &templates).unwrap();
+        assert!(packet.is_reliable());
+        assert_eq!(packet.sequence_number, 3);
+        assert_eq!(packet.appended_acks, vec![9]);
+        assert_eq!(packet.message.name, "FutureMessage");
+        assert_eq!(
+            packet.message.field("Data", 0, "Value"),
+            Some(&FieldValue::U32(42))
+        );
+    }
+
     #[test]
     fn reader_skip() {
         let data: [u8; 6] = [0, 1, 2, 3, 4, 5];
@@ -1,11 +1,17 @@
 use capabilities::Capabilities;
 use circuit::{message_handlers, MessageSender};
+use futures::sync::oneshot;
 use logging::Log;
-use std::cell::RefCell;
-use std::sync::Arc;
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 use types::Uuid;
 
-pub trait Service {
+/// A service handling a domain of messages of a sim.
+///
+/// Services are shared between the reader thread of the circuit and the
+/// application, so they have to be usable from any thread.
+pub trait Service: Send + Sync {
     fn register_service(
         handlers: &mut message_handlers::Handlers,
         circuit_data: CircuitDataHandle,
@@ -16,29 +22,132 @@ pub trait Service {
 /// Provides acess to the CircuitData once it is available.
 ///
 /// The reason it has to be passed like this is that it will not be available
-/// when registering the services. It is guaranteed that the value will be
-/// available when handlers are called.
+/// when registering the services, and only becomes available once the sim
+/// accepted the circuit. Handlers called before that, or application threads,
+/// can wait for it with `wait`.
 #[derive(Clone)]
-pub struct CircuitDataHandle(Arc<RefCell<Option<Arc<CircuitData>>>>);
+pub struct CircuitDataHandle(Arc<Mutex<CircuitDataState>>);
+
+struct CircuitDataState {
+    data: Option<Arc<CircuitData>>,
+    waiting: Vec<oneshot::Sender<Arc<CircuitData>>>,
+}
 
 impl CircuitDataHandle {
     pub(crate) fn new() -> Self {
-        CircuitDataHandle(Arc::new(RefCell::new(None)))
+        CircuitDataHandle(Arc::new(Mutex::new(CircuitDataState {
+            data: None,
+            waiting: Vec::new(),
+        })))
     }
 
+    /// Make the CircuitData available, only the first call has an effect.
     pub(crate) fn set(&self, data: CircuitData) {
-        self.0.replace(Some(Arc::new(data)));
+        let mut state = self.0.lock().unwrap();
+        if state.data.is_some() {
+            return;
+        }
+        let data = Arc::new(data);
+        for sender in state.waiting.drain(..) {
+            let _ = sender.send(Arc::clone(&data));
+        }
+        state.data = Some(data);
     }
 
-    /// Return the available CircuitData.
-    ///
-    /// # Panics
+    /// Return the CircuitData if it is available already.
+    pub fn get(&self) -> Option<Arc<CircuitData>> {
+        self.0.lock().unwrap().data.clone()
+    }
+
+    /// Wait until the CircuitData is available.
     ///
-    /// If data is not yet available. This should not happen outside the
-    /// register_service methods.
-    pub fn unwrap(&self) -> Arc<CircuitData> {
-        let option = self.0.borrow();
-        Arc::clone(option.as_ref().unwrap())
+    /// The future is resolved immediately if it is available already. It
+    /// fails with `Canceled` if the connection to the sim failed, so the data
+    /// will never be available.
+    pub fn wait(&self) -> oneshot::Receiver<Arc<CircuitData>> {
+        let (sender, receiver) = oneshot::channel();
+        let mut state = self.0.lock().unwrap();
+        match state.data {
+            Some(ref data) => {
+                let _ = sender.send(Arc::clone(data));
+            }
+            None => state.waiting.push(sender),
+        }
+        receiver
+    }
+}
+
+type RegisterFn =
+    fn(&mut message_handlers::Handlers, CircuitDataHandle, &Log) -> Box<Any + Send + Sync>;
+
+fn register_boxed<S: Service + 'static>(
+    handlers: &mut message_handlers::Handlers,
+    circuit_data: CircuitDataHandle,
+    log: &Log,
+) -> Box<Any + Send + Sync> {
+    Box::new(S::register_service(handlers, circuit_data, log))
+}
+
+/// The services to register when connecting to a sim, see
+/// `Simulator::connect`.
+///
+/// The services of this crate are always included, services implemented
+/// elsewhere can be added with `register`.
+pub struct ServicesBuilder {
+    registrations: Vec<(TypeId, RegisterFn)>,
+}
+
+impl ServicesBuilder {
+    pub fn new() -> Self {
+        let mut builder = ServicesBuilder {
+            registrations: Vec::new(),
+        };
+        builder.register::<region_handle::LookupService>();
+        builder.register::<terrain::TerrainService>();
+        builder
+    }
+
+    /// Add a service of type `S`, adding the same type twice only registers
+    /// it once.
+    pub fn register<S: Service + 'static>(&mut self) {
+        let type_id = TypeId::of::<S>();
+        if self.registrations.iter().all(|&(t, _)| t != type_id) {
+            self.registrations.push((type_id, register_boxed::<S>));
+        }
+    }
+
+    pub(crate) fn build(
+        self,
+        handlers: &mut message_handlers::Handlers,
+        circuit_data: CircuitDataHandle,
+        log: &Log,
+    ) -> Services {
+        let services = self
+            .registrations
+            .into_iter()
+            .map(|(type_id, register)| (type_id, register(handlers, circuit_data.clone(), log)))
+            .collect();
+        Services { services: services }
+    }
+}
+
+impl Default for ServicesBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The services registered for a sim, keyed by their type.
+pub struct Services {
+    services: HashMap<TypeId, Box<Any + Send + Sync>>,
+}
+
+impl Services {
+    /// The service of type `S`, if it was registered.
+    pub fn get<S: Service + 'static>(&self) -> Option<&S> {
+        self.services
+            .get(&TypeId::of::<S>())
+            .and_then(|service| (**service).downcast_ref())
     }
 }
 
@@ -50,3 +159,70 @@ pub struct CircuitData {
 
 pub mod region_handle;
 pub mod terrain;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use capabilities::Urls;
+    use futures::{Async, Future};
+    use url::Url;
+
+    fn region_id(n: u8) -> Uuid {
+        Uuid::parse_str(&format!("00000000-0000-0000-0000-0000000000{:02x}", n)).unwrap()
+    }
+
+    fn circuit_data(region_id: Uuid) -> CircuitData {
+        CircuitData {
+            capabilities: Capabilities::from_urls(Urls {
+                get_texture: Url::parse("http://127.0.0.1:9000/texture").unwrap(),
+            }),
+            region_id: region_id,
+            message_sender: MessageSender::unconnected(),
+        }
+    }
+
+    #[test]
+    fn wait_before_set() {
+        let handle = CircuitDataHandle::new();
+        let waiting = handle.wait();
+        assert!(handle.get().is_none());
+
+        handle.set(circuit_data(region_id(1)));
+        assert_eq!(waiting.wait().unwrap().region_id, region_id(1));
+        assert_eq!(handle.get().unwrap().region_id, region_id(1));
+    }
+
+    #[test]
+    fn wait_after_set() {
+        let handle = CircuitDataHandle::new();
+        handle.set(circuit_data(region_id(1)));
+
+        // Resolved right away, without being polled by a task.
+        match handle.wait().poll() {
+            Ok(Async::Ready(data)) => assert_eq!(data.region_id, region_id(1)),
+            _ => panic!("wait did not resolve immediately"),
+        }
+    }
+
+    #[test]
+    fn second_set_ignored() {
+        let handle = CircuitDataHandle::new();
+        handle.clone().set(circuit_data(region_id(1)));
+        handle.set(circuit_data(region_id(2)));
+
+        assert_eq!(handle.get().unwrap().region_id, region_id(1));
+        assert_eq!(handle.wait().wait().unwrap().region_id, region_id(1));
+    }
+
+    #[test]
+    fn dropped_handles_cancel_wait() {
+        let handle = CircuitDataHandle::new();
+        let clone = handle.clone();
+        let waiting = handle.wait();
+        drop(handle);
+        drop(clone);
+
+        // The data will never be available.
+        assert!(waiting.wait().is_err());
+    }
+}
@@ -2,9 +2,10 @@
 
 use circuit::message_handlers;
 use futures::sync::oneshot;
+use futures::Future;
 use grid_map::region_handle::RegionHandle;
 use logging::Log;
-use messages::{MessageInstance, MessageType};
+use messages::all::RegionIDAndHandleReply;
 use services::{CircuitDataHandle, Service};
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
@@ -24,44 +25,33 @@ impl Service for LookupService {
         let pending = Arc::new(Mutex::new(HashMap::new()));
         let pending2 = Arc::clone(&pending);
 
-        let handler = Box::new(
-            move |message: MessageInstance, _context: &message_handlers::HandlerContext| {
-                match message {
-                    MessageInstance::RegionIDAndHandleReply(msg) => {
-                        let mut p = pending.lock().unwrap();
-                        let uuid = msg.reply_block.region_id;
-                        let handle = RegionHandle::from_handle(msg.reply_block.region_handle);
+        handlers.register(
+            move |msg: RegionIDAndHandleReply, _context: &message_handlers::HandlerContext| {
+                let mut p = pending.lock().unwrap();
+                let uuid = msg.reply_block.region_id;
+                let handle = RegionHandle::from_handle(msg.reply_block.region_handle);
 
-                        if let Some(sender) = p.remove(&uuid) {
-                            let sender: oneshot::Sender<LookupResult> = sender;
-                            sender
-                                .send(LookupResult {
-                                    uuid: uuid,
-                                    handle: handle,
-                                })
-                                .map_err(|_| message_handlers::Error {
-                                    msg: MessageInstance::RegionIDAndHandleReply(msg),
-                                    kind: message_handlers::ErrorKind::Other(Box::new(
-                                        Error::ChannelClosed,
-                                    )),
-                                })
-                        } else {
-                            Err(message_handlers::Error {
-                                msg: MessageInstance::RegionIDAndHandleReply(msg),
-                                kind: message_handlers::ErrorKind::Other(Box::new(
-                                    Error::NotRegistered,
-                                )),
-                            })
-                        }
-                    }
-                    _ => Err(message_handlers::Error {
-                        msg: message,
-                        kind: message_handlers::ErrorKind::WrongHandler,
-                    }),
+                if let Some(sender) = p.remove(&uuid) {
+                    let sender: oneshot::Sender<LookupResult> = sender;
+                    sender
+                        .send(LookupResult {
+                            uuid: uuid,
+                            handle: handle,
+                        })
+                        .map_err(|_| message_handlers::Error {
+                            msg: msg.into(),
+                            kind: message_handlers::ErrorKind::Other(Box::new(
+                                Error::ChannelClosed,
+                            )),
+                        })
+                } else {
+                    Err(message_handlers::Error {
+                        msg: msg.into(),
+                        kind: message_handlers::ErrorKind::Other(Box::new(Error::NotRegistered)),
+                    })
                 }
             },
         );
-        handlers.register_type(MessageType::RegionIDAndHandleReply, handler);
 
         LookupService {
             circuit_data,
@@ -77,27 +67,38 @@ pub enum Error {
 
     #[fail(display = "Handler was not registered (logic bug!)")]
     NotRegistered,
+
+    #[fail(display = "The circuit to the sim was never established.")]
+    CircuitUnavailable,
 }
 
 impl LookupService {
-    pub fn lookup(&self, region_id: Uuid) -> oneshot::Receiver<LookupResult> {
+    /// Look up the handle of a region, the request is sent as soon as the
+    /// circuit is available.
+    pub fn lookup(&self, region_id: Uuid) -> impl Future<Item = LookupResult, Error = Error> {
         use messages::all::{RegionHandleRequest, RegionHandleRequest_RequestBlock};
         let msg = RegionHandleRequest {
             request_block: RegionHandleRequest_RequestBlock {
                 region_id: region_id.clone(),
             },
         };
+        let pending = Arc::clone(&self.pending);
 
-        // Register pending callback.
-        let (sender, receiver) = oneshot::channel();
-        {
-            let mut pending = self.pending.lock().unwrap();
-            pending.insert(region_id, sender);
-        }
+        self.circuit_data
+            .wait()
+            .map_err(|_| Error::CircuitUnavailable)
+            .and_then(move |circuit_data| {
+                // Register pending callback.
+                let (sender, receiver) = oneshot::channel();
+                {
+                    let mut pending = pending.lock().unwrap();
+                    pending.insert(region_id, sender);
+                }
 
-        // Send the request
-        let _ = self.circuit_data.unwrap().message_sender.send(msg, true);
-        receiver
+                // Send the request
+                let _ = circuit_data.message_sender.send(msg, true);
+                receiver.map_err(|_| Error::ChannelClosed)
+            })
     }
 }
 
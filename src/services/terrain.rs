@@ -5,9 +5,8 @@ use crossbeam_channel;
 use futures::Future;
 use layer_data::{extract_land_patch, Patch};
 use logging::{Log, Logger};
-use messages::{MessageInstance, MessageType};
+use messages::all::LayerData;
 use services::{CircuitDataHandle, Service};
-use std::cell::Cell;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use types::Uuid;
@@ -52,7 +51,7 @@ impl Receivers {
             return Err(ReceiversError::RegisterTwice(region_id));
         }
 
-        if let Some(receiver) = terrain_service.receiver.replace(None) {
+        if let Some(receiver) = terrain_service.receiver.lock().unwrap().take() {
             self.receivers.insert(region_id, receiver);
             Ok(())
         } else {
@@ -102,7 +101,7 @@ struct Receiver {
 }
 
 pub struct TerrainService {
-    receiver: Cell<Option<Receiver>>,
+    receiver: Mutex<Option<Receiver>>,
 }
 
 impl Service for TerrainService {
@@ -119,41 +118,33 @@ impl Service for TerrainService {
         let channel_lock2 = Arc::clone(&channel_lock);
         let logger = Arc::new(Logger::root(log.clone(), o!("service" => "TerrainService")));
 
-        let handler = move |msg: MessageInstance, context: &message_handlers::HandlerContext| {
+        let handler = move |msg: LayerData, context: &message_handlers::HandlerContext| {
             let patch_tx = Arc::clone(&patch_tx);
             let channel_lock3 = Arc::clone(&channel_lock2);
-            match msg {
-                MessageInstance::LayerData(msg) => {
-                    debug!(logger, "Received new layer data msg");
-                    let logger2 = Arc::clone(&logger);
-                    let logger3 = Arc::clone(&logger);
-                    let patches = context.cpupool.spawn_fn(move || {
-                        extract_land_patch(&msg).map(|patches| {
-                            debug!(logger2, "Decoding layer data ok.");
-                            // Wait for potential reads of the values.
-                            let lock = channel_lock3.lock().unwrap();
-                            let tx = patch_tx.lock().unwrap();
-                            tx.send(patches).unwrap();
-                            drop(lock);
-                        })
-                    });
-                    let fut = patches.map_err(move |e| {
-                        debug!(logger3, "Decoding layer data failed: {:?}", e);
-                        // TODO
-                        ()
-                    });
-
-                    context.reactor.spawn(|_handle| fut);
-
-                    Ok(())
-                }
-                _ => Err(message_handlers::Error {
-                    msg: msg,
-                    kind: message_handlers::ErrorKind::WrongHandler,
-                }),
-            }
+            debug!(logger, "Received new layer data msg");
+            let logger2 = Arc::clone(&logger);
+            let logger3 = Arc::clone(&logger);
+            let patches = context.cpupool.spawn_fn(move || {
+                extract_land_patch(&msg).map(|patches| {
+                    debug!(logger2, "Decoding layer data ok.");
+                    // Wait for potential reads of the values.
+                    let lock = channel_lock3.lock().unwrap();
+                    let tx = patch_tx.lock().unwrap();
+                    tx.send(patches).unwrap();
+                    drop(lock);
+                })
+            });
+            let fut = patches.map_err(move |e| {
+                debug!(logger3, "Decoding layer data failed: {:?}", e);
+                // TODO
+                ()
+            });
+
+            context.reactor.spawn(|_handle| fut);
+
+            Ok(())
         };
-        handlers.register_type(MessageType::LayerData, Box::new(handler));
+        handlers.register(handler);
 
         let receiver = Receiver {
             channel_lock,
@@ -161,7 +152,7 @@ impl Service for TerrainService {
         };
 
         TerrainService {
-            receiver: Cell::new(Some(receiver)),
+            receiver: Mutex::new(Some(receiver)),
         }
     }
 }
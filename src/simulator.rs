@@ -1,5 +1,9 @@
 use capabilities::{Capabilities, CapabilitiesError};
-use circuit::{message_handlers, Circuit, CircuitConfig, SendMessage};
+use crossbeam_channel::Receiver;
+use circuit::{
+    message_handlers, Circuit, CircuitConfig, CircuitEvent, CircuitRuntime, CircuitStats,
+    ReadMessageError, SendMessage, Subscription, Throttles,
+};
 use data::RegionInfo;
 use failure::Error;
 use futures::prelude::{await, *};
@@ -7,12 +11,14 @@ use hyper::Uri;
 use logging::Log;
 use login::LoginResponse;
 use messages::all::{
-    CompleteAgentMovement, CompleteAgentMovement_AgentData, UseCircuitCode,
-    UseCircuitCode_CircuitCode,
+    CompleteAgentMovement, CompleteAgentMovement_AgentData, LogoutRequest, LogoutRequest_AgentData,
+    RegionHandshakeReply, RegionHandshakeReply_AgentData, RegionHandshakeReply_RegionInfo,
+    UseCircuitCode, UseCircuitCode_CircuitCode,
 };
-use messages::MessageInstance;
-use services::{self, CircuitData, CircuitDataHandle, Service};
+use messages::{MessageInstance, MessageType};
+use services::{CircuitData, CircuitDataHandle, Service, Services, ServicesBuilder};
 use std::sync::Mutex;
+use std::time::Instant;
 use systems::agent_update::{AgentState, Modality};
 use textures::{GetTexture, TextureService};
 use tokio_core::reactor::{self, Handle};
@@ -53,9 +59,57 @@ impl From<LoginResponse> for ConnectInfo {
     }
 }
 
-pub struct Services {
-    pub region_handle: services::region_handle::LookupService,
-    pub terrain: services::terrain::TerrainService,
+/// The parameters of connecting to a sim, see `Simulator::connect`.
+#[derive(Clone)]
+pub struct ConnectOptions {
+    /// The configuration of the circuit, its bandwidth budgets are sent to
+    /// the sim once the agent is in the region.
+    pub circuit: CircuitConfig,
+
+    /// How long to wait for the `RegionHandshake` of the sim.
+    pub handshake_timeout: Duration,
+
+    /// Whether to answer the `RegionHandshake` with a
+    /// `RegionHandshakeReply`, which makes the sim send the objects of the
+    /// region.
+    pub handshake_reply: bool,
+
+    /// The state of the agent sent once it is in the region, including its
+    /// draw distance.
+    pub agent_state: AgentState,
+
+    /// Drive the circuit on a runtime shared with the circuits to other sims
+    /// instead of threads of its own, see `Circuit::initiate_on`.
+    pub runtime: Option<CircuitRuntime>,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        let z_axis = Vector3::z_axis();
+        ConnectOptions {
+            circuit: CircuitConfig {
+                send_timeout: Duration::from_millis(5000),
+                send_attempts: 5,
+                ping_interval: Duration::from_millis(5000),
+                throttles: None,
+                trusted: false,
+                silence_timeout: Duration::from_secs(10),
+                dead_timeout: Some(Duration::from_secs(60)),
+                stats_interval: None,
+            },
+            handshake_timeout: Duration::from_millis(15_000),
+            handshake_reply: false,
+            agent_state: AgentState {
+                position: Vector3::new(10., 10., 0.),
+                move_direction: None,
+                modality: Modality::Walking,
+                body_rotation: UnitQuaternion::from_axis_angle(&z_axis, 0.),
+                head_rotation: UnitQuaternion::from_axis_angle(&z_axis, 0.),
+                draw_distance: 0.,
+            },
+            runtime: None,
+        }
+    }
 }
 
 /// This struct manages all connections from the viewer to a (single) simulator
@@ -68,6 +122,8 @@ pub struct Simulator {
 
     handle: Handle,
     locator: SimLocator,
+    agent_id: Uuid,
+    session_id: Uuid,
 
     // TODO: (future) can this be updated remotely somehow, i.e. by the estate manager?
     // If yes we should register appropriate message handlers which update this data,
@@ -91,10 +147,36 @@ pub enum ConnectError {
     Msg(String),
 }
 
+#[derive(Debug, Fail)]
+pub enum LogoutError {
+    #[fail(display = "The sim did not reply to the logout request in time.")]
+    NoReply,
+    #[fail(display = "Read message error: {}", 0)]
+    ReadMessageError(#[cause] ::circuit::ReadMessageError),
+    #[fail(display = "Close error: {}", 0)]
+    CloseError(#[cause] ::circuit::CloseError),
+}
+
+impl From<::circuit::ReadMessageError> for LogoutError {
+    fn from(e: ::circuit::ReadMessageError) -> Self {
+        LogoutError::ReadMessageError(e)
+    }
+}
+
+impl From<::circuit::CloseError> for LogoutError {
+    fn from(e: ::circuit::CloseError) -> Self {
+        LogoutError::CloseError(e)
+    }
+}
+
 impl Simulator {
+    /// Connect to the sim, registering the provided services and handlers on
+    /// the circuit.
     pub fn connect(
         connect_info: ConnectInfo,
+        options: ConnectOptions,
         handlers: message_handlers::Handlers,
+        services: ServicesBuilder,
         handle: Handle,
         log: Log,
     ) -> impl Future<Item = Simulator, Error = Error> {
@@ -106,12 +188,9 @@ impl Simulator {
 
             let mut handlers = handlers;
             let circuit_data_handle = CircuitDataHandle::new();
-            let services = Services {
-                region_handle: services::region_handle::LookupService::register_service(&mut handlers, circuit_data_handle.clone(), &log),
-                terrain: services::terrain::TerrainService::register_service(&mut handlers, circuit_data_handle.clone(), &log),
-            };
+            let services = services.build(&mut handlers, circuit_data_handle.clone(), &log);
 
-            let (circuit, region_info) = await!(Self::setup_circuit(connect_info.clone(), handlers, handle.remote().clone(), log.clone()))?;
+            let (circuit, region_info) = await!(Self::setup_circuit(connect_info.clone(), options, handlers, handle.remote().clone(), log.clone()))?;
 
             // Update circuit_data_handle.
             circuit_data_handle.set(CircuitData {
@@ -136,6 +215,8 @@ impl Simulator {
                 texture_service: Mutex::new(texture_service),
                 handle: handle,
                 locator: locator,
+                agent_id: connect_info.agent_id,
+                session_id: connect_info.session_id,
             })
         }
     }
@@ -148,6 +229,11 @@ impl Simulator {
         &self.services
     }
 
+    /// The service of type `S`, if it was registered, see `ServicesBuilder`.
+    pub fn service<S: Service + 'static>(&self) -> Option<&S> {
+        self.services.get::<S>()
+    }
+
     pub fn region_info(&self) -> &RegionInfo {
         &self.region_info
     }
@@ -160,6 +246,67 @@ impl Simulator {
         self.circuit.lock().unwrap().send(message, reliable)
     }
 
+    /// The smoothed round-trip time of the circuit, see `Circuit::rtt`.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.circuit.lock().unwrap().rtt()
+    }
+
+    /// Observe the received messages of a type, see `Circuit::subscribe`.
+    pub fn subscribe(&self, m_type: MessageType) -> Subscription {
+        self.circuit.lock().unwrap().subscribe(m_type)
+    }
+
+    /// The events of the circuit, see `Circuit::events`.
+    pub fn events(&self) -> Receiver<CircuitEvent> {
+        self.circuit.lock().unwrap().events()
+    }
+
+    /// The stats of the circuit, see `Circuit::stats`.
+    pub fn stats(&self) -> CircuitStats {
+        self.circuit.lock().unwrap().stats()
+    }
+
+    /// Change the bandwidth budgets of the circuit, see
+    /// `Circuit::set_throttles`.
+    pub fn set_throttles(&self, throttles: Throttles) -> SendMessage {
+        self.circuit.lock().unwrap().set_throttles(throttles)
+    }
+
+    /// Log the agent out and close the circuit to the sim.
+    ///
+    /// Sends a `LogoutRequest` and waits at most `timeout` for the
+    /// `LogoutReply`, otherwise the sim keeps the session around and the next
+    /// login of the agent fails. Messages received in the meantime are
+    /// dropped. The circuit is closed in any case, see `Circuit::close`.
+    pub fn logout(self, timeout: Duration) -> Result<(), LogoutError> {
+        let circuit = self.circuit.into_inner().unwrap();
+        let message = LogoutRequest {
+            agent_data: LogoutRequest_AgentData {
+                agent_id: self.agent_id,
+                session_id: self.session_id,
+            },
+        };
+        // The reply tells us the request arrived, no need to wait for the ack.
+        circuit.send(message, true);
+
+        let deadline = Instant::now() + timeout;
+        let result = loop {
+            let now = Instant::now();
+            if now >= deadline {
+                break Err(LogoutError::NoReply);
+            }
+            match circuit.read(Some(deadline - now)) {
+                Ok(MessageInstance::LogoutReply(_)) => break Ok(()),
+                Ok(_) => {}
+                Err(ReadMessageError::Timeout) => break Err(LogoutError::NoReply),
+                Err(e) => break Err(e.into()),
+            }
+        };
+
+        circuit.close()?;
+        result
+    }
+
     /// To call this method you need to use `EventLoop::run_with_handle`.
     pub fn get_texture(&self, id: &Uuid, handle: &Handle) -> GetTexture {
         self.texture_service.lock().unwrap().get_texture(id, handle)
@@ -171,21 +318,26 @@ impl Simulator {
     fn setup_circuit(
         //connect_info: &ConnectInfo,
         connect_info: ConnectInfo,
+        options: ConnectOptions,
         handlers: message_handlers::Handlers,
         reactor_remote: reactor::Remote,
         log: Log,
         //log: &Log,
     ) -> Result<(Circuit, RegionInfo), Error> {
-        let config = CircuitConfig {
-            send_timeout: Duration::from_millis(5000),
-            send_attempts: 5,
-        };
         let agent_id = connect_info.agent_id.clone();
         let session_id = connect_info.session_id.clone();
         let circuit_code = connect_info.circuit_code.clone();
+        let throttles = options.circuit.throttles.clone();
 
-        let circuit =
-            Circuit::initiate(&connect_info, config, handlers, reactor_remote, log.clone())?;
+        let config = options.circuit;
+        let circuit = match options.runtime {
+            Some(ref runtime) => {
+                Circuit::initiate_on(runtime, &connect_info, config, handlers, log.clone())?
+            }
+            None => {
+                Circuit::initiate(&connect_info, config, handlers, reactor_remote, log.clone())?
+            }
+        };
 
         let message = UseCircuitCode {
             circuit_code: UseCircuitCode_CircuitCode {
@@ -197,8 +349,7 @@ impl Simulator {
         await!(circuit.send(message, true))?;
 
         // Now wait for the RegionHandshake message.
-        let timeout = Duration::from_millis(15_000);
-        let region_info = match circuit.read(Some(timeout))? {
+        let region_info = match circuit.read(Some(options.handshake_timeout))? {
             MessageInstance::RegionHandshake(handshake) => {
                 Ok(RegionInfo::extract_message(handshake))
             }
@@ -209,6 +360,17 @@ impl Simulator {
             "Connected to simulator successfully, received region_info: {:?}", region_info
         );
 
+        if options.handshake_reply {
+            let message = RegionHandshakeReply {
+                agent_data: RegionHandshakeReply_AgentData {
+                    agent_id: agent_id.clone(),
+                    session_id: session_id.clone(),
+                },
+                region_info: RegionHandshakeReply_RegionInfo { flags: 0 },
+            };
+            await!(circuit.send(message, true))?;
+        }
+
         let message = CompleteAgentMovement {
             agent_data: CompleteAgentMovement_AgentData {
                 agent_id: agent_id.clone(),
@@ -218,22 +380,14 @@ impl Simulator {
         };
         await!(circuit.send(message, true))?;
 
-        // let region_x = 256000.;
-        // let region_y = 256000.;
-        let local_x = 10.;
-        let local_y = 10.;
-
-        let z_axis = Vector3::z_axis();
-        let agent_state = AgentState {
-            position: Vector3::new(local_x, local_y, 0.),
-            move_direction: None,
-            modality: Modality::Walking,
-            body_rotation: UnitQuaternion::from_axis_angle(&z_axis, 0.),
-            head_rotation: UnitQuaternion::from_axis_angle(&z_axis, 0.),
-        };
-        let message = agent_state.to_update_message(agent_id, session_id);
+        let message = options.agent_state.to_update_message(agent_id, session_id);
         await!(circuit.send(message, true))?;
 
+        // The sim only accepts budgets once the agent is in the region.
+        if let Some(throttles) = throttles {
+            await!(circuit.set_throttles(throttles))?;
+        }
+
         Ok((circuit, region_info))
     }
 
@@ -35,6 +35,7 @@ bitflags! {
     }
 }
 
+#[derive(Clone, Debug)]
 pub enum MoveDirection {
     Forward,
     Backward,
@@ -57,6 +58,7 @@ impl MoveDirection {
     }
 }
 
+#[derive(Clone, Debug)]
 pub enum Modality {
     /// The default.
     Walking,
@@ -71,6 +73,7 @@ pub enum Modality {
     Sitting,
 }
 
+#[derive(Clone, Debug)]
 pub struct AgentState {
     /// The region local coordinates.
     pub position: Vector3<f32>,
@@ -83,6 +86,10 @@ pub struct AgentState {
 
     pub body_rotation: UnitQuaternion<f32>,
     pub head_rotation: UnitQuaternion<f32>,
+
+    /// How far the agent sees in meters, the sim only sends the objects
+    /// within this distance.
+    pub draw_distance: f32,
 }
 
 impl AgentState {
@@ -121,7 +128,7 @@ impl AgentState {
                 camera_at_axis: self.position + Vector3::new(1., 0., 0.),
                 camera_left_axis: self.position + Vector3::new(0., 1., 0.),
                 camera_up_axis: self.position + Vector3::new(0., 0., 1.),
-                far: 0.,
+                far: self.draw_distance,
                 control_flags: self.to_control_flags().bits(),
                 flags: 0,
             },
@@ -1,9 +1,50 @@
 extern crate opensim_networking;
 
+use opensim_networking::layer_data::{self, ExtractSurfaceError};
+use opensim_networking::messages::MessageInstance;
+use opensim_networking::object_update;
 use opensim_networking::packet::Packet;
+use opensim_networking::types::Uuid;
+use std::io::BufReader;
 
 #[test]
 fn packet_appended_acks() {
     let data = include_bytes!("data/appended_acks.bin");
     Packet::read(data).unwrap();
 }
+
+#[test]
+fn layer_data_mismatching_layer_type() {
+    // Message claims VarLand but the patch group header contains Land.
+    let data = include_bytes!("../examples/data/layer_land/00000018.bin");
+    let mut msg = match Packet::read(data).unwrap().message {
+        MessageInstance::LayerData(msg) => msg,
+        _ => panic!("wrong message type"),
+    };
+    msg.layer_id.type_ = b'M';
+    match layer_data::extract_land_patch(&msg) {
+        Err(ExtractSurfaceError::WrongLayerType(_)) => {}
+        r => panic!("unexpected result: {:?}", r),
+    }
+}
+
+#[test]
+fn texture_entry_face_out_of_range() {
+    // Default texture followed by a texture for faces 0 and 2, which were
+    // not seen before.
+    let mut data = vec![1u8; 16];
+    data.push(0x85);
+    data.extend_from_slice(&[2u8; 16]);
+    data.push(0x80);
+    for &size in &[4, 4, 4, 2, 2, 2, 1, 1, 1, 16] {
+        data.extend(::std::iter::repeat(3u8).take(size));
+        data.push(0x80);
+    }
+
+    let mut reader = BufReader::new(&data[..]);
+    let entry = object_update::read_texture_entry(&mut reader).unwrap();
+    assert_eq!(entry.len(), 3);
+    assert_eq!(entry[0].texture_id, Uuid::from_bytes([2u8; 16]));
+    assert_eq!(entry[1].texture_id, Uuid::from_bytes([1u8; 16]));
+    assert_eq!(entry[2].texture_id, Uuid::from_bytes([2u8; 16]));
+}